@@ -0,0 +1,110 @@
+//! API REST sotto `/api`: tiri senza stanza e lettura delle stanze (vedi
+//! [`seven_seas_protocol::api`]). Le richieste contano nei limiti per
+//! indirizzo IP come i messaggi delle connessioni.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::response::content::RawJson;
+use rocket::serde::json::Json;
+use rocket::tokio::task;
+use rocket::{Request, State};
+use seven_seas_core::{roll, RollOptions, RollWithRerollResponse};
+use seven_seas_protocol::api::{ErroreApi, PaginaStorico};
+use seven_seas_protocol::limiti::valida_opzioni;
+use seven_seas_protocol::{schema, Rifiuto, Stanza, PAGINA_LOG};
+
+use crate::stanza::StatoStanza;
+use crate::stato::StatoServer;
+
+/// Il corpo JSON della risposta, o lo stato e il motivo dell'errore
+pub type Risposta<T> = Result<Json<T>, (Status, Json<ErroreApi>)>;
+
+/// Fa un tiro con le opzioni del corpo, fuori da ogni stanza, con gli
+/// stessi limiti dei tiri al tavolo. Il calcolo dei raises gira su un thread
+/// per i lavori bloccanti, non su quelli che servono le richieste
+#[rocket::post("/api/roll", data = "<opzioni>")]
+pub async fn tira(
+    opzioni: Json<RollOptions>,
+    ip: Option<IpAddr>,
+    stato: &State<StatoServer>,
+) -> Risposta<RollWithRerollResponse> {
+    stato.consenti_ip(ip).map_err(troppe)?;
+    valida_opzioni(&opzioni).map_err(|rifiuto| errore(Status::BadRequest, rifiuto))?;
+    let opzioni = opzioni.into_inner();
+    match task::spawn_blocking(move || roll(&opzioni)).await {
+        Ok(tiro) => tiro.map(Json).map_err(|e| errore(Status::BadRequest, e)),
+        Err(e) => Err(errore(Status::InternalServerError, e)),
+    }
+}
+
+/// La stanza col codice `codice`
+#[rocket::get("/api/rooms/<codice>")]
+pub fn stanza(codice: &str, ip: Option<IpAddr>, stato: &State<StatoServer>) -> Risposta<Stanza> {
+    stato.consenti_ip(ip).map_err(troppe)?;
+    let (_, descrizione) = leggibile(stato, codice)?;
+    Ok(Json(descrizione))
+}
+
+/// Una pagina del log della stanza `codice`: al più `limit` righe (e non più
+/// di [`PAGINA_LOG`]) prima di quella con `seq` uguale a `before`, o le ultime
+#[rocket::get("/api/rooms/<codice>/history?<before>&<limit>")]
+pub fn storico(
+    codice: &str,
+    before: Option<u64>,
+    limit: Option<u32>,
+    ip: Option<IpAddr>,
+    stato: &State<StatoServer>,
+) -> Risposta<PaginaStorico> {
+    stato.consenti_ip(ip).map_err(troppe)?;
+    let (stanza, _) = leggibile(stato, codice)?;
+    let limite = limit.map_or(PAGINA_LOG, |l| l as usize);
+    Ok(Json(stanza.pagina(before, limite)))
+}
+
+/// Il documento OpenAPI di questa API
+#[rocket::get("/api/openapi.json")]
+pub fn openapi() -> RawJson<String> {
+    RawJson(schema::openapi())
+}
+
+/// La risposta alle richieste preliminari dei browser, per le POST in JSON
+/// da altri domini
+#[rocket::options("/api/<_..>")]
+pub fn preliminare() -> Status {
+    Status::NoContent
+}
+
+/// Anche le richieste che non arrivano a una rotta, come quelle con un
+/// JSON sbagliato, ricevono un [`ErroreApi`]
+#[rocket::catch(default)]
+pub fn errore_api(status: Status, _: &Request<'_>) -> (Status, Json<ErroreApi>) {
+    let motivo = format!("{} {}", status.code, status.reason_lossy());
+    errore(status, motivo)
+}
+
+/// La stanza `codice`, se c'è e non è chiusa a chi non ci sta dentro
+fn leggibile(
+    stato: &StatoServer,
+    codice: &str,
+) -> Result<(Arc<StatoStanza>, Stanza), (Status, Json<ErroreApi>)> {
+    let Some(stanza) = stato.cerca(codice) else {
+        let motivo = format!("Nessuna stanza col codice {}", codice);
+        return Err(errore(Status::NotFound, motivo));
+    };
+    let descrizione = stanza.descrivi();
+    if descrizione.bloccata {
+        let motivo = format!("La stanza {} è chiusa dal GM", descrizione.codice);
+        return Err(errore(Status::Forbidden, motivo));
+    }
+    Ok((stanza, descrizione))
+}
+
+fn troppe(rifiuto: Rifiuto) -> (Status, Json<ErroreApi>) {
+    errore(Status::TooManyRequests, rifiuto)
+}
+
+fn errore(status: Status, motivo: impl ToString) -> (Status, Json<ErroreApi>) {
+    (status, Json(ErroreApi::new(motivo)))
+}
@@ -0,0 +1,510 @@
+//! Archivio SQLite delle stanze e dei loro log, per ritrovarli dopo un
+//! riavvio del server.
+//!
+//! Le stanze tengono il log anche in memoria: l'archivio riceve ogni
+//! modifica e serve solo a ricaricarle all'avvio. Ci sono anche la chiave
+//! dei [gettoni](crate::gettoni), perché restino validi, i nomi già presi
+//! in ogni stanza e chi il GM ne ha mandato via.
+//!
+//! Le stanze non scrivono da sé: mettono le modifiche in coda a uno
+//! [`Scrittore`], che le salva nell'ordine su un suo thread. Così un disco
+//! lento non ferma le stanze, né i gestori delle connessioni.
+
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::serde::de::DeserializeOwned;
+use rocket::serde::Serialize;
+use rocket::tokio::sync::oneshot;
+use rusqlite::{params, Connection};
+use seven_seas_protocol::{ChatMessage, Moderazione, RollResult};
+
+/// Percorso da usare per un archivio che sparisce con il server
+pub const IN_MEMORIA: &str = ":memory:";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS stanze (
+        codice TEXT PRIMARY KEY,
+        nome TEXT NOT NULL,
+        gm TEXT,
+        password_gm TEXT,
+        bloccata INTEGER NOT NULL,
+        cancellati INTEGER NOT NULL,
+        seme TEXT NOT NULL,
+        contatore INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS righe (
+        stanza TEXT NOT NULL REFERENCES stanze (codice),
+        seq INTEGER NOT NULL,
+        username TEXT NOT NULL,
+        message TEXT NOT NULL,
+        timestamp INTEGER,
+        tiro TEXT,
+        moderata TEXT,
+        PRIMARY KEY (stanza, seq)
+    );
+    CREATE TABLE IF NOT EXISTS nomi (
+        stanza TEXT NOT NULL REFERENCES stanze (codice),
+        username TEXT NOT NULL,
+        identita TEXT NOT NULL,
+        PRIMARY KEY (stanza, username)
+    );
+    CREATE TABLE IF NOT EXISTS banditi (
+        stanza TEXT NOT NULL REFERENCES stanze (codice),
+        username TEXT NOT NULL,
+        identita TEXT NOT NULL,
+        PRIMARY KEY (stanza, username)
+    );
+    CREATE TABLE IF NOT EXISTS chiavi (
+        nome TEXT PRIMARY KEY,
+        valore BLOB NOT NULL
+    );
+";
+
+/// Nome della chiave dei gettoni nella tabella `chiavi`
+const CHIAVE_GETTONI: &str = "gettoni";
+
+/// Quello che si salva di una stanza, a parte il log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatiStanza {
+    pub codice: String,
+    pub nome: String,
+    pub gm: Option<String>,
+    /// L'[impronta](crate::password) della password del GM, mai la password;
+    /// sta nella colonna `password_gm`, che prima la teneva in chiaro
+    pub impronta_gm: Option<String>,
+    pub bloccata: bool,
+    /// Righe tolte svuotando il log
+    pub cancellati: u64,
+    /// Seme della sessione in corso, in esadecimale
+    pub seme: String,
+    /// Tiri già fatti col seme
+    pub contatore: u64,
+}
+
+/// Una stanza come l'ha lasciata il server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StanzaSalvata {
+    pub dati: DatiStanza,
+    /// Il log, dal più vecchio
+    pub storico: Vec<ChatMessage>,
+    /// A quale identità appartiene ogni nome usato nella stanza
+    pub nomi: BTreeMap<String, String>,
+    /// I nomi mandati via dal GM, con l'identità a cui appartenevano
+    pub banditi: BTreeMap<String, String>,
+}
+
+/// Il database del server
+pub struct Archivio {
+    db: Mutex<Connection>,
+}
+
+impl Archivio {
+    /// Apre (o crea) l'archivio nel file `percorso`, o in memoria con
+    /// [`IN_MEMORIA`]
+    pub fn apri(percorso: &str) -> rusqlite::Result<Self> {
+        let db = Connection::open(percorso)?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Archivio { db: Mutex::new(db) })
+    }
+
+    /// Un archivio vuoto che non sopravvive al server
+    pub fn in_memoria() -> Self {
+        Self::apri(IN_MEMORIA).expect("SQLite in memoria si apre sempre")
+    }
+
+    /// La chiave con cui firmare i gettoni; la prima volta se ne sceglie una
+    pub fn chiave_gettoni(&self) -> rusqlite::Result<[u8; 32]> {
+        let db = self.db.lock().unwrap();
+        let mut chiave = [0; 32];
+        OsRng.fill_bytes(&mut chiave);
+        db.execute(
+            "INSERT OR IGNORE INTO chiavi (nome, valore) VALUES (?1, ?2)",
+            params![CHIAVE_GETTONI, chiave],
+        )?;
+        let salvata: Vec<u8> = db.query_row(
+            "SELECT valore FROM chiavi WHERE nome = ?1",
+            [CHIAVE_GETTONI],
+            |r| r.get(0),
+        )?;
+        salvata.try_into().map_err(|_| {
+            rusqlite::Error::FromSqlConversionFailure(
+                0,
+                rusqlite::types::Type::Blob,
+                "la chiave dei gettoni deve essere di 32 byte".into(),
+            )
+        })
+    }
+
+    /// Le stanze salvate
+    pub fn stanze(&self) -> rusqlite::Result<Vec<StanzaSalvata>> {
+        let db = self.db.lock().unwrap();
+        let mut query = db.prepare(
+            "SELECT codice, nome, gm, password_gm, bloccata, cancellati, seme, contatore
+             FROM stanze",
+        )?;
+        let stanze = query
+            .query_map([], |r| {
+                Ok(DatiStanza {
+                    codice: r.get(0)?,
+                    nome: r.get(1)?,
+                    gm: r.get(2)?,
+                    impronta_gm: r.get(3)?,
+                    bloccata: r.get(4)?,
+                    cancellati: r.get(5)?,
+                    seme: r.get(6)?,
+                    contatore: r.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut righe = db.prepare(
+            "SELECT username, message, seq, timestamp, tiro, moderata
+             FROM righe WHERE stanza = ?1 ORDER BY seq",
+        )?;
+        let mut nomi = db.prepare("SELECT username, identita FROM nomi WHERE stanza = ?1")?;
+        let mut banditi = db.prepare("SELECT username, identita FROM banditi WHERE stanza = ?1")?;
+        stanze
+            .into_iter()
+            .map(|dati| {
+                let storico = righe
+                    .query_map([&dati.codice], |r| {
+                        Ok(ChatMessage {
+                            username: r.get(0)?,
+                            message: r.get(1)?,
+                            seq: r.get(2)?,
+                            timestamp: r.get(3)?,
+                            tiro: da_json::<RollResult>(r.get(4)?, 4)?.map(Box::new),
+                            moderata: da_json::<Moderazione>(r.get(5)?, 5)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                let nomi = nomi
+                    .query_map([&dati.codice], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                let banditi = banditi
+                    .query_map([&dati.codice], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(StanzaSalvata {
+                    dati,
+                    storico,
+                    nomi,
+                    banditi,
+                })
+            })
+            .collect()
+    }
+
+    /// Salva i dati della stanza, nuova o già salvata
+    pub fn salva_stanza(&self, dati: &DatiStanza) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO stanze
+                (codice, nome, gm, password_gm, bloccata, cancellati, seme, contatore)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (codice) DO UPDATE SET
+                nome = ?2, gm = ?3, password_gm = ?4, bloccata = ?5, cancellati = ?6,
+                seme = ?7, contatore = ?8",
+            params![
+                dati.codice,
+                dati.nome,
+                dati.gm,
+                dati.impronta_gm,
+                dati.bloccata,
+                dati.cancellati,
+                dati.seme,
+                dati.contatore,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Salva una riga del log della stanza `codice`, nuova o cambiata dal GM
+    pub fn salva_riga(&self, codice: &str, riga: &ChatMessage) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO righe
+                (stanza, seq, username, message, timestamp, tiro, moderata)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                codice,
+                riga.seq,
+                riga.username,
+                riga.message,
+                riga.timestamp,
+                in_json(&riga.tiro),
+                in_json(&riga.moderata),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Segna che nella stanza `codice` il nome `username` appartiene a
+    /// `identita`
+    pub fn salva_nome(&self, codice: &str, username: &str, identita: &str) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO nomi (stanza, username, identita) VALUES (?1, ?2, ?3)",
+            params![codice, username, identita],
+        )?;
+        Ok(())
+    }
+
+    /// Segna che il GM ha mandato via dalla stanza `codice` il nome
+    /// `username`, che apparteneva a `identita`
+    pub fn salva_bando(
+        &self,
+        codice: &str,
+        username: &str,
+        identita: &str,
+    ) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO banditi (stanza, username, identita) VALUES (?1, ?2, ?3)",
+            params![codice, username, identita],
+        )?;
+        Ok(())
+    }
+
+    /// Toglie tutto il log della stanza `codice`
+    pub fn svuota(&self, codice: &str) -> rusqlite::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM righe WHERE stanza = ?1", [codice])?;
+        Ok(())
+    }
+}
+
+/// Una modifica da salvare
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scrittura {
+    /// Vedi [`Archivio::salva_stanza`]
+    Stanza(DatiStanza),
+    /// Vedi [`Archivio::salva_riga`]
+    Riga { stanza: String, riga: ChatMessage },
+    /// Vedi [`Archivio::salva_nome`]
+    Nome {
+        stanza: String,
+        username: String,
+        identita: String,
+    },
+    /// Vedi [`Archivio::salva_bando`]
+    Bando {
+        stanza: String,
+        username: String,
+        identita: String,
+    },
+    /// Vedi [`Archivio::svuota`]
+    Svuota(String),
+}
+
+impl Scrittura {
+    /// Il codice della stanza modificata
+    fn stanza(&self) -> &str {
+        match self {
+            Scrittura::Stanza(dati) => &dati.codice,
+            Scrittura::Riga { stanza, .. }
+            | Scrittura::Nome { stanza, .. }
+            | Scrittura::Bando { stanza, .. }
+            | Scrittura::Svuota(stanza) => stanza,
+        }
+    }
+
+    fn esegui(&self, archivio: &Archivio) -> rusqlite::Result<()> {
+        match self {
+            Scrittura::Stanza(dati) => archivio.salva_stanza(dati),
+            Scrittura::Riga { stanza, riga } => archivio.salva_riga(stanza, riga),
+            Scrittura::Nome {
+                stanza,
+                username,
+                identita,
+            } => archivio.salva_nome(stanza, username, identita),
+            Scrittura::Bando {
+                stanza,
+                username,
+                identita,
+            } => archivio.salva_bando(stanza, username, identita),
+            Scrittura::Svuota(stanza) => archivio.svuota(stanza),
+        }
+    }
+}
+
+enum Comando {
+    Scrivi(Scrittura),
+    /// Avvisa quando le scritture precedenti sono finite
+    Avvisa(oneshot::Sender<()>),
+}
+
+/// Salva le modifiche in un [`Archivio`] su un thread tutto suo, che finisce
+/// quando lo scrittore viene scartato
+pub struct Scrittore {
+    coda: mpsc::Sender<Comando>,
+}
+
+impl Scrittore {
+    pub fn avvia(archivio: Arc<Archivio>) -> Self {
+        let (coda, comandi) = mpsc::channel();
+        thread::Builder::new()
+            .name("archivio".into())
+            .spawn(move || {
+                for comando in comandi {
+                    match comando {
+                        Comando::Scrivi(scrittura) => {
+                            // un errore dell'archivio non ferma la stanza, che
+                            // ha tutto in memoria: lo si segnala e basta
+                            if let Err(e) = scrittura.esegui(&archivio) {
+                                rocket::error!(
+                                    "Archivio della stanza {}: {}",
+                                    scrittura.stanza(),
+                                    e
+                                );
+                            }
+                        }
+                        Comando::Avvisa(avviso) => {
+                            let _ = avviso.send(());
+                        }
+                    }
+                }
+            })
+            .expect("il thread dell'archivio deve partire");
+        Scrittore { coda }
+    }
+
+    /// Mette in coda `scrittura`, dopo quelle già chieste; non aspetta il
+    /// disco
+    pub fn scrivi(&self, scrittura: Scrittura) {
+        if let Err(mpsc::SendError(Comando::Scrivi(scrittura))) =
+            self.coda.send(Comando::Scrivi(scrittura))
+        {
+            rocket::error!(
+                "Archivio della stanza {}: scrittore fermo",
+                scrittura.stanza()
+            );
+        }
+    }
+
+    /// Aspetta che le scritture chieste finora siano salvate
+    pub async fn attendi(&self) {
+        let (avviso, fatto) = oneshot::channel();
+        if self.coda.send(Comando::Avvisa(avviso)).is_ok() {
+            let _ = fatto.await;
+        }
+    }
+}
+
+fn in_json<T: Serialize>(valore: &Option<T>) -> Option<String> {
+    valore
+        .as_ref()
+        .map(|v| serde_json::to_string(v).expect("i messaggi si serializzano sempre"))
+}
+
+/// Legge la colonna `colonna`, scritta da [`in_json`]
+fn da_json<T: DeserializeOwned>(
+    json: Option<String>,
+    colonna: usize,
+) -> rusqlite::Result<Option<T>> {
+    json.map(|json| {
+        serde_json::from_str(&json).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                colonna,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dati(codice: &str) -> DatiStanza {
+        DatiStanza {
+            codice: codice.into(),
+            nome: "Campagna".into(),
+            gm: Some("Anna".into()),
+            impronta_gm: None,
+            bloccata: false,
+            cancellati: 0,
+            seme: "ab".repeat(32),
+            contatore: 0,
+        }
+    }
+
+    #[test]
+    fn stanze_e_righe() {
+        let archivio = Archivio::in_memoria();
+        let mut stanza = dati("ABC234");
+        archivio.salva_stanza(&stanza).unwrap();
+        archivio.salva_stanza(&dati("XYZ789")).unwrap();
+        stanza.bloccata = true;
+        stanza.contatore = 3;
+        archivio.salva_stanza(&stanza).unwrap();
+
+        let mut riga = ChatMessage::new("Anna", "ciao");
+        riga.seq = Some(1);
+        riga.timestamp = Some(1_700_000_000_000);
+        archivio.salva_riga("ABC234", &riga).unwrap();
+        riga.moderata = Some(Moderazione::Annullata);
+        archivio.salva_riga("ABC234", &riga).unwrap();
+        archivio.salva_nome("ABC234", "Anna", "01").unwrap();
+        archivio.salva_nome("ABC234", "Bruno", "02").unwrap();
+        archivio.salva_bando("ABC234", "Bruno", "02").unwrap();
+
+        let mut stanze = archivio.stanze().unwrap();
+        stanze.sort_by(|a, b| a.dati.codice.cmp(&b.dati.codice));
+        let salvata = StanzaSalvata {
+            dati: stanza,
+            storico: vec![riga],
+            nomi: BTreeMap::from([("Anna".into(), "01".into()), ("Bruno".into(), "02".into())]),
+            banditi: BTreeMap::from([("Bruno".into(), "02".into())]),
+        };
+        assert_eq!(stanze[0], salvata);
+        assert!(stanze[1].storico.is_empty());
+        assert!(stanze[1].nomi.is_empty());
+        assert!(stanze[1].banditi.is_empty());
+
+        archivio.svuota("ABC234").unwrap();
+        assert!(archivio
+            .stanze()
+            .unwrap()
+            .iter()
+            .all(|s| s.storico.is_empty()));
+    }
+
+    #[rocket::async_test]
+    async fn scritture_in_ordine() {
+        let archivio = Arc::new(Archivio::in_memoria());
+        let scrittore = Scrittore::avvia(archivio.clone());
+        let mut stanza = dati("ABC234");
+        scrittore.scrivi(Scrittura::Stanza(stanza.clone()));
+        for i in 1..=3 {
+            let mut riga = ChatMessage::new("Anna", i.to_string());
+            riga.seq = Some(i);
+            scrittore.scrivi(Scrittura::Riga {
+                stanza: stanza.codice.clone(),
+                riga,
+            });
+        }
+        scrittore.scrivi(Scrittura::Svuota(stanza.codice.clone()));
+        stanza.bloccata = true;
+        scrittore.scrivi(Scrittura::Stanza(stanza.clone()));
+        scrittore.attendi().await;
+
+        let stanze = archivio.stanze().unwrap();
+        assert_eq!(stanze.len(), 1);
+        assert_eq!(stanze[0].dati, stanza);
+        assert!(stanze[0].storico.is_empty());
+    }
+
+    #[test]
+    fn chiave_dei_gettoni_stabile() {
+        let archivio = Archivio::in_memoria();
+        let chiave = archivio.chiave_gettoni().unwrap();
+        assert_eq!(archivio.chiave_gettoni().unwrap(), chiave);
+        assert_ne!(Archivio::in_memoria().chiave_gettoni().unwrap(), chiave);
+    }
+}
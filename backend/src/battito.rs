@@ -0,0 +1,43 @@
+//! Battito delle connessioni: come il server si accorge dei client spariti.
+//!
+//! Ogni `intervallo_ms` il server manda un ping del WebSocket, a cui i
+//! browser rispondono da soli, e chiede ai client nel [`Welcome`] un
+//! [`ClientMessage::Ping`] con lo stesso intervallo. Una connessione da cui
+//! non arriva niente per `scadenza_ms` è morta: il server la chiude e il
+//! giocatore risulta scollegato.
+//!
+//! [`Welcome`]: seven_seas_protocol::Welcome
+//! [`ClientMessage::Ping`]: seven_seas_protocol::ClientMessage::Ping
+
+use std::time::Duration;
+
+use rocket::serde::Deserialize;
+
+/// Gli intervalli del battito, dalla chiave `battito` della configurazione
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Battito {
+    /// Ogni quanto si manda un ping
+    pub intervallo_ms: u32,
+    /// Dopo quanto silenzio si chiude la connessione
+    pub scadenza_ms: u32,
+}
+
+impl Default for Battito {
+    fn default() -> Self {
+        Battito {
+            intervallo_ms: 15_000,
+            scadenza_ms: 45_000,
+        }
+    }
+}
+
+impl Battito {
+    pub fn intervallo(&self) -> Duration {
+        Duration::from_millis(self.intervallo_ms.max(1).into())
+    }
+
+    pub fn scadenza(&self) -> Duration {
+        Duration::from_millis(self.scadenza_ms.into())
+    }
+}
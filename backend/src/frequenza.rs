@@ -0,0 +1,271 @@
+//! Limite alla frequenza dei messaggi, per connessione e per indirizzo IP.
+//!
+//! Ogni limite è un secchio di gettoni: si riempie di `al_secondo` gettoni
+//! al secondo fino a `raffica`, e ogni messaggio ne consuma uno. Così un
+//! client può mandare `raffica` messaggi di fila, poi non più di
+//! `al_secondo` al secondo. Le richieste del ruolo di GM, che controllano
+//! una password apposta lenta, hanno in più secchi loro, molto più piccoli.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::serde::Deserialize;
+
+/// Oltre tanti indirizzi si dimenticano quelli col secchio pieno, che
+/// valgono come nuovi. Se ne restano comunque molti, la pulizia successiva
+/// aspetta che raddoppino
+const MAX_INDIRIZZI: usize = 1024;
+
+/// Quanti messaggi si possono mandare
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Frequenza {
+    pub al_secondo: f64,
+    pub raffica: f64,
+}
+
+/// I limiti del server, dalla chiave `frequenza` della configurazione
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Limiti {
+    /// Per ogni connessione
+    pub connessione: Frequenza,
+    /// Per tutte le connessioni dallo stesso indirizzo
+    pub ip: Frequenza,
+    /// Per le richieste del ruolo di GM di ogni connessione
+    pub gm_connessione: Frequenza,
+    /// Per le richieste del ruolo di GM dallo stesso indirizzo
+    pub gm_ip: Frequenza,
+}
+
+impl Frequenza {
+    /// Perché questa frequenza non si può usare, se non si può
+    fn errore(&self) -> Option<&'static str> {
+        if !(self.al_secondo.is_finite() && self.al_secondo > 0.0) {
+            Some("al_secondo deve essere un numero positivo")
+        } else if !(self.raffica.is_finite() && self.raffica >= 1.0) {
+            Some("raffica deve essere almeno 1")
+        } else {
+            None
+        }
+    }
+}
+
+impl Limiti {
+    /// Controlla che i limiti si possano usare: con `al_secondo` nullo un
+    /// secchio vuoto non si riempirebbe mai, con `raffica` sotto 1 non
+    /// passerebbe nessun messaggio
+    pub fn valida(&self) -> Result<(), String> {
+        for (nome, frequenza) in [
+            ("connessione", self.connessione),
+            ("ip", self.ip),
+            ("gm_connessione", self.gm_connessione),
+            ("gm_ip", self.gm_ip),
+        ] {
+            if let Some(errore) = frequenza.errore() {
+                return Err(format!("frequenza.{}: {}", nome, errore));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limiti {
+    fn default() -> Self {
+        Limiti {
+            connessione: Frequenza {
+                al_secondo: 5.0,
+                raffica: 20.0,
+            },
+            ip: Frequenza {
+                al_secondo: 20.0,
+                raffica: 60.0,
+            },
+            // una password ogni dieci secondi dopo le prime tre
+            gm_connessione: Frequenza {
+                al_secondo: 0.1,
+                raffica: 3.0,
+            },
+            gm_ip: Frequenza {
+                al_secondo: 0.2,
+                raffica: 10.0,
+            },
+        }
+    }
+}
+
+/// Un secchio di gettoni, pieno all'inizio
+#[derive(Debug, Clone)]
+pub struct Secchio {
+    frequenza: Frequenza,
+    gettoni: f64,
+    ultimo: Instant,
+}
+
+impl Secchio {
+    pub fn new(frequenza: Frequenza) -> Self {
+        Secchio {
+            frequenza,
+            gettoni: frequenza.raffica,
+            ultimo: Instant::now(),
+        }
+    }
+
+    /// Consuma un gettone all'istante `adesso`; se non ce ne sono dice tra
+    /// quanto ce ne sarà uno
+    pub fn prendi(&mut self, adesso: Instant) -> Result<(), Duration> {
+        let passato = adesso.saturating_duration_since(self.ultimo).as_secs_f64();
+        self.ultimo = adesso;
+        self.gettoni =
+            (self.gettoni + passato * self.frequenza.al_secondo).min(self.frequenza.raffica);
+        if self.gettoni >= 1.0 {
+            self.gettoni -= 1.0;
+            Ok(())
+        } else {
+            let mancanti = 1.0 - self.gettoni;
+            Err(Duration::from_secs_f64(
+                mancanti / self.frequenza.al_secondo,
+            ))
+        }
+    }
+
+    /// Pieno, e quindi uguale a uno nuovo
+    fn pieno(&self, adesso: Instant) -> bool {
+        let passato = adesso.saturating_duration_since(self.ultimo).as_secs_f64();
+        self.gettoni + passato * self.frequenza.al_secondo >= self.frequenza.raffica
+    }
+}
+
+/// I secchi degli indirizzi che stanno mandando messaggi
+pub struct PerIp {
+    frequenza: Frequenza,
+    secchi: Mutex<Secchi>,
+}
+
+struct Secchi {
+    per_ip: HashMap<IpAddr, Secchio>,
+    /// Oltre quanti indirizzi fare pulizia
+    soglia: usize,
+}
+
+impl PerIp {
+    pub fn new(frequenza: Frequenza) -> Self {
+        PerIp {
+            frequenza,
+            secchi: Mutex::new(Secchi {
+                per_ip: HashMap::new(),
+                soglia: MAX_INDIRIZZI,
+            }),
+        }
+    }
+
+    /// Come [`Secchio::prendi`], dal secchio di `ip`
+    pub fn prendi(&self, ip: IpAddr, adesso: Instant) -> Result<(), Duration> {
+        let mut secchi = self.secchi.lock().unwrap();
+        if secchi.per_ip.len() > secchi.soglia {
+            secchi.per_ip.retain(|_, s| !s.pieno(adesso));
+            // una pulizia ogni tanti indirizzi nuovi quanti ne sono rimasti:
+            // in media costa poco anche quando non libera niente
+            secchi.soglia = MAX_INDIRIZZI.max(2 * secchi.per_ip.len());
+        }
+        secchi
+            .per_ip
+            .entry(ip)
+            .or_insert_with(|| Secchio::new(self.frequenza))
+            .prendi(adesso)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raffica_poi_frequenza() {
+        let frequenza = Frequenza {
+            al_secondo: 2.0,
+            raffica: 3.0,
+        };
+        let inizio = Instant::now();
+        let mut secchio = Secchio::new(frequenza);
+        for _ in 0..3 {
+            assert_eq!(secchio.prendi(inizio), Ok(()));
+        }
+        assert_eq!(secchio.prendi(inizio), Err(Duration::from_millis(500)));
+
+        // mezzo secondo dopo c'è un gettone, e uno solo
+        let dopo = inizio + Duration::from_millis(500);
+        assert_eq!(secchio.prendi(dopo), Ok(()));
+        assert!(secchio.prendi(dopo).is_err());
+
+        // e non si accumulano oltre la raffica
+        let molto_dopo = dopo + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(secchio.prendi(molto_dopo), Ok(()));
+        }
+        assert!(secchio.prendi(molto_dopo).is_err());
+    }
+
+    #[test]
+    fn un_secchio_per_indirizzo() {
+        let per_ip = PerIp::new(Frequenza {
+            al_secondo: 1.0,
+            raffica: 1.0,
+        });
+        let adesso = Instant::now();
+        let anna: IpAddr = [10, 0, 0, 1].into();
+        let bruno: IpAddr = [10, 0, 0, 2].into();
+        assert_eq!(per_ip.prendi(anna, adesso), Ok(()));
+        assert!(per_ip.prendi(anna, adesso).is_err());
+        assert_eq!(per_ip.prendi(bruno, adesso), Ok(()));
+    }
+
+    #[test]
+    fn indirizzi_dimenticati_a_blocchi() {
+        let per_ip = PerIp::new(Frequenza {
+            al_secondo: 1.0,
+            raffica: 1.0,
+        });
+        let adesso = Instant::now();
+        let indirizzo = |i: usize| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+        let ricordati = || per_ip.secchi.lock().unwrap().per_ip.len();
+        for i in 0..=MAX_INDIRIZZI {
+            per_ip.prendi(indirizzo(i), adesso).unwrap();
+        }
+        // nessuno è pieno: la pulizia non libera niente e la prossima
+        // aspetta il doppio degli indirizzi
+        per_ip.prendi(indirizzo(MAX_INDIRIZZI + 1), adesso).unwrap();
+        assert_eq!(ricordati(), MAX_INDIRIZZI + 2);
+
+        // raddoppiati, alla pulizia se ne vanno tutti quelli tornati pieni
+        let dopo = adesso + Duration::from_secs(1);
+        for i in MAX_INDIRIZZI + 2..=2 * (MAX_INDIRIZZI + 1) {
+            per_ip.prendi(indirizzo(i), dopo).unwrap();
+        }
+        assert_eq!(ricordati(), 2 * (MAX_INDIRIZZI + 1) + 1);
+        // restano i nuovi, più quello che ha appena mandato un messaggio
+        per_ip.prendi(indirizzo(0), dopo).unwrap();
+        assert_eq!(ricordati(), MAX_INDIRIZZI + 2);
+    }
+
+    #[test]
+    fn limiti_inutilizzabili() {
+        assert_eq!(Limiti::default().valida(), Ok(()));
+        let mut limiti = Limiti::default();
+        limiti.ip.al_secondo = 0.0;
+        assert!(limiti.valida().unwrap_err().contains("frequenza.ip"));
+        limiti.ip.al_secondo = f64::NAN;
+        assert!(limiti.valida().is_err());
+        let mut limiti = Limiti::default();
+        limiti.connessione.raffica = 0.5;
+        assert!(limiti
+            .valida()
+            .unwrap_err()
+            .contains("frequenza.connessione"));
+        let mut limiti = Limiti::default();
+        limiti.gm_ip.al_secondo = -1.0;
+        assert!(limiti.valida().unwrap_err().contains("frequenza.gm_ip"));
+    }
+}
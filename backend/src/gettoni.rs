@@ -0,0 +1,85 @@
+//! Gettoni di sessione: dicono al server chi c'è dietro una connessione,
+//! così nessuno può scrivere col nome di un altro.
+//!
+//! Un gettone è `<identità>.<firma>`: l'identità è un numero casuale, la
+//! firma il suo HMAC-SHA256 con la chiave del server. Il client lo riceve
+//! alla prima connessione e lo ripresenta alle successive; in ogni stanza
+//! un nome appartiene alla prima identità che l'ha usato.
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// Byte casuali di un'identità
+const LUNGHEZZA_IDENTITA: usize = 16;
+
+/// Firma e controlla i gettoni con la chiave del server
+pub struct Gettoni {
+    chiave: [u8; 32],
+}
+
+impl Gettoni {
+    pub fn new(chiave: [u8; 32]) -> Self {
+        Gettoni { chiave }
+    }
+
+    /// Un'identità nuova e il suo gettone
+    pub fn emetti(&self) -> (String, String) {
+        let mut byte = [0; LUNGHEZZA_IDENTITA];
+        OsRng.fill_bytes(&mut byte);
+        let identita = hex::encode(byte);
+        let gettone = format!("{}.{}", identita, hex::encode(self.firma(&identita)));
+        (identita, gettone)
+    }
+
+    /// L'identità di un gettone emesso con questa chiave; `None` se è
+    /// malformato o la firma non torna
+    pub fn verifica(&self, gettone: &str) -> Option<String> {
+        let (identita, firma) = gettone.split_once('.')?;
+        let firma = hex::decode(firma).ok()?;
+        if hex::decode(identita).ok()?.len() != LUNGHEZZA_IDENTITA {
+            return None;
+        }
+        self.mac(identita).verify_slice(&firma).ok()?;
+        Some(identita.to_string())
+    }
+
+    fn firma(&self, identita: &str) -> Vec<u8> {
+        self.mac(identita).finalize().into_bytes().to_vec()
+    }
+
+    fn mac(&self, identita: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.chiave)
+            .expect("HMAC accetta chiavi di ogni lunghezza");
+        mac.update(identita.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gettoni_firmati() {
+        let gettoni = Gettoni::new([7; 32]);
+        let (identita, gettone) = gettoni.emetti();
+        assert_eq!(gettoni.verifica(&gettone), Some(identita.clone()));
+        assert_ne!(gettoni.emetti().0, identita);
+
+        // un'altra chiave, una firma cambiata o un'identità inventata non
+        // passano
+        assert_eq!(Gettoni::new([8; 32]).verifica(&gettone), None);
+        let mut alterato = gettone.clone();
+        let ultimo = if alterato.ends_with('0') { "1" } else { "0" };
+        alterato.replace_range(alterato.len() - 1.., ultimo);
+        assert_eq!(gettoni.verifica(&alterato), None);
+        let firma = gettone.split_once('.').unwrap().1;
+        assert_eq!(
+            gettoni.verifica(&format!("{}.{}", "ab".repeat(16), firma)),
+            None
+        );
+        assert_eq!(gettoni.verifica("Anna"), None);
+    }
+}
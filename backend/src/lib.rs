@@ -0,0 +1,121 @@
+//! Server di riferimento del tavolo di 7th Sea.
+//!
+//! Parla il protocollo di `seven_seas_protocol` su un WebSocket in
+//! `/ws/`, o con eventi SSE in `/sse` per chi non riesce ad aprirlo, e
+//! offre un'API REST sotto `/api` per tirare e leggere le stanze. Divide i
+//! giocatori in stanze e per ognuna tiene il log dei messaggi, fa i tiri e
+//! gestisce le sessioni del seme per i tiri verificabili. A chi si collega
+//! dà un gettone firmato che lega il suo nome a lui solo, e chiude le
+//! connessioni rimaste in silenzio. Stanze e log sono salvati in un
+//! [`Archivio`] SQLite e ricaricati al riavvio.
+
+use std::sync::Arc;
+
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use rocket::{Build, Rocket};
+
+pub mod api;
+pub mod archivio;
+pub mod battito;
+pub mod frequenza;
+pub mod gettoni;
+pub mod password;
+pub mod sessione;
+pub mod sse;
+pub mod stanza;
+pub mod stato;
+pub mod ws;
+
+pub use archivio::Archivio;
+pub use battito::Battito;
+pub use frequenza::Limiti;
+pub use stanza::StatoStanza;
+pub use stato::StatoServer;
+
+/// File dell'archivio se la configurazione non ne indica un altro
+pub const ARCHIVIO_PREDEFINITO: &str = "seven_seas.db";
+
+/// Il server configurato da `Rocket.toml` e dalle variabili `ROCKET_*`.
+/// L'archivio è nel file della chiave `archivio` (`ROCKET_ARCHIVIO`), o in
+/// [`ARCHIVIO_PREDEFINITO`]; con `":memory:"` non si salva niente. La
+/// chiave `frequenza` cambia i [`Limiti`] ai messaggi dei client, la chiave
+/// `battito` il [`Battito`] delle connessioni
+pub fn rocket() -> Rocket<Build> {
+    rocket::build()
+        .attach(AdHoc::try_on_ignite("Archivio", |rocket| async {
+            let percorso = rocket
+                .figment()
+                .extract_inner::<String>("archivio")
+                .unwrap_or_else(|_| ARCHIVIO_PREDEFINITO.to_string());
+            let limiti = match rocket.figment().extract_inner::<Limiti>("frequenza") {
+                Ok(limiti) => limiti,
+                Err(e) if e.missing() => Limiti::default(),
+                Err(e) => {
+                    rocket::error!("Configurazione di frequenza non valida: {}", e);
+                    return Err(rocket);
+                }
+            };
+            if let Err(e) = limiti.valida() {
+                rocket::error!("Configurazione di frequenza non valida: {}", e);
+                return Err(rocket);
+            }
+            let battito = match rocket.figment().extract_inner::<Battito>("battito") {
+                Ok(battito) => battito,
+                Err(e) if e.missing() => Battito::default(),
+                Err(e) => {
+                    rocket::error!("Configurazione del battito non valida: {}", e);
+                    return Err(rocket);
+                }
+            };
+            // leggere l'archivio, e rifare le impronte delle password salvate
+            // in chiaro, blocca: fuori dai thread che serviranno le richieste
+            let aperto = percorso.clone();
+            let stato = rocket::tokio::task::spawn_blocking(move || {
+                Archivio::apri(&aperto)
+                    .and_then(|archivio| StatoServer::con_archivio(Arc::new(archivio)))
+            })
+            .await
+            .expect("l'apertura dell'archivio non va in panico");
+            match stato {
+                Ok(stato) => Ok(rocket.manage(stato.con_limiti(limiti).con_battito(battito))),
+                Err(e) => {
+                    rocket::error!("Archivio {} non utilizzabile: {}", percorso, e);
+                    Err(rocket)
+                }
+            }
+        }))
+        // le modifiche ancora in coda finiscono nell'archivio prima di uscire
+        .attach(AdHoc::on_shutdown("Archivio", |rocket| {
+            Box::pin(async move {
+                if let Some(stato) = rocket.state::<StatoServer>() {
+                    stato.archiviato().await;
+                }
+            })
+        }))
+        // il frontend sta su un altro dominio: gli eventi SSE, le POST e
+        // l'API devono poterli leggere tutti
+        .attach(AdHoc::on_response("CORS", |_, risposta| {
+            Box::pin(async move {
+                risposta.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+                risposta.set_header(Header::new(
+                    "Access-Control-Allow-Methods",
+                    "GET, POST, OPTIONS",
+                ));
+                risposta.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type"));
+            })
+        }))
+        .manage(sse::Sessioni::default())
+        .mount("/", rocket::routes![ws::ws, sse::sse, sse::invia])
+        .mount(
+            "/",
+            rocket::routes![
+                api::tira,
+                api::stanza,
+                api::storico,
+                api::openapi,
+                api::preliminare
+            ],
+        )
+        .register("/api", rocket::catchers![api::errore_api])
+}
@@ -0,0 +1,4 @@
+#[rocket::launch]
+fn rocket() -> _ {
+    seven_seas_backend::rocket()
+}
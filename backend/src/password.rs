@@ -0,0 +1,149 @@
+//! Impronte delle password del GM: l'archivio non tiene le password, ma un
+//! PBKDF2-HMAC-SHA256 con un sale casuale per ognuna.
+//!
+//! Un'impronta è `pbkdf2-sha256$<iterazioni>$<sale>$<chiave>`, con sale e
+//! chiave in esadecimale: le iterazioni restano scritte, così si possono
+//! aumentare senza rendere inutili le impronte già salvate.
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::tokio::task;
+use sha2::Sha256;
+
+const SCHEMA: &str = "pbkdf2-sha256";
+/// Iterazioni per le impronte nuove
+const ITERAZIONI: u32 = 100_000;
+const LUNGHEZZA_SALE: usize = 16;
+
+/// L'impronta di `password`, con un sale nuovo
+pub fn impronta(password: &str) -> String {
+    let mut sale = [0; LUNGHEZZA_SALE];
+    OsRng.fill_bytes(&mut sale);
+    let chiave = pbkdf2(password, &sale, ITERAZIONI);
+    format!(
+        "{}${}${}${}",
+        SCHEMA,
+        ITERAZIONI,
+        hex::encode(sale),
+        hex::encode(chiave)
+    )
+}
+
+/// Se `password` è quella da cui viene `impronta`; `false` anche se
+/// l'impronta è malformata
+pub fn verifica(impronta: &str, password: &str) -> bool {
+    let Some((iterazioni, sale, chiave)) = leggi(impronta) else {
+        return false;
+    };
+    let calcolata = pbkdf2(password, &sale, iterazioni);
+    // confronto a tempo costante: il tempo non dice quanti byte tornano
+    chiave.len() == calcolata.len()
+        && chiave
+            .iter()
+            .zip(calcolata)
+            .fold(0, |diversi, (a, b)| diversi | (a ^ b))
+            == 0
+}
+
+/// Come [`impronta`], su un thread che può bloccarsi: le iterazioni
+/// durano decine di millisecondi, e non devono fermare le connessioni
+pub async fn impronta_a_parte(password: String) -> String {
+    task::spawn_blocking(move || impronta(&password))
+        .await
+        .expect("il calcolo di un'impronta non va in panico")
+}
+
+/// Come [`verifica`], su un thread che può bloccarsi
+pub async fn verifica_a_parte(impronta: String, password: String) -> bool {
+    task::spawn_blocking(move || verifica(&impronta, &password))
+        .await
+        .expect("il calcolo di un'impronta non va in panico")
+}
+
+/// Se `testo` è un'impronta, e non una password salvata in chiaro da una
+/// versione precedente del server
+pub fn e_impronta(testo: &str) -> bool {
+    leggi(testo).is_some()
+}
+
+fn leggi(impronta: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut parti = impronta.split('$');
+    if parti.next()? != SCHEMA {
+        return None;
+    }
+    let iterazioni = parti.next()?.parse().ok().filter(|&n| n > 0)?;
+    let sale = hex::decode(parti.next()?).ok()?;
+    let chiave = hex::decode(parti.next()?).ok()?;
+    match parti.next() {
+        None => Some((iterazioni, sale, chiave)),
+        Some(_) => None,
+    }
+}
+
+/// Il primo blocco di PBKDF2-HMAC-SHA256 (RFC 8018), lungo quanto un SHA-256
+fn pbkdf2(password: &str, sale: &[u8], iterazioni: u32) -> [u8; 32] {
+    let chiave = Hmac::<Sha256>::new_from_slice(password.as_bytes())
+        .expect("HMAC accetta chiavi di ogni lunghezza");
+    let mut mac = chiave.clone();
+    mac.update(sale);
+    mac.update(&1u32.to_be_bytes());
+    let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut risultato = u;
+    for _ in 1..iterazioni {
+        let mut mac = chiave.clone();
+        mac.update(&u);
+        u = mac.finalize().into_bytes().into();
+        risultato.iter_mut().zip(u).for_each(|(r, u)| *r ^= u);
+    }
+    risultato
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vettore_noto() {
+        // RFC 7914, § 11
+        let chiave = pbkdf2("passwd", b"salt", 1);
+        assert_eq!(
+            hex::encode(&chiave[..16]),
+            "55ac046e56e3089fec1691c22544b605"
+        );
+        // i vettori di RFC 6070 rifatti con SHA-256: più iterazioni
+        // controllano anche lo XOR dei blocchi successivi
+        for (iterazioni, attesa) in [
+            (
+                1,
+                "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b",
+            ),
+            (
+                2,
+                "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43",
+            ),
+            (
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+        ] {
+            let chiave = pbkdf2("password", b"salt", iterazioni);
+            assert_eq!(hex::encode(chiave), attesa, "{} iterazioni", iterazioni);
+        }
+    }
+
+    #[test]
+    fn impronte_salate() {
+        let salvata = impronta("segreta");
+        assert!(e_impronta(&salvata));
+        assert!(!salvata.contains("segreta"));
+        assert!(verifica(&salvata, "segreta"));
+        assert!(!verifica(&salvata, "Segreta"));
+        // lo stesso sale non torna due volte
+        assert_ne!(impronta("segreta"), salvata);
+
+        assert!(!e_impronta("segreta"));
+        assert!(!verifica("segreta", "segreta"));
+        assert!(!verifica(&format!("{}$extra", salvata), "segreta"));
+    }
+}
@@ -0,0 +1,118 @@
+//! Una connessione di un client, qualunque sia il trasporto: il WebSocket
+//! di [`crate::ws`] o gli eventi SSE e le POST di [`crate::sse`].
+//!
+//! Il trasporto porta i testi del client a [`Sessione::ricevuto`] e manda
+//! al client, nell'ordine, tutti i [`ServerMessage`] che ne escono, compresi
+//! quelli di [`Sessione::apri`] e di [`Sessione::aggiornamento`].
+
+use std::net::IpAddr;
+
+use rocket::tokio::sync::broadcast::error::RecvError;
+use seven_seas_protocol::limiti::MAX_BYTE_MESSAGGIO;
+use seven_seas_protocol::{
+    valida_username, ClientMessage, ErroreMessaggio, Rifiuto, ServerMessage,
+};
+
+use crate::frequenza::Secchio;
+use crate::stato::{Posto, StatoServer};
+
+/// Un client seduto in una stanza, coi suoi limiti alla frequenza
+pub struct Sessione<'r> {
+    stato: &'r StatoServer,
+    posto: Posto,
+    secchio: Secchio,
+    /// Per le richieste del ruolo di GM, oltre a `secchio`
+    secchio_gm: Secchio,
+    ip: Option<IpAddr>,
+}
+
+impl<'r> Sessione<'r> {
+    /// Fa entrare un client nella stanza `room` (o in quella comune) e
+    /// restituisce i primi messaggi da mandargli: il gettone nuovo se non ne
+    /// ha presentato uno valido, la stanza col suo log, e il rifiuto del nome
+    /// se non è valido, nel qual caso entra senza
+    pub fn apri(
+        stato: &'r StatoServer,
+        room: Option<&str>,
+        username: Option<String>,
+        after: Option<u64>,
+        token: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> (Self, Vec<ServerMessage>) {
+        let (identita, nuovo) = stato.identifica(token);
+        let (username, rifiuto) = match username.filter(|u| !u.is_empty()) {
+            Some(username) => match valida_username(&username) {
+                Ok(()) => (Some(username), None),
+                Err(rifiuto) => (None, Some(ServerMessage::Rejected(rifiuto))),
+            },
+            None => (None, None),
+        };
+        let (posto, iniziali) = stato.siedi(room, username, identita, after);
+        let messaggi = nuovo
+            .map(ServerMessage::SessionToken)
+            .into_iter()
+            .chain(iniziali)
+            .chain(rifiuto)
+            .collect();
+        let sessione = Sessione {
+            stato,
+            posto,
+            secchio: stato.secchio(),
+            secchio_gm: stato.secchio_gm(),
+            ip,
+        };
+        (sessione, messaggi)
+    }
+
+    /// Gestisce un testo del client, se sta nei limiti; restituisce le
+    /// risposte per lui solo
+    pub async fn ricevuto(&mut self, txt: &str) -> Vec<ServerMessage> {
+        match self.stato.consenti(&mut self.secchio, self.ip) {
+            Ok(()) => self.rispondi(txt).await,
+            Err(rifiuto) => vec![ServerMessage::Rejected(rifiuto)],
+        }
+    }
+
+    async fn rispondi(&mut self, txt: &str) -> Vec<ServerMessage> {
+        if txt.len() > MAX_BYTE_MESSAGGIO {
+            return vec![ServerMessage::Rejected(Rifiuto::MessaggioTroppoGrande {
+                massimo: MAX_BYTE_MESSAGGIO,
+            })];
+        }
+        let msg = match ClientMessage::da_json(txt) {
+            Ok(msg) => msg,
+            // probabilmente un client più nuovo: lo avvisiamo e andiamo avanti
+            Err(ErroreMessaggio::Sconosciuto(tipo)) => {
+                return vec![ServerMessage::System(format!(
+                    "Messaggio {} non supportato da questo server",
+                    tipo
+                ))]
+            }
+            Err(e) => return vec![ServerMessage::System(format!("{}", e))],
+        };
+        let consentito = msg.valida().and_then(|()| match msg {
+            ClientMessage::ClaimGm { .. } => self.stato.consenti_gm(&mut self.secchio_gm, self.ip),
+            _ => Ok(()),
+        });
+        match consentito {
+            Ok(()) => self.stato.gestisci(&mut self.posto, msg).await,
+            Err(rifiuto) => vec![ServerMessage::Rejected(rifiuto)],
+        }
+    }
+
+    /// I prossimi messaggi della stanza per questo client; `None` se la
+    /// stanza non ne manderà più
+    pub async fn aggiornamento(&mut self) -> Option<Vec<ServerMessage>> {
+        match self.posto.aggiornamenti.recv().await {
+            Ok(msg) => Some(self.stato.aggiornamento(&mut self.posto, msg)),
+            // rimasto indietro: meglio il log completo che buchi
+            Err(RecvError::Lagged(_)) => Some(vec![self.posto.storico()]),
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+/// Dopo questo messaggio il server chiude la connessione
+pub fn ultimo(msg: &ServerMessage) -> bool {
+    matches!(msg, ServerMessage::Incompatible { .. })
+}
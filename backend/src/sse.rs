@@ -0,0 +1,145 @@
+//! Trasporto di riserva per chi non riesce ad aprire un WebSocket, per
+//! esempio dietro un proxy che non lo lascia passare: gli stessi messaggi
+//! del protocollo, dal server come eventi SSE su `GET /sse` e dal client
+//! come `POST /sse/<sessione>`.
+//!
+//! Il primo evento, di tipo `sessione`, porta il codice a cui mandare le
+//! POST; gli altri sono [`ServerMessage`] in JSON, come sul WebSocket. Le
+//! risposte alle POST arrivano anche loro come eventi, nell'ordine.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::time::{interval_at, Instant};
+use rocket::State;
+use seven_seas_protocol::limiti::MAX_BYTE_MESSAGGIO;
+use seven_seas_protocol::ServerMessage;
+
+use crate::sessione::{ultimo, Sessione};
+use crate::stato::StatoServer;
+
+/// Testi del client in attesa di essere gestiti: oltre, le POST vengono
+/// rifiutate
+const MAX_IN_ATTESA: usize = 32;
+
+/// Le sessioni SSE aperte, per portare a ognuna le sue POST
+#[derive(Default)]
+pub struct Sessioni(Mutex<HashMap<String, mpsc::Sender<String>>>);
+
+impl Sessioni {
+    /// Una sessione nuova: il suo codice e da dove arrivano le sue POST.
+    /// Resta aperta finché non si scarta l'[`Iscrizione`]
+    fn apri(&self) -> (Iscrizione<'_>, mpsc::Receiver<String>) {
+        let mut byte = [0; 16];
+        OsRng.fill_bytes(&mut byte);
+        let codice = hex::encode(byte);
+        let (invio, ricevuti) = mpsc::channel(MAX_IN_ATTESA);
+        self.0.lock().unwrap().insert(codice.clone(), invio);
+        let iscrizione = Iscrizione {
+            sessioni: self,
+            codice,
+        };
+        (iscrizione, ricevuti)
+    }
+
+    /// Porta `txt` alla sessione `codice`
+    fn inoltra(&self, codice: &str, txt: String) -> Status {
+        let invio = self.0.lock().unwrap().get(codice).cloned();
+        match invio.map(|invio| invio.try_send(txt)) {
+            Some(Ok(())) => Status::Accepted,
+            Some(Err(mpsc::error::TrySendError::Full(_))) => Status::TooManyRequests,
+            Some(Err(mpsc::error::TrySendError::Closed(_))) | None => Status::NotFound,
+        }
+    }
+}
+
+/// Una sessione aperta in [`Sessioni`], che si chiude quando viene scartata
+struct Iscrizione<'a> {
+    sessioni: &'a Sessioni,
+    codice: String,
+}
+
+impl Drop for Iscrizione<'_> {
+    fn drop(&mut self) {
+        self.sessioni.0.lock().unwrap().remove(&self.codice);
+    }
+}
+
+/// Come [`ws`](crate::ws::ws), ma i messaggi arrivano come eventi SSE e il
+/// client scrive con [`invia`] al codice del primo evento
+#[rocket::get("/sse?<room>&<username>&<after>&<token>")]
+pub fn sse<'r>(
+    room: Option<&'r str>,
+    username: Option<String>,
+    after: Option<u64>,
+    token: Option<&'r str>,
+    ip: Option<IpAddr>,
+    stato: &'r State<StatoServer>,
+    sessioni: &'r State<Sessioni>,
+) -> EventStream![Event + 'r] {
+    let battito = stato.battito();
+    EventStream! {
+        let (iscrizione, mut ricevuti) = sessioni.apri();
+        yield Event::data(iscrizione.codice.clone()).event("sessione");
+        let (mut sessione, iniziali) = Sessione::apri(stato, room, username, after, token, ip);
+        for msg in &iniziali {
+            yield evento(msg);
+        }
+        let mut tic = interval_at(Instant::now() + battito.intervallo(), battito.intervallo());
+        let mut sentito = Instant::now();
+
+        loop {
+            // None: la connessione è finita
+            let messaggi = select! {
+                txt = ricevuti.recv() => match txt {
+                    Some(txt) => {
+                        sentito = Instant::now();
+                        Some(sessione.ricevuto(&txt).await)
+                    }
+                    None => None,
+                },
+                aggiornamento = sessione.aggiornamento() => aggiornamento,
+                // il client è sparito: lasciando il posto risulta scollegato
+                _ = tic.tick() => {
+                    (sentito.elapsed() <= battito.scadenza()).then(Vec::new)
+                }
+            };
+            let Some(messaggi) = messaggi else { break };
+            for msg in &messaggi {
+                yield evento(msg);
+            }
+            if messaggi.iter().any(ultimo) {
+                break;
+            }
+        }
+    }
+    .heartbeat(battito.intervallo())
+}
+
+/// Un messaggio del client per la sessione SSE `sessione`. Risponde
+/// `202` se la sessione lo gestirà, `404` se non è aperta e `429` se ha già
+/// troppi messaggi da gestire
+#[rocket::post("/sse/<sessione>", data = "<dati>")]
+pub async fn invia(sessione: &str, dati: Data<'_>, sessioni: &State<Sessioni>) -> Status {
+    // uno in più del massimo, così la sessione vede che è troppo grande
+    match dati
+        .open((MAX_BYTE_MESSAGGIO + 1).bytes())
+        .into_bytes()
+        .await
+    {
+        Ok(byte) => sessioni.inoltra(sessione, String::from_utf8_lossy(&byte).into_owned()),
+        Err(_) => Status::BadRequest,
+    }
+}
+
+fn evento(msg: &ServerMessage) -> Event {
+    Event::data(serde_json::to_string(msg).expect("ServerMessage si serializza sempre"))
+}
@@ -0,0 +1,676 @@
+//! Una stanza del server: log dei messaggi, tiri, sessione di seme,
+//! giocatori presenti e GM. Tutto passa per [`StatoStanza::per`], che copre
+//! i tiri nascosti a chi non li deve vedere. Ogni nome appartiene
+//! all'identità che l'ha usato per prima (vedi [`crate::gettoni`]), e si
+//! scrive e si tira solo col nome con cui si è entrati. Log, GM, nomi,
+//! giocatori mandati via e sessione vengono salvati nell'[`Archivio`] a
+//! ogni modifica.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::tokio::sync::broadcast;
+use rocket::tokio::task;
+use seven_seas_core::{RollResult, SemeSegreto, Tavolo, TiroPrenotato};
+use seven_seas_protocol::api::PaginaStorico;
+use seven_seas_protocol::{
+    ChatMessage, ClientMessage, Giocatore, Moderazione, ServerMessage, Stanza, PAGINA_LOG,
+};
+
+use crate::archivio::{DatiStanza, Scrittore, Scrittura, StanzaSalvata};
+use crate::password;
+
+/// Messaggi in coda per ogni client prima che debba riallinearsi col log
+/// completo
+const CAPACITA_CANALE: usize = 64;
+
+const SOLO_GM: &str = "Solo il GM della stanza può farlo";
+
+/// Una stanza condivisa dalle connessioni che ci sono entrate
+pub struct StatoStanza {
+    codice: String,
+    nome: String,
+    interno: Mutex<Interno>,
+    canale: broadcast::Sender<ServerMessage>,
+    scrittore: Arc<Scrittore>,
+}
+
+struct Interno {
+    /// La riga con `seq` n è in posizione n - `cancellati` - 1
+    storico: Vec<ChatMessage>,
+    /// Righe tolte dal GM svuotando il log: i `seq` nuovi continuano da qui
+    cancellati: u64,
+    tavolo: Tavolo,
+    /// Ingressi presenti, col nome del giocatore se l'ha detto
+    membri: BTreeMap<u64, Option<String>>,
+    /// Ultima attività di ogni giocatore passato dalla stanza
+    attivita: BTreeMap<String, u64>,
+    /// L'identità a cui appartiene ogni nome usato nella stanza
+    nomi: BTreeMap<String, String>,
+    /// I nomi mandati via dal GM, con l'identità a cui appartenevano: non
+    /// rientrano né l'uno né l'altra
+    banditi: BTreeMap<String, String>,
+    gm: Option<String>,
+    /// L'[impronta](crate::password) della password per prendere il ruolo
+    /// di GM; senza, lo tiene chi ha creato la stanza
+    impronta_gm: Option<String>,
+    bloccata: bool,
+}
+
+impl Interno {
+    /// Numera la riga, la aggiunge al log e restituisce l'evento da mandare
+    fn aggiungi(&mut self, mut msg: ChatMessage) -> ServerMessage {
+        msg.seq = Some(self.cancellati + self.storico.len() as u64 + 1);
+        msg.timestamp = Some(adesso());
+        self.storico.push(msg.clone());
+        ServerMessage::NewMessage(msg)
+    }
+
+    /// Le ultime righe del log, quelle che si mandano entrando
+    fn ultime(&self) -> ServerMessage {
+        let inizio = self.storico.len().saturating_sub(PAGINA_LOG);
+        ServerMessage::FullHistory(self.storico[inizio..].to_vec())
+    }
+
+    /// Le righe dopo `seq`; le ultime se `seq` non è ancora stato assegnato
+    /// (es. il client l'ha visto prima di un riavvio del server), se il
+    /// client può avere righe che il GM ha cancellato o se gliene mancano
+    /// più di una pagina
+    fn dopo(&self, seq: u64) -> ServerMessage {
+        let visti = match seq.checked_sub(self.cancellati) {
+            Some(visti) if visti > 0 || self.cancellati == 0 => usize::try_from(visti).ok(),
+            _ => None,
+        };
+        match visti {
+            Some(visti)
+                if visti <= self.storico.len() && self.storico.len() - visti <= PAGINA_LOG =>
+            {
+                ServerMessage::Resumed(self.storico[visti..].to_vec())
+            }
+            _ => self.ultime(),
+        }
+    }
+
+    /// Al più `limite` righe prima di quella con `seq` uguale a `prima`
+    fn prima(&self, prima: u64, limite: usize) -> ServerMessage {
+        let fine = prima
+            .saturating_sub(self.cancellati + 1)
+            .min(self.storico.len() as u64) as usize;
+        let inizio = fine.saturating_sub(limite.min(PAGINA_LOG));
+        ServerMessage::OlderHistory {
+            righe: self.storico[inizio..fine].to_vec(),
+            altre: inizio > 0,
+        }
+    }
+
+    /// La riga del log con questo `seq`, se c'è ancora
+    fn riga(&mut self, seq: u64) -> Option<&mut ChatMessage> {
+        let posizione = seq.checked_sub(self.cancellati + 1)?;
+        self.storico.get_mut(usize::try_from(posizione).ok()?)
+    }
+
+    /// Segna che il giocatore entrato con `id` ha appena fatto qualcosa;
+    /// restituisce il suo nome, se l'ha detto
+    fn tocca(&mut self, id: u64) -> Option<String> {
+        let username = self.membri.get(&id).cloned().flatten()?;
+        self.attivita.insert(username.clone(), adesso());
+        Some(username)
+    }
+
+    /// Se `username` può vedere `tiro`
+    fn vede(&self, tiro: &RollResult, username: Option<&str>) -> bool {
+        tiro.visibilita
+            .as_ref()
+            .is_none_or(|v| v.vede(&tiro.username, username, self.gm.as_deref()))
+    }
+
+    /// `msg` come lo deve ricevere `username`: le righe coi tiri che non può
+    /// vedere sono coperte, i loro esiti non arrivano
+    fn per(&self, msg: ServerMessage, username: Option<&str>) -> Option<ServerMessage> {
+        let copri = |riga: ChatMessage| match &riga.tiro {
+            Some(tiro) if !self.vede(tiro, username) => riga.coperta(),
+            _ => riga,
+        };
+        Some(match msg {
+            ServerMessage::NewMessage(riga) => ServerMessage::NewMessage(copri(riga)),
+            ServerMessage::EntryUpdated(riga) => ServerMessage::EntryUpdated(copri(riga)),
+            ServerMessage::FullHistory(righe) => {
+                ServerMessage::FullHistory(righe.into_iter().map(copri).collect())
+            }
+            ServerMessage::Resumed(righe) => {
+                ServerMessage::Resumed(righe.into_iter().map(copri).collect())
+            }
+            ServerMessage::OlderHistory { righe, altre } => ServerMessage::OlderHistory {
+                righe: righe.into_iter().map(copri).collect(),
+                altre,
+            },
+            ServerMessage::RollResult(tiro) if !self.vede(&tiro, username) => return None,
+            altro => altro,
+        })
+    }
+
+    fn presenze(&self) -> ServerMessage {
+        let giocatori = self
+            .attivita
+            .iter()
+            .map(|(username, &ultima_attivita)| Giocatore {
+                username: username.clone(),
+                collegato: self.membri.values().any(|m| m.as_ref() == Some(username)),
+                ultima_attivita,
+            })
+            .collect();
+        ServerMessage::Presence(giocatori)
+    }
+}
+
+/// Cosa resta da fare per un messaggio dopo [`StatoStanza::elabora`]
+enum Passo {
+    /// Niente: c'è al più la risposta per il mittente
+    Risposta(Option<ServerMessage>),
+    /// Fare il tiro prenotato e mandarlo a tutti
+    Tira(Box<TiroPrenotato>),
+}
+
+/// Un messaggio di sistema per il solo mittente
+fn risposta(testo: impl Into<String>) -> Passo {
+    Passo::Risposta(Some(ServerMessage::System(testo.into())))
+}
+
+impl StatoStanza {
+    /// Una stanza vuota, subito salvata con `scrittore`; `gm` ne è il GM da
+    /// subito, chi conosce la password di `impronta_gm` lo può diventare
+    pub fn new(
+        codice: impl Into<String>,
+        nome: impl Into<String>,
+        gm: Option<String>,
+        impronta_gm: Option<String>,
+        scrittore: Arc<Scrittore>,
+    ) -> Self {
+        let stanza = Self::con_interno(
+            codice.into(),
+            nome.into(),
+            Interno {
+                storico: Vec::new(),
+                cancellati: 0,
+                tavolo: Tavolo::new(),
+                membri: BTreeMap::new(),
+                attivita: BTreeMap::new(),
+                nomi: BTreeMap::new(),
+                banditi: BTreeMap::new(),
+                gm,
+                impronta_gm,
+                bloccata: false,
+            },
+            scrittore,
+        );
+        stanza.salva(&stanza.interno.lock().unwrap());
+        stanza
+    }
+
+    /// Una stanza salvata, che da qui salva con `scrittore`. Se il seme
+    /// salvato non è leggibile si apre una sessione nuova; una password del
+    /// GM salvata in chiaro viene sostituita dalla sua impronta
+    pub fn ripristina(salvata: StanzaSalvata, scrittore: Arc<Scrittore>) -> Self {
+        let StanzaSalvata {
+            dati,
+            storico,
+            nomi,
+            banditi,
+        } = salvata;
+        let in_chiaro = dati
+            .impronta_gm
+            .as_deref()
+            .is_some_and(|p| !password::e_impronta(p));
+        let impronta_gm = match dati.impronta_gm {
+            Some(p) if in_chiaro => Some(password::impronta(&p)),
+            impronta => impronta,
+        };
+        let tavolo = match SemeSegreto::da_hex(&dati.seme) {
+            Ok(seme) => Tavolo::riprendi(seme, dati.contatore),
+            Err(e) => {
+                rocket::warn!("Seme della stanza {} non valido: {}", dati.codice, e);
+                Tavolo::new()
+            }
+        };
+        let stanza = Self::con_interno(
+            dati.codice,
+            dati.nome,
+            Interno {
+                storico,
+                cancellati: dati.cancellati,
+                tavolo,
+                membri: BTreeMap::new(),
+                attivita: BTreeMap::new(),
+                nomi,
+                banditi,
+                gm: dati.gm,
+                impronta_gm,
+                bloccata: dati.bloccata,
+            },
+            scrittore,
+        );
+        if in_chiaro {
+            stanza.salva(&stanza.interno.lock().unwrap());
+        }
+        stanza
+    }
+
+    fn con_interno(
+        codice: String,
+        nome: String,
+        interno: Interno,
+        scrittore: Arc<Scrittore>,
+    ) -> Self {
+        let (canale, _) = broadcast::channel(CAPACITA_CANALE);
+        StatoStanza {
+            codice,
+            nome,
+            interno: Mutex::new(interno),
+            canale,
+            scrittore,
+        }
+    }
+
+    pub fn codice(&self) -> &str {
+        &self.codice
+    }
+
+    /// Se `username` può entrare: in una stanza bloccata entrano solo il GM
+    /// e chi ci era già stato, anche prima di un riavvio (chi è stato
+    /// mandato via non entra comunque, vedi [`Self::bandito`])
+    pub fn aperta_a(&self, username: Option<&str>) -> bool {
+        let interno = self.interno.lock().unwrap();
+        !interno.bloccata
+            || username
+                .is_some_and(|u| interno.gm.as_deref() == Some(u) || interno.nomi.contains_key(u))
+    }
+
+    /// Se il GM ha mandato via `username` o `identita`
+    pub fn bandito(&self, username: Option<&str>, identita: &str) -> bool {
+        let interno = self.interno.lock().unwrap();
+        username.is_some_and(|u| interno.banditi.contains_key(u))
+            || interno.banditi.values().any(|i| i == identita)
+    }
+
+    /// Riserva `username` a `identita`, se nessun altro l'ha già usato in
+    /// questa stanza; `false` se è di qualcun altro
+    pub fn prenota(&self, username: &str, identita: &str) -> bool {
+        let mut interno = self.interno.lock().unwrap();
+        match interno.nomi.get(username) {
+            Some(proprietario) => proprietario == identita,
+            None => {
+                interno
+                    .nomi
+                    .insert(username.to_string(), identita.to_string());
+                self.scrivi(Scrittura::Nome {
+                    stanza: self.codice.clone(),
+                    username: username.to_string(),
+                    identita: identita.to_string(),
+                });
+                true
+            }
+        }
+    }
+
+    /// Fa entrare una connessione col numero di ingresso `id`: restituisce i
+    /// messaggi da mandarle subito (la stanza, le ultime righe del log o
+    /// quelle dopo `dopo`, l'impegno della sessione e i giocatori) e il
+    /// ricevitore degli aggiornamenti successivi. Gli altri giocatori
+    /// ricevono le presenze aggiornate
+    pub fn entra(
+        &self,
+        id: u64,
+        username: Option<String>,
+        dopo: Option<u64>,
+    ) -> (Vec<ServerMessage>, broadcast::Receiver<ServerMessage>) {
+        let mut interno = self.interno.lock().unwrap();
+        interno.membri.insert(id, username.clone());
+        if interno.tocca(id).is_some() {
+            // prima di iscriversi: il nuovo arrivato le riceve tra i
+            // messaggi iniziali
+            self.invia(interno.presenze());
+        }
+        // iscrizione sotto lock: nessun aggiornamento va perso tra il log e
+        // il ricevitore
+        let ricevitore = self.canale.subscribe();
+        let log = match dopo {
+            Some(seq) => interno.dopo(seq),
+            None => interno.ultime(),
+        };
+        let log = interno
+            .per(log, username.as_deref())
+            .expect("il log arriva sempre");
+        let iniziali = vec![
+            ServerMessage::RoomJoined(self.descrizione(&interno)),
+            log,
+            ServerMessage::SeedCommitment(interno.tavolo.impegno()),
+            interno.presenze(),
+        ];
+        (iniziali, ricevitore)
+    }
+
+    /// La connessione entrata con `id` se ne va
+    pub fn esci(&self, id: u64) {
+        let mut interno = self.interno.lock().unwrap();
+        let con_nome = interno.tocca(id).is_some();
+        interno.membri.remove(&id);
+        if con_nome {
+            self.invia(interno.presenze());
+        }
+    }
+
+    /// Le ultime righe del log per la connessione entrata con `id`, rimasta
+    /// indietro
+    pub fn storico(&self, id: u64) -> ServerMessage {
+        let interno = self.interno.lock().unwrap();
+        let log = interno.ultime();
+        let username = interno.membri.get(&id).cloned().flatten();
+        interno
+            .per(log, username.as_deref())
+            .expect("il log arriva sempre")
+    }
+
+    /// Com'è la stanza adesso
+    pub fn descrivi(&self) -> Stanza {
+        self.descrizione(&self.interno.lock().unwrap())
+    }
+
+    /// Al più `limite` righe prima di quella con `seq` uguale a `prima`, o
+    /// le ultime, come le vede chi non è nella stanza
+    pub fn pagina(&self, prima: Option<u64>, limite: usize) -> PaginaStorico {
+        let interno = self.interno.lock().unwrap();
+        let pagina = interno.prima(prima.unwrap_or(u64::MAX), limite);
+        match interno.per(pagina, None) {
+            Some(ServerMessage::OlderHistory { righe, altre }) => PaginaStorico { righe, altre },
+            _ => unreachable!("una pagina resta una pagina"),
+        }
+    }
+
+    /// Un aggiornamento del canale come lo deve ricevere la connessione
+    /// entrata con `id`; `None` se non la riguarda
+    pub fn per(&self, id: u64, msg: ServerMessage) -> Option<ServerMessage> {
+        let interno = self.interno.lock().unwrap();
+        let username = interno.membri.get(&id).cloned().flatten();
+        interno.per(msg, username.as_deref())
+    }
+
+    /// Gestisce un messaggio del client entrato con `id`. Quello che
+    /// riguarda tutti viene mandato sul canale; restituisce la risposta per
+    /// il solo mittente, se c'è. I tiri si fanno fuori dal lock, su un thread
+    /// che può bloccarsi: coi set grandi il calcolo dei raises è lungo, e la
+    /// stanza intanto non si ferma
+    pub async fn gestisci(&self, id: u64, msg: ClientMessage) -> Option<ServerMessage> {
+        if let ClientMessage::ClaimGm { password } = msg {
+            return self.rivendica(id, password).await;
+        }
+        let prenotato = match self.elabora(id, msg) {
+            Passo::Risposta(risposta) => return risposta,
+            Passo::Tira(prenotato) => prenotato,
+        };
+        let tiro = match task::spawn_blocking(move || prenotato.esegui()).await {
+            Ok(Ok(tiro)) => tiro,
+            Ok(Err(e)) => return Some(ServerMessage::System(format!("Tiro non riuscito: {}", e))),
+            Err(e) => {
+                rocket::error!("Tiro nella stanza {} interrotto: {}", self.codice, e);
+                return Some(ServerMessage::System("Tiro non riuscito".into()));
+            }
+        };
+        let mut interno = self.interno.lock().unwrap();
+        interno.tavolo.registra(&tiro);
+        self.aggiungi(&mut interno, ChatMessage::di_tiro(&tiro));
+        self.invia(ServerMessage::RollResult(Box::new(tiro)));
+        None
+    }
+
+    /// La parte di [`Self::gestisci`] sotto il lock: i tiri qui vengono solo
+    /// prenotati, col loro numero nella sessione già salvato
+    fn elabora(&self, id: u64, msg: ClientMessage) -> Passo {
+        let mut interno = self.interno.lock().unwrap();
+        if !interno.membri.contains_key(&id) {
+            // mandato via dal GM: il posto sta per cambiare stanza
+            return Passo::Risposta(None);
+        }
+        let username = interno.tocca(id);
+        let gm = username.is_some() && username == interno.gm;
+        let dichiarato = match &msg {
+            ClientMessage::AddMessage(chat) => Some(&chat.username),
+            ClientMessage::RollRequest(richiesta) => Some(&richiesta.username),
+            ClientMessage::RerollRequest(richiesta) => Some(&richiesta.username),
+            _ => None,
+        };
+        if let Some(dichiarato) = dichiarato {
+            if username.as_ref() != Some(dichiarato) {
+                return risposta(match &username {
+                    Some(username) => format!("Puoi scrivere e tirare solo come {}", username),
+                    None => "Per scrivere e tirare entra nella stanza con un nome".into(),
+                });
+            }
+        }
+        match msg {
+            ClientMessage::AddMessage(chat) => {
+                // numeri, orari e tiri li scrive solo il server
+                let chat = ChatMessage::new(chat.username, chat.message);
+                self.aggiungi(&mut interno, chat);
+            }
+            ClientMessage::RollRequest(richiesta) => match interno.tavolo.prenota(richiesta) {
+                Ok(prenotato) => {
+                    self.salva(&interno);
+                    return Passo::Tira(Box::new(prenotato));
+                }
+                Err(e) => return risposta(format!("Tiro non valido: {}", e)),
+            },
+            ClientMessage::RerollRequest(richiesta) => {
+                match interno.tavolo.prenota_rilancio(richiesta) {
+                    Ok(prenotato) => {
+                        self.salva(&interno);
+                        return Passo::Tira(Box::new(prenotato));
+                    }
+                    Err(e) => return risposta(format!("Rilancio non valido: {}", e)),
+                }
+            }
+            // il seme rivelato rifà i tiri: se la stanza ha un GM, decide lui
+            // quando chiudere la sessione
+            ClientMessage::EndSession if interno.gm.is_some() && !gm => {
+                return risposta(SOLO_GM);
+            }
+            ClientMessage::EndSession => {
+                let seme = interno.tavolo.nuova_sessione();
+                self.salva(&interno);
+                self.invia(ServerMessage::SeedReveal(seme));
+                self.invia(ServerMessage::SeedCommitment(interno.tavolo.impegno()));
+            }
+            ClientMessage::Resume(seq) => {
+                let log = interno.dopo(seq);
+                return Passo::Risposta(interno.per(log, username.as_deref()));
+            }
+            ClientMessage::LoadOlder { before, limit } => {
+                let limite = limit.map_or(PAGINA_LOG, |l| l as usize);
+                let pagina = interno.prima(before, limite);
+                return Passo::Risposta(interno.per(pagina, username.as_deref()));
+            }
+            ClientMessage::Kick { .. }
+            | ClientMessage::VoidEntry(_)
+            | ClientMessage::DeleteEntry(_)
+            | ClientMessage::ClearHistory
+            | ClientMessage::LockRoom(_)
+                if !gm =>
+            {
+                return risposta(SOLO_GM);
+            }
+            ClientMessage::Kick { username } if interno.gm.as_ref() == Some(&username) => {
+                return risposta("Il GM non può mandare via sé stesso");
+            }
+            ClientMessage::Kick { username } => {
+                let Some(identita) = interno.nomi.get(&username).cloned() else {
+                    return risposta(format!("Nessun giocatore {} nella stanza", username));
+                };
+                interno.attivita.remove(&username);
+                // fuori da subito: quando i loro posti escono non c'è più
+                // niente da togliere
+                interno.membri.retain(|_, m| m.as_ref() != Some(&username));
+                interno.banditi.insert(username.clone(), identita.clone());
+                self.scrivi(Scrittura::Bando {
+                    stanza: self.codice.clone(),
+                    username: username.clone(),
+                    identita,
+                });
+                self.invia(ServerMessage::Kicked(username));
+                self.invia(interno.presenze());
+            }
+            ClientMessage::VoidEntry(seq) => {
+                return Passo::Risposta(self.modera(&mut interno, seq, Moderazione::Annullata))
+            }
+            ClientMessage::DeleteEntry(seq) => {
+                return Passo::Risposta(self.modera(&mut interno, seq, Moderazione::Eliminata))
+            }
+            ClientMessage::ClearHistory => {
+                // i tiri cancellati restano verificabili col seme rivelato
+                let seme = interno.tavolo.nuova_sessione();
+                self.invia(ServerMessage::SeedReveal(seme));
+                self.invia(ServerMessage::SeedCommitment(interno.tavolo.impegno()));
+                interno.cancellati += interno.storico.len() as u64;
+                interno.storico.clear();
+                self.scrivi(Scrittura::Svuota(self.codice.clone()));
+                self.salva(&interno);
+                self.invia(ServerMessage::HistoryCleared);
+            }
+            ClientMessage::LockRoom(bloccata) => {
+                interno.bloccata = bloccata;
+                self.salva(&interno);
+                self.invia(ServerMessage::RoomUpdated(self.descrizione(&interno)));
+            }
+            // presentazione, battito e cambi di stanza li gestisce il server;
+            // la richiesta del ruolo di GM è passata da `rivendica`
+            ClientMessage::ClaimGm { .. }
+            | ClientMessage::Hello(_)
+            | ClientMessage::Ping(_)
+            | ClientMessage::CreateRoom { .. }
+            | ClientMessage::JoinRoom { .. }
+            | ClientMessage::LeaveRoom => {}
+        }
+        Passo::Risposta(None)
+    }
+
+    /// Dà il ruolo di GM al giocatore entrato con `id`, se `password` è
+    /// quella della stanza. L'impronta si controlla fuori dal lock e su un
+    /// thread che può bloccarsi: è lenta apposta, e la stanza intanto non si
+    /// ferma
+    async fn rivendica(&self, id: u64, password: String) -> Option<ServerMessage> {
+        let (username, impronta) = {
+            let mut interno = self.interno.lock().unwrap();
+            if !interno.membri.contains_key(&id) {
+                return None;
+            }
+            (interno.tocca(id), interno.impronta_gm.clone())
+        };
+        if username.is_none() {
+            return Some(ServerMessage::System(
+                "Per diventare GM entra nella stanza con un nome".into(),
+            ));
+        }
+        let giusta = match impronta {
+            Some(impronta) => password::verifica_a_parte(impronta, password).await,
+            None => false,
+        };
+        if !giusta {
+            return Some(ServerMessage::System("Password del GM sbagliata".into()));
+        }
+        let mut interno = self.interno.lock().unwrap();
+        if !interno.membri.contains_key(&id) {
+            // mandato via mentre si controllava la password
+            return None;
+        }
+        interno.gm = username;
+        self.salva(&interno);
+        self.invia(ServerMessage::RoomUpdated(self.descrizione(&interno)));
+        None
+    }
+
+    /// Annulla o elimina la riga `seq` e lo dice a tutti
+    fn modera(
+        &self,
+        interno: &mut Interno,
+        seq: u64,
+        moderazione: Moderazione,
+    ) -> Option<ServerMessage> {
+        let Some(riga) = interno.riga(seq) else {
+            return Some(ServerMessage::System(format!(
+                "Nessuna riga {} nel log",
+                seq
+            )));
+        };
+        match moderazione {
+            // il tiro resta: serve per verificare la sessione
+            Moderazione::Eliminata => {
+                riga.message.clear();
+                riga.moderata = Some(moderazione);
+            }
+            // una riga eliminata resta eliminata
+            Moderazione::Annullata => {
+                riga.moderata.get_or_insert(moderazione);
+            }
+        }
+        let riga = riga.clone();
+        self.scrivi(Scrittura::Riga {
+            stanza: self.codice.clone(),
+            riga: riga.clone(),
+        });
+        self.invia(ServerMessage::EntryUpdated(riga));
+        None
+    }
+
+    /// Aggiunge una riga al log, la salva e la manda a tutti
+    fn aggiungi(&self, interno: &mut Interno, riga: ChatMessage) {
+        let evento = interno.aggiungi(riga);
+        if let ServerMessage::NewMessage(riga) = &evento {
+            self.scrivi(Scrittura::Riga {
+                stanza: self.codice.clone(),
+                riga: riga.clone(),
+            });
+        }
+        self.invia(evento);
+    }
+
+    /// Salva tutto della stanza tranne il log
+    fn salva(&self, interno: &Interno) {
+        let dati = DatiStanza {
+            codice: self.codice.clone(),
+            nome: self.nome.clone(),
+            gm: interno.gm.clone(),
+            impronta_gm: interno.impronta_gm.clone(),
+            bloccata: interno.bloccata,
+            cancellati: interno.cancellati,
+            seme: interno.tavolo.seme().rivela(),
+            contatore: interno.tavolo.contatore(),
+        };
+        self.scrivi(Scrittura::Stanza(dati));
+    }
+
+    /// Mette in coda una modifica per l'archivio: la si prepara sotto il
+    /// lock, così le modifiche arrivano nell'ordine, ma il disco non lo tiene
+    fn scrivi(&self, scrittura: Scrittura) {
+        self.scrittore.scrivi(scrittura);
+    }
+
+    fn descrizione(&self, interno: &Interno) -> Stanza {
+        Stanza {
+            codice: self.codice.clone(),
+            nome: self.nome.clone(),
+            gm: interno.gm.clone(),
+            bloccata: interno.bloccata,
+        }
+    }
+
+    fn invia(&self, msg: ServerMessage) {
+        // nessun client connesso non è un errore
+        let _ = self.canale.send(msg);
+    }
+}
+
+/// Millisecondi dal 1970
+fn adesso() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
@@ -0,0 +1,439 @@
+//! Stato condiviso del server: le stanze, chi ci sta dentro e i gettoni che
+//! dicono chi è.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use rocket::tokio::sync::broadcast;
+use seven_seas_protocol::{
+    compatibile, ClientMessage, Rifiuto, ServerMessage, Welcome, STANZA_COMUNE, VERSIONE_MINIMA,
+    VERSIONE_PROTOCOLLO,
+};
+
+use crate::archivio::{Archivio, Scrittore};
+use crate::battito::Battito;
+use crate::frequenza::{Limiti, PerIp, Secchio};
+use crate::gettoni::Gettoni;
+use crate::password;
+use crate::stanza::StatoStanza;
+
+const NOME_COMUNE: &str = "Tavolo comune";
+
+/// Caratteri dei codici delle stanze: niente 0/O e 1/I, che si confondono.
+/// [`STANZA_COMUNE`] ha una `O`, quindi nessun codice generato può coincidere
+const ALFABETO_CODICI: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const LUNGHEZZA_CODICE: usize = 6;
+
+/// Nome del server nel [`Welcome`]
+const NOME_SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Tutte le stanze del server
+pub struct StatoServer {
+    stanze: Mutex<HashMap<String, Arc<StatoStanza>>>,
+    /// Per numerare gli ingressi nelle stanze
+    ingressi: AtomicU64,
+    scrittore: Arc<Scrittore>,
+    gettoni: Gettoni,
+    limiti: Limiti,
+    per_ip: PerIp,
+    /// Per le richieste del ruolo di GM
+    per_ip_gm: PerIp,
+    battito: Battito,
+}
+
+/// La stanza in cui si trova una connessione e gli aggiornamenti che ne
+/// arrivano; quando viene scartato la connessione esce dalla stanza. Ogni
+/// ingresso ha un `id` suo, così rientrare nella stessa stanza non fa uscire
+/// il posto nuovo quando si scarta il vecchio
+pub struct Posto {
+    id: u64,
+    /// Chi c'è dietro la connessione, dal suo gettone
+    identita: String,
+    username: Option<String>,
+    stanza: Arc<StatoStanza>,
+    pub aggiornamenti: broadcast::Receiver<ServerMessage>,
+}
+
+impl Posto {
+    pub fn stanza(&self) -> &StatoStanza {
+        &self.stanza
+    }
+
+    /// Le ultime righe del log come le deve vedere questo posto
+    pub fn storico(&self) -> ServerMessage {
+        self.stanza.storico(self.id)
+    }
+}
+
+impl Drop for Posto {
+    fn drop(&mut self) {
+        self.stanza.esci(self.id);
+    }
+}
+
+impl StatoServer {
+    /// Un server con la sola stanza comune, che non salva niente
+    pub fn new() -> Self {
+        Self::con_archivio(Arc::new(Archivio::in_memoria()))
+            .expect("un archivio in memoria è vuoto")
+    }
+
+    /// Un server con le stanze e la chiave dei gettoni salvate in
+    /// `archivio`, più la stanza comune se non c'è ancora
+    pub fn con_archivio(archivio: Arc<Archivio>) -> rusqlite::Result<Self> {
+        let gettoni = Gettoni::new(archivio.chiave_gettoni()?);
+        let salvate = archivio.stanze()?;
+        let scrittore = Arc::new(Scrittore::avvia(archivio));
+        let mut stanze: HashMap<_, _> = salvate
+            .into_iter()
+            .map(|salvata| {
+                let stanza = StatoStanza::ripristina(salvata, scrittore.clone());
+                (stanza.codice().to_string(), Arc::new(stanza))
+            })
+            .collect();
+        stanze.entry(STANZA_COMUNE.to_string()).or_insert_with(|| {
+            // la stanza comune non ha GM
+            let comune =
+                StatoStanza::new(STANZA_COMUNE, NOME_COMUNE, None, None, scrittore.clone());
+            Arc::new(comune)
+        });
+        Ok(StatoServer {
+            stanze: Mutex::new(stanze),
+            ingressi: AtomicU64::new(0),
+            scrittore,
+            gettoni,
+            limiti: Limiti::default(),
+            per_ip: PerIp::new(Limiti::default().ip),
+            per_ip_gm: PerIp::new(Limiti::default().gm_ip),
+            battito: Battito::default(),
+        })
+    }
+
+    /// Aspetta che le modifiche fatte finora siano nell'archivio, per
+    /// esempio prima di spegnere il server
+    pub async fn archiviato(&self) {
+        self.scrittore.attendi().await;
+    }
+
+    /// Lo stesso server con altri limiti alla frequenza dei messaggi
+    pub fn con_limiti(self, limiti: Limiti) -> Self {
+        StatoServer {
+            limiti,
+            per_ip: PerIp::new(limiti.ip),
+            per_ip_gm: PerIp::new(limiti.gm_ip),
+            ..self
+        }
+    }
+
+    /// Lo stesso server con un altro battito delle connessioni
+    pub fn con_battito(self, battito: Battito) -> Self {
+        StatoServer { battito, ..self }
+    }
+
+    pub fn battito(&self) -> Battito {
+        self.battito
+    }
+
+    /// Il secchio di una connessione nuova, per [`StatoServer::consenti`]
+    pub fn secchio(&self) -> Secchio {
+        Secchio::new(self.limiti.connessione)
+    }
+
+    /// Se una connessione, col suo `secchio`, può mandare un altro messaggio
+    /// da `ip`
+    pub fn consenti(&self, secchio: &mut Secchio, ip: Option<IpAddr>) -> Result<(), Rifiuto> {
+        let adesso = Instant::now();
+        secchio
+            .prendi(adesso)
+            .and_then(|()| ip.map_or(Ok(()), |ip| self.per_ip.prendi(ip, adesso)))
+            .map_err(troppe_richieste)
+    }
+
+    /// Il secchio delle richieste del ruolo di GM di una connessione nuova,
+    /// per [`StatoServer::consenti_gm`]
+    pub fn secchio_gm(&self) -> Secchio {
+        Secchio::new(self.limiti.gm_connessione)
+    }
+
+    /// Se una connessione, col suo `secchio_gm`, può chiedere di nuovo il
+    /// ruolo di GM da `ip`: ogni richiesta fa calcolare un'impronta della
+    /// password, e non si devono poter provare password a raffica
+    pub fn consenti_gm(&self, secchio_gm: &mut Secchio, ip: Option<IpAddr>) -> Result<(), Rifiuto> {
+        let adesso = Instant::now();
+        secchio_gm
+            .prendi(adesso)
+            .and_then(|()| ip.map_or(Ok(()), |ip| self.per_ip_gm.prendi(ip, adesso)))
+            .map_err(troppe_richieste)
+    }
+
+    /// Se da `ip` può arrivare un'altra richiesta fuori da una connessione,
+    /// come quelle dell'API
+    pub fn consenti_ip(&self, ip: Option<IpAddr>) -> Result<(), Rifiuto> {
+        ip.map_or(Ok(()), |ip| self.per_ip.prendi(ip, Instant::now()))
+            .map_err(troppe_richieste)
+    }
+
+    /// L'identità di chi si collega presentando `gettone`; se il gettone
+    /// manca o non è valido ne emette uno nuovo, da mandargli
+    pub fn identifica(&self, gettone: Option<&str>) -> (String, Option<String>) {
+        match gettone.and_then(|g| self.gettoni.verifica(g)) {
+            Some(identita) => (identita, None),
+            None => {
+                let (identita, gettone) = self.gettoni.emetti();
+                (identita, Some(gettone))
+            }
+        }
+    }
+
+    /// Fa sedere una connessione nuova di `identita` nella stanza `codice`
+    /// (o in quella comune, se non esiste, è bloccata o il nome è di un
+    /// altro); restituisce il posto e i messaggi da mandarle subito
+    pub fn siedi(
+        &self,
+        codice: Option<&str>,
+        username: Option<String>,
+        identita: String,
+        dopo: Option<u64>,
+    ) -> (Posto, Vec<ServerMessage>) {
+        let scelta = match codice.map(|c| (c, self.cerca(c))) {
+            Some((_, Some(stanza))) => match ammetti(&stanza, username.as_deref(), &identita) {
+                None => Ok(stanza),
+                Some(rifiuto) => Err(rifiuto),
+            },
+            Some((codice, None)) => Err(non_trovata(codice)),
+            None => return self.entra_comune(username, identita, dopo),
+        };
+        match scelta {
+            Ok(stanza) => self.entra(stanza, username, identita, dopo),
+            Err(avviso) => {
+                let (posto, mut iniziali) = self.entra_comune(username, identita, dopo);
+                iniziali.push(avviso);
+                (posto, iniziali)
+            }
+        }
+    }
+
+    /// Gestisce un messaggio di un client seduto in `posto`; restituisce le
+    /// risposte per lui solo
+    pub async fn gestisci(&self, posto: &mut Posto, msg: ClientMessage) -> Vec<ServerMessage> {
+        let (stanza, username, dopo) = match msg {
+            ClientMessage::Hello(hello) => {
+                return vec![if compatibile(hello.versione) {
+                    ServerMessage::Welcome(
+                        Welcome::new(NOME_SERVER).battito(self.battito.intervallo_ms),
+                    )
+                } else {
+                    ServerMessage::Incompatible {
+                        versione: VERSIONE_PROTOCOLLO,
+                        minima: VERSIONE_MINIMA,
+                    }
+                }];
+            }
+            ClientMessage::Ping(numero) => return vec![ServerMessage::Pong(numero)],
+            ClientMessage::CreateRoom {
+                nome,
+                username,
+                password_gm,
+            } => {
+                let gm = Some(username.clone()).filter(|u| !u.is_empty());
+                // l'impronta si calcola prima di prendere il lock delle
+                // stanze, e fuori dai thread che servono le connessioni
+                let impronta_gm = match password_gm.filter(|p| !p.is_empty()) {
+                    Some(p) => Some(password::impronta_a_parte(p).await),
+                    None => None,
+                };
+                let stanza = self.crea(nome, gm.clone(), impronta_gm);
+                if let Some(gm) = &gm {
+                    // la stanza è nuova: il nome è sicuramente libero
+                    stanza.prenota(gm, &posto.identita);
+                }
+                (stanza, username, None)
+            }
+            ClientMessage::JoinRoom {
+                codice,
+                username,
+                after,
+            } => match self.cerca(&codice) {
+                Some(stanza) => {
+                    let nome = Some(username.as_str()).filter(|u| !u.is_empty());
+                    match ammetti(&stanza, nome, &posto.identita) {
+                        None => (stanza, username, after),
+                        Some(rifiuto) => return vec![rifiuto],
+                    }
+                }
+                None => return vec![non_trovata(&codice)],
+            },
+            ClientMessage::LeaveRoom => (self.comune(), String::new(), None),
+            altro => {
+                return posto
+                    .stanza
+                    .gestisci(posto.id, altro)
+                    .await
+                    .into_iter()
+                    .collect()
+            }
+        };
+
+        let username = Some(username).filter(|u| !u.is_empty());
+        // il posto vecchio esce dalla stanza quando viene sostituito
+        let (nuovo, iniziali) = self.entra(stanza, username, posto.identita.clone(), dopo);
+        *posto = nuovo;
+        iniziali
+    }
+
+    /// Filtra un aggiornamento arrivato dalla stanza di `posto` (vedi
+    /// [`StatoStanza::per`]); se il GM ha mandato via proprio questo
+    /// giocatore il posto torna nella stanza comune, e la risposta comprende
+    /// i messaggi per entrarci
+    pub fn aggiornamento(&self, posto: &mut Posto, msg: ServerMessage) -> Vec<ServerMessage> {
+        let Some(msg) = posto.stanza.per(posto.id, msg) else {
+            return Vec::new();
+        };
+        let mandato_via =
+            matches!(&msg, ServerMessage::Kicked(u) if posto.username.as_ref() == Some(u));
+        let mut risposte = vec![msg];
+        if mandato_via {
+            let (nuovo, iniziali) =
+                self.entra_comune(posto.username.clone(), posto.identita.clone(), None);
+            *posto = nuovo;
+            risposte.extend(iniziali);
+        }
+        risposte
+    }
+
+    fn entra(
+        &self,
+        stanza: Arc<StatoStanza>,
+        username: Option<String>,
+        identita: String,
+        dopo: Option<u64>,
+    ) -> (Posto, Vec<ServerMessage>) {
+        let id = self.ingressi.fetch_add(1, Ordering::Relaxed);
+        let (iniziali, aggiornamenti) = stanza.entra(id, username.clone(), dopo);
+        let posto = Posto {
+            id,
+            identita,
+            username,
+            stanza,
+            aggiornamenti,
+        };
+        (posto, iniziali)
+    }
+
+    /// Entra nella stanza comune, che non si blocca mai; se il nome è di un
+    /// altro si entra senza, e la risposta lo dice
+    fn entra_comune(
+        &self,
+        username: Option<String>,
+        identita: String,
+        dopo: Option<u64>,
+    ) -> (Posto, Vec<ServerMessage>) {
+        let comune = self.comune();
+        match ammetti(&comune, username.as_deref(), &identita) {
+            None => self.entra(comune, username, identita, dopo),
+            Some(rifiuto) => {
+                let (posto, mut iniziali) = self.entra(comune, None, identita, dopo);
+                iniziali.push(rifiuto);
+                (posto, iniziali)
+            }
+        }
+    }
+
+    /// Una stanza nuova con un codice libero
+    fn crea(
+        &self,
+        nome: String,
+        gm: Option<String>,
+        impronta_gm: Option<String>,
+    ) -> Arc<StatoStanza> {
+        let mut stanze = self.stanze.lock().unwrap();
+        let codice = loop {
+            let codice = codice_casuale();
+            if !stanze.contains_key(&codice) {
+                break codice;
+            }
+        };
+        let nome = match nome.trim() {
+            "" => format!("Stanza {}", codice),
+            nome => nome.to_string(),
+        };
+        let stanza = StatoStanza::new(
+            codice.clone(),
+            nome,
+            gm,
+            impronta_gm,
+            self.scrittore.clone(),
+        );
+        let stanza = Arc::new(stanza);
+        stanze.insert(codice, stanza.clone());
+        stanza
+    }
+
+    /// La stanza col codice `codice`, senza badare a maiuscole e spazi
+    pub fn cerca(&self, codice: &str) -> Option<Arc<StatoStanza>> {
+        let codice = codice.trim().to_ascii_uppercase();
+        self.stanze.lock().unwrap().get(&codice).cloned()
+    }
+
+    fn comune(&self) -> Arc<StatoStanza> {
+        self.cerca(STANZA_COMUNE)
+            .expect("la stanza comune c'è sempre")
+    }
+}
+
+impl Default for StatoServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Il motivo per cui `identita` non può entrare in `stanza` come `username`;
+/// se può, il nome da lì in poi è suo
+fn ammetti(stanza: &StatoStanza, username: Option<&str>, identita: &str) -> Option<ServerMessage> {
+    if stanza.bandito(username, identita) {
+        return Some(bandito(stanza.codice()));
+    }
+    if !stanza.aperta_a(username) {
+        return Some(bloccata(stanza.codice()));
+    }
+    match username {
+        Some(username) if !stanza.prenota(username, identita) => Some(nome_occupato(username)),
+        _ => None,
+    }
+}
+
+fn non_trovata(codice: &str) -> ServerMessage {
+    ServerMessage::System(format!("Nessuna stanza col codice {}", codice))
+}
+
+fn bloccata(codice: &str) -> ServerMessage {
+    ServerMessage::System(format!("La stanza {} è chiusa dal GM", codice))
+}
+
+fn bandito(codice: &str) -> ServerMessage {
+    ServerMessage::System(format!("Il GM ti ha mandato via dalla stanza {}", codice))
+}
+
+fn nome_occupato(username: &str) -> ServerMessage {
+    ServerMessage::System(format!(
+        "Il nome {} è già di un altro giocatore in questa stanza",
+        username
+    ))
+}
+
+fn troppe_richieste(attesa: Duration) -> Rifiuto {
+    Rifiuto::TroppeRichieste {
+        attesa_ms: attesa.as_millis().max(1) as u64,
+    }
+}
+
+fn codice_casuale() -> String {
+    let mut rng = rand::thread_rng();
+    (0..LUNGHEZZA_CODICE)
+        .map(|_| ALFABETO_CODICI[rng.gen_range(0..ALFABETO_CODICI.len())] as char)
+        .collect()
+}
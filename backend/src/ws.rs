@@ -0,0 +1,96 @@
+//! Endpoint WebSocket `/ws/` del tavolo.
+
+use std::net::IpAddr;
+
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::tokio::select;
+use rocket::tokio::time::{interval_at, Instant};
+use rocket::State;
+use rocket_ws::{Channel, Config, Message, WebSocket};
+use seven_seas_protocol::limiti::MAX_BYTE_MESSAGGIO;
+use seven_seas_protocol::ServerMessage;
+
+use crate::sessione::{ultimo, Sessione};
+use crate::stato::StatoServer;
+
+/// Oltre questa dimensione il socket si chiude senza leggere il messaggio;
+/// fino a lì si risponde con un [`Rifiuto`](seven_seas_protocol::Rifiuto)
+const MAX_BYTE_SOCKET: usize = 4 * MAX_BYTE_MESSAGGIO;
+
+/// Una connessione al server: entra nella stanza `room` (o in quella comune)
+/// e riceve subito la stanza, il suo log e l'impegno della sessione, poi gli
+/// aggiornamenti di tutti i giocatori della stanza. Con `?after=N` riceve
+/// solo le righe del log successive alla N-esima. Con `?token=` si
+/// presenta il gettone ricevuto in una connessione precedente; senza, o se
+/// non è valido, il primo messaggio è un gettone nuovo. Chi si presenta con
+/// un protocollo incompatibile viene avvisato e scollegato. I messaggi fuori
+/// dai limiti o troppo frequenti vengono rifiutati. Le connessioni da cui
+/// non arriva niente per troppo tempo vengono chiuse (vedi
+/// [`Battito`](crate::Battito))
+#[rocket::get("/ws?<room>&<username>&<after>&<token>")]
+pub fn ws<'r>(
+    ws: WebSocket,
+    room: Option<&'r str>,
+    username: Option<String>,
+    after: Option<u64>,
+    token: Option<&'r str>,
+    ip: Option<IpAddr>,
+    stato: &'r State<StatoServer>,
+) -> Channel<'r> {
+    let config = Config {
+        max_message_size: Some(MAX_BYTE_SOCKET),
+        max_frame_size: Some(MAX_BYTE_SOCKET),
+        ..Config::default()
+    };
+    ws.config(config).channel(move |mut stream| {
+        Box::pin(async move {
+            let (mut sessione, iniziali) = Sessione::apri(stato, room, username, after, token, ip);
+            for msg in &iniziali {
+                stream.send(testo(msg)).await?;
+            }
+            let battito = stato.battito();
+            let mut tic = interval_at(Instant::now() + battito.intervallo(), battito.intervallo());
+            let mut sentito = Instant::now();
+
+            loop {
+                select! {
+                    ricevuto = stream.next() => match ricevuto {
+                        Some(Ok(Message::Text(txt))) => {
+                            sentito = Instant::now();
+                            for risposta in sessione.ricevuto(&txt).await {
+                                stream.send(testo(&risposta)).await?;
+                                if ultimo(&risposta) {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => break,
+                        // anche i pong del WebSocket dicono che il client c'è
+                        Some(Ok(_)) => sentito = Instant::now(),
+                        Some(Err(e)) => return Err(e),
+                    },
+                    aggiornamento = sessione.aggiornamento() => match aggiornamento {
+                        Some(messaggi) => {
+                            for msg in &messaggi {
+                                stream.send(testo(msg)).await?;
+                            }
+                        }
+                        None => break,
+                    },
+                    _ = tic.tick() => {
+                        // il client è sparito: lasciando il posto risulta scollegato
+                        if sentito.elapsed() > battito.scadenza() {
+                            break;
+                        }
+                        stream.send(Message::Ping(Vec::new())).await?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+fn testo(msg: &ServerMessage) -> Message {
+    Message::Text(serde_json::to_string(msg).expect("ServerMessage si serializza sempre"))
+}
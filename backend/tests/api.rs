@@ -0,0 +1,124 @@
+//! Test dell'API REST sotto `/api`.
+
+mod common;
+
+use rocket::http::{ContentType, Status};
+use rocket::local::asynchronous::Client;
+use seven_seas_backend::stato::StatoServer;
+use seven_seas_core::{RollOptions, RollWithRerollResponse};
+use seven_seas_protocol::api::{ErroreApi, PaginaStorico};
+use seven_seas_protocol::{ChatMessage, ClientMessage, ServerMessage, Stanza, STANZA_COMUNE};
+
+async fn tira(client: &Client, corpo: &str) -> (Status, String) {
+    let risposta = client
+        .post("/api/roll")
+        .header(ContentType::JSON)
+        .body(corpo)
+        .dispatch()
+        .await;
+    (risposta.status(), risposta.into_string().await.unwrap())
+}
+
+async fn leggi(client: &Client, url: &str) -> (Status, String) {
+    let risposta = client.get(url).dispatch().await;
+    (risposta.status(), risposta.into_string().await.unwrap())
+}
+
+#[rocket::async_test]
+async fn tiri_senza_stanza() {
+    let client = common::client().await;
+    let opzioni = serde_json::to_string(&RollOptions::new(5)).unwrap();
+    let (status, corpo) = tira(&client, &opzioni).await;
+    assert_eq!(status, Status::Ok);
+    let tiro: RollWithRerollResponse = serde_json::from_str(&corpo).unwrap();
+    assert_eq!(tiro.risultati_originali.len(), 5);
+
+    // gli errori arrivano sempre come JSON, anche quando il corpo non lo è
+    for corpo in [
+        r#"{"num_dadi":0}"#,
+        r#"{"num_dadi":1000}"#,
+        r#"{"num_dadi":100,"target":30,"set_doppi":true}"#,
+        "dadi!",
+    ] {
+        let (status, corpo) = tira(&client, corpo).await;
+        assert!(status.class().is_client_error(), "{}", status);
+        let errore: ErroreApi = serde_json::from_str(&corpo).unwrap();
+        assert!(!errore.errore.is_empty());
+    }
+}
+
+#[rocket::async_test]
+async fn storico_delle_stanze() {
+    let client = common::client().await;
+    let stato = client.rocket().state::<StatoServer>().unwrap();
+    let (mut anna, _) = stato.siedi(None, Some("Anna".into()), "anna".into(), None);
+    let crea = ClientMessage::CreateRoom {
+        nome: "Campagna".into(),
+        username: "Anna".into(),
+        password_gm: None,
+    };
+    let stanza = stato
+        .gestisci(&mut anna, crea)
+        .await
+        .into_iter()
+        .find_map(|msg| match msg {
+            ServerMessage::RoomJoined(stanza) => Some(stanza),
+            _ => None,
+        })
+        .expect("attesa la stanza nuova");
+    for i in 0..3 {
+        let riga = ChatMessage::new("Anna", format!("riga {}", i));
+        stato
+            .gestisci(&mut anna, ClientMessage::AddMessage(riga))
+            .await;
+    }
+
+    let (status, corpo) = leggi(&client, &format!("/api/rooms/{}", stanza.codice)).await;
+    assert_eq!(status, Status::Ok);
+    let descritta: Stanza = serde_json::from_str(&corpo).unwrap();
+    assert_eq!(descritta.nome, "Campagna");
+
+    let url = format!("/api/rooms/{}/history?limit=2", stanza.codice);
+    let (status, corpo) = leggi(&client, &url).await;
+    assert_eq!(status, Status::Ok);
+    let pagina: PaginaStorico = serde_json::from_str(&corpo).unwrap();
+    assert_eq!(pagina.righe.len(), 2);
+    assert!(pagina.altre);
+    assert_eq!(pagina.righe[1].message, "riga 2");
+
+    let prima = pagina.righe[0].seq.unwrap();
+    let url = format!("/api/rooms/{}/history?before={}", stanza.codice, prima);
+    let pagina: PaginaStorico = serde_json::from_str(&leggi(&client, &url).await.1).unwrap();
+    assert!(!pagina.altre);
+    assert!(pagina.righe.iter().all(|riga| riga.seq.unwrap() < prima));
+
+    // chiusa dal GM, la stanza non si legge più da fuori
+    stato
+        .gestisci(&mut anna, ClientMessage::LockRoom(true))
+        .await;
+    let (status, corpo) = leggi(&client, &format!("/api/rooms/{}", stanza.codice)).await;
+    assert_eq!(status, Status::Forbidden);
+    serde_json::from_str::<ErroreApi>(&corpo).unwrap();
+
+    let (status, _) = leggi(&client, &format!("/api/rooms/{}/history", STANZA_COMUNE)).await;
+    assert_eq!(status, Status::Ok);
+    let (status, corpo) = leggi(&client, "/api/rooms/NESSUNA/history").await;
+    assert_eq!(status, Status::NotFound);
+    serde_json::from_str::<ErroreApi>(&corpo).unwrap();
+}
+
+#[rocket::async_test]
+async fn documento_openapi() {
+    let client = common::client().await;
+    let (status, corpo) = leggi(&client, "/api/openapi.json").await;
+    assert_eq!(status, Status::Ok);
+    let documento: serde_json::Value = serde_json::from_str(&corpo).unwrap();
+    assert!(documento["paths"]["/api/roll"]["post"].is_object());
+
+    let risposta = client.options("/api/roll").dispatch().await;
+    assert_eq!(risposta.status(), Status::NoContent);
+    assert_eq!(
+        risposta.headers().get_one("Access-Control-Allow-Origin"),
+        Some("*")
+    );
+}
@@ -0,0 +1,13 @@
+//! Preparazione comune ai test con il client locale di Rocket.
+
+use rocket::local::asynchronous::Client;
+use seven_seas_backend::archivio::IN_MEMORIA;
+
+/// Un client locale del server, senza log e con l'archivio in memoria
+pub async fn client() -> Client {
+    let figment = rocket::Config::figment()
+        .merge(("log_level", "off"))
+        .merge(("archivio", IN_MEMORIA));
+    let rocket = seven_seas_backend::rocket().configure(figment);
+    Client::tracked(rocket).await.unwrap()
+}
@@ -0,0 +1,145 @@
+//! Test del trasporto di riserva: eventi SSE dal server, POST dal client.
+
+mod common;
+
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use rocket::tokio::time::timeout;
+use seven_seas_protocol::limiti::MAX_TESTO;
+use seven_seas_protocol::{ChatMessage, ClientMessage, Hello, ServerMessage};
+
+type Eventi<'c> = BufReader<LocalResponse<'c>>;
+
+/// Il prossimo evento: il suo tipo, se ne ha uno, e i suoi dati
+async fn evento(eventi: &mut Eventi<'_>) -> (Option<String>, String) {
+    let mut tipo = None;
+    let mut dati = String::new();
+    loop {
+        let mut riga = String::new();
+        timeout(Duration::from_secs(5), eventi.read_line(&mut riga))
+            .await
+            .expect("nessun evento dal server")
+            .unwrap();
+        let riga = riga.trim_end_matches('\n');
+        if riga.is_empty() {
+            if !dati.is_empty() {
+                return (tipo, dati);
+            }
+        } else if let Some(valore) = riga.strip_prefix("event:") {
+            tipo = Some(valore.trim_start().to_string());
+        } else if let Some(valore) = riga.strip_prefix("data:") {
+            dati.push_str(valore.trim_start());
+        }
+        // il resto, come i commenti del battito, non conta
+    }
+}
+
+async fn ricevi(eventi: &mut Eventi<'_>) -> ServerMessage {
+    let (tipo, dati) = evento(eventi).await;
+    assert_eq!(tipo, None);
+    serde_json::from_str(&dati).unwrap()
+}
+
+/// Apre gli eventi come `username` e consuma i messaggi iniziali;
+/// restituisce gli eventi e il codice della sessione
+async fn entra<'c>(client: &'c Client, username: &str) -> (Eventi<'c>, String) {
+    let risposta = client
+        .get(format!("/sse?username={}", username))
+        .dispatch()
+        .await;
+    assert_eq!(risposta.status(), Status::Ok);
+    let mut eventi = BufReader::new(risposta);
+    let (tipo, sessione) = evento(&mut eventi).await;
+    assert_eq!(tipo.as_deref(), Some("sessione"));
+    assert!(matches!(
+        ricevi(&mut eventi).await,
+        ServerMessage::SessionToken(_)
+    ));
+    assert!(matches!(
+        ricevi(&mut eventi).await,
+        ServerMessage::RoomJoined(_)
+    ));
+    assert!(matches!(
+        ricevi(&mut eventi).await,
+        ServerMessage::FullHistory(_)
+    ));
+    assert!(matches!(
+        ricevi(&mut eventi).await,
+        ServerMessage::SeedCommitment(_)
+    ));
+    assert!(matches!(
+        ricevi(&mut eventi).await,
+        ServerMessage::Presence(_)
+    ));
+    (eventi, sessione)
+}
+
+async fn invia(client: &Client, sessione: &str, msg: &ClientMessage) -> Status {
+    client
+        .post(format!("/sse/{}", sessione))
+        .body(serde_json::to_string(msg).unwrap())
+        .dispatch()
+        .await
+        .status()
+}
+
+#[rocket::async_test]
+async fn stesso_protocollo_su_sse() {
+    let client = common::client().await;
+    let (mut anna, sessione) = entra(&client, "Anna").await;
+
+    let hello = ClientMessage::Hello(Hello::new("test/1.0"));
+    assert_eq!(invia(&client, &sessione, &hello).await, Status::Accepted);
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::Welcome(_)));
+
+    let ciao = ClientMessage::AddMessage(ChatMessage::new("Anna", "ciao"));
+    assert_eq!(invia(&client, &sessione, &ciao).await, Status::Accepted);
+    let ServerMessage::NewMessage(riga) = ricevi(&mut anna).await else {
+        panic!("attesa la riga nuova");
+    };
+    assert_eq!(
+        (riga.username.as_str(), riga.message.as_str()),
+        ("Anna", "ciao")
+    );
+
+    // anche sulle POST valgono i limiti del protocollo
+    let lungo = ClientMessage::AddMessage(ChatMessage::new("Anna", "a".repeat(MAX_TESTO + 1)));
+    assert_eq!(invia(&client, &sessione, &lungo).await, Status::Accepted);
+    assert!(matches!(
+        ricevi(&mut anna).await,
+        ServerMessage::Rejected(_)
+    ));
+
+    // i giocatori su SSE si vedono tra loro come sul WebSocket
+    let (mut bruno, _) = entra(&client, "Bruno").await;
+    assert!(matches!(
+        ricevi(&mut anna).await,
+        ServerMessage::Presence(g) if g.len() == 2
+    ));
+    // il client locale non legge gli eventi da solo: la POST di Anna la
+    // gestisce la sua sessione quando si leggono i suoi
+    assert_eq!(invia(&client, &sessione, &ciao).await, Status::Accepted);
+    assert!(matches!(
+        ricevi(&mut anna).await,
+        ServerMessage::NewMessage(_)
+    ));
+    assert!(matches!(
+        ricevi(&mut bruno).await,
+        ServerMessage::NewMessage(riga) if riga.username == "Anna"
+    ));
+}
+
+#[rocket::async_test]
+async fn sessioni_chiuse_o_sconosciute() {
+    let client = common::client().await;
+    let ping = ClientMessage::Ping(1);
+    assert_eq!(invia(&client, "nessuna", &ping).await, Status::NotFound);
+
+    let (anna, sessione) = entra(&client, "Anna").await;
+    assert_eq!(invia(&client, &sessione, &ping).await, Status::Accepted);
+    drop(anna);
+    assert_eq!(invia(&client, &sessione, &ping).await, Status::NotFound);
+}
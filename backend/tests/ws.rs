@@ -0,0 +1,1081 @@
+//! Test del server attraverso veri client WebSocket.
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use rocket::error::ErrorKind;
+use rocket::figment::Figment;
+use rocket::tokio::net::TcpStream;
+use rocket::tokio::{self, time::timeout};
+use seven_seas_backend::archivio::IN_MEMORIA;
+use seven_seas_backend::Limiti;
+use seven_seas_core::{
+    nonce_casuale, verifica_sessione, RollOptions, RollRequest, RollResult, Visibilita,
+};
+use seven_seas_protocol::limiti::{MAX_BYTE_MESSAGGIO, MAX_TESTO};
+use seven_seas_protocol::{
+    ChatMessage, ClientMessage, Giocatore, Hello, Moderazione, Rifiuto, ServerMessage, Stanza,
+    PAGINA_LOG, STANZA_COMUNE, VERSIONE_PROTOCOLLO,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Avvia un server su una porta libera e restituisce l'URL del WebSocket
+async fn avvia() -> String {
+    avvia_con(|figment| figment).await
+}
+
+/// Come [`avvia`], cambiando la configurazione con `configura`
+async fn avvia_con(configura: impl FnOnce(Figment) -> Figment) -> String {
+    avvia_fermabile(configura).await.0
+}
+
+/// Come [`avvia_con`]; il server si spegne, salvando quello che ha in coda,
+/// aspettando quello che restituisce con lo spegnimento
+async fn avvia_fermabile(
+    configura: impl FnOnce(Figment) -> Figment,
+) -> (String, impl std::future::Future<Output = ()>) {
+    let porta = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let figment = rocket::Config::figment()
+        .merge(("port", porta))
+        .merge(("log_level", "off"))
+        .merge(("archivio", IN_MEMORIA));
+    let server = seven_seas_backend::rocket()
+        .configure(configura(figment))
+        .ignite()
+        .await
+        .unwrap();
+    let spegni = server.shutdown();
+    let lanciato = tokio::spawn(server.launch());
+    let fermo = async move {
+        spegni.notify();
+        lanciato.await.unwrap().unwrap();
+    };
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", porta)).await.is_ok() {
+            return (format!("ws://127.0.0.1:{}/ws/", porta), fermo);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("il server non è partito");
+}
+
+/// Si collega senza gettone; restituisce il client e il gettone nuovo, il
+/// primo messaggio del server
+async fn connetti(url: &str) -> (Client, String) {
+    let (mut client, _) = connect_async(url).await.unwrap();
+    let ServerMessage::SessionToken(gettone) = ricevi(&mut client).await else {
+        panic!("senza gettone il primo messaggio deve essere uno nuovo");
+    };
+    (client, gettone)
+}
+
+/// Si collega senza nome e consuma i messaggi iniziali; restituisce il log
+/// ricevuto
+async fn collega(url: &str) -> (Client, Vec<ChatMessage>) {
+    let (mut client, _) = connetti(url).await;
+    let (_, storico, _) = entrata(&mut client).await;
+    (client, storico)
+}
+
+/// Come [`collega`], entrando come `username`
+async fn collega_come(url: &str, username: &str) -> (Client, Vec<ChatMessage>) {
+    let separatore = if url.contains('?') { '&' } else { '?' };
+    collega(&format!("{}{}username={}", url, separatore, username)).await
+}
+
+/// I messaggi che arrivano entrando in una stanza: la stanza, il suo log e
+/// i giocatori
+async fn entrata(client: &mut Client) -> (Stanza, Vec<ChatMessage>, Vec<Giocatore>) {
+    let ServerMessage::RoomJoined(stanza) = ricevi(client).await else {
+        panic!("il primo messaggio deve essere la stanza");
+    };
+    let ServerMessage::FullHistory(storico) = ricevi(client).await else {
+        panic!("dopo la stanza arriva il log");
+    };
+    assert!(matches!(
+        ricevi(client).await,
+        ServerMessage::SeedCommitment(_)
+    ));
+    (stanza, storico, ricevi_presenze(client).await)
+}
+
+async fn ricevi_presenze(client: &mut Client) -> Vec<Giocatore> {
+    match ricevi(client).await {
+        ServerMessage::Presence(giocatori) => giocatori,
+        altro => panic!("attesi i giocatori, arrivato {:?}", altro),
+    }
+}
+
+/// Nomi e stato di collegamento dei giocatori
+fn presenti(giocatori: &[Giocatore]) -> Vec<(&str, bool)> {
+    giocatori
+        .iter()
+        .map(|g| (g.username.as_str(), g.collegato))
+        .collect()
+}
+
+async fn invia(client: &mut Client, msg: &ClientMessage) {
+    let txt = serde_json::to_string(msg).unwrap();
+    client.send(Message::Text(txt)).await.unwrap();
+}
+
+async fn ricevi(client: &mut Client) -> ServerMessage {
+    loop {
+        let msg = timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("nessun messaggio dal server")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(txt) = msg {
+            return serde_json::from_str(&txt).unwrap();
+        }
+    }
+}
+
+fn chat(username: &str, message: &str) -> ChatMessage {
+    ChatMessage::new(username, message)
+}
+
+/// La prossima riga nuova del log
+async fn ricevi_riga(client: &mut Client) -> ChatMessage {
+    match ricevi(client).await {
+        ServerMessage::NewMessage(msg) => msg,
+        altro => panic!("attesa una riga del log, arrivato {:?}", altro),
+    }
+}
+
+/// Il prossimo tiro, dopo la riga del log che lo riporta
+async fn ricevi_tiro(client: &mut Client) -> RollResult {
+    let riga = ricevi_riga(client).await;
+    let ServerMessage::RollResult(tiro) = ricevi(client).await else {
+        panic!("atteso un RollResult");
+    };
+    assert_eq!(riga.tiro.as_deref(), Some(&*tiro));
+    *tiro
+}
+
+/// Quanti tiri di `tiri` tornano col seme rivelato, se c'è tutta la sessione
+fn verificati<'a>(
+    seme: &str,
+    tiri: impl IntoIterator<Item = &'a RollResult>,
+) -> Result<usize, seven_seas_core::ErroreVerifica> {
+    verifica_sessione(seme, tiri).map(|v| {
+        assert!(v.completa(), "sessione incompleta: {:?}", v);
+        v.verificati
+    })
+}
+
+fn richiesta(username: &str, num_dadi: usize) -> ClientMessage {
+    ClientMessage::RollRequest(RollRequest {
+        username: username.into(),
+        opzioni: RollOptions::new(num_dadi),
+        nonce: format!("nonce di {}", username),
+        visibilita: None,
+    })
+}
+
+#[rocket::async_test]
+async fn messaggi_a_tutti_i_client() {
+    let url = avvia().await;
+    let (mut anna, storico) = collega_come(&url, "Anna").await;
+    assert!(storico.is_empty());
+    let (mut bruno, _) = collega(&url).await;
+
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", "ciao"))).await;
+    let riga = ricevi_riga(&mut anna).await;
+    assert_eq!(
+        (riga.username.as_str(), riga.message.as_str()),
+        ("Anna", "ciao")
+    );
+    assert_eq!(riga.seq, Some(1));
+    assert!(riga.timestamp.is_some());
+    assert_eq!(ricevi_riga(&mut bruno).await, riga);
+
+    // chi arriva dopo riceve il log
+    let (_, storico) = collega(&url).await;
+    assert_eq!(storico, vec![riga]);
+}
+
+#[rocket::async_test]
+async fn formato_json_del_protocollo() {
+    let url = avvia().await;
+    let (mut client, _) = collega_come(&url, "Anna").await;
+
+    let json = r#"{"type":"AddMessage","data":{"username":"Anna","message":"ciao"}}"#;
+    client.send(Message::Text(json.into())).await.unwrap();
+    let Some(Ok(Message::Text(risposta))) = client.next().await else {
+        panic!("risposta mancante");
+    };
+    let risposta: serde_json::Value = serde_json::from_str(&risposta).unwrap();
+    assert_eq!(risposta["type"], "NewMessage");
+    assert_eq!(risposta["data"]["username"], "Anna");
+    assert_eq!(risposta["data"]["message"], "ciao");
+    assert_eq!(risposta["data"]["seq"], 1);
+    assert!(risposta["data"]["timestamp"].is_u64());
+}
+
+#[rocket::async_test]
+async fn i_tiri_li_fa_il_server() {
+    let url = avvia().await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    let (mut bruno, _) = collega_come(&url, "Bruno").await;
+    ricevi_presenze(&mut anna).await;
+
+    invia(&mut anna, &richiesta("Anna", 5)).await;
+    let tiro = ricevi_tiro(&mut anna).await;
+    assert_eq!(tiro.username, "Anna");
+    assert_eq!(tiro.esito.risultati_aggiornati.len(), 5);
+    assert!(tiro.prova.is_some());
+    assert_eq!(ricevi_tiro(&mut bruno).await, tiro);
+
+    // un client non può scrivere un tiro nel log, né scegliersi il numero
+    let mut falso = chat("Bruno", "Bruno ha tirato 10 dadi: tutti 10");
+    falso.tiro = Some(Box::new(tiro));
+    falso.seq = Some(1);
+    invia(&mut bruno, &ClientMessage::AddMessage(falso)).await;
+    let riga = ricevi_riga(&mut bruno).await;
+    assert_eq!(riga.tiro, None);
+    assert_eq!(riga.seq, Some(2));
+}
+
+#[rocket::async_test]
+async fn errori_solo_al_mittente() {
+    let url = avvia().await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    let (mut bruno, _) = collega(&url).await;
+
+    invia(&mut anna, &richiesta("Anna", 0)).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    anna.send(Message::Text("non è json".into())).await.unwrap();
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    // Bruno riceve solo il messaggio successivo
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", "ok"))).await;
+    assert_eq!(ricevi_riga(&mut bruno).await.message, "ok");
+}
+
+#[rocket::async_test]
+async fn ripresa_dopo_una_disconnessione() {
+    let url = avvia().await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    for testo in ["uno", "due", "tre"] {
+        invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", testo))).await;
+        ricevi_riga(&mut anna).await;
+    }
+
+    // riconnessione dopo aver visto la prima riga
+    let (mut bruno, _) = connetti(&format!("{}?after=1", url)).await;
+    assert!(matches!(
+        ricevi(&mut bruno).await,
+        ServerMessage::RoomJoined(_)
+    ));
+    let ServerMessage::Resumed(mancanti) = ricevi(&mut bruno).await else {
+        panic!("attese le righe mancanti");
+    };
+    let seq: Vec<Option<u64>> = mancanti.iter().map(|m| m.seq).collect();
+    assert_eq!(seq, [Some(2), Some(3)]);
+    assert!(matches!(
+        ricevi(&mut bruno).await,
+        ServerMessage::SeedCommitment(_)
+    ));
+    ricevi_presenze(&mut bruno).await;
+
+    // la stessa domanda si può fare anche a connessione aperta
+    invia(&mut bruno, &ClientMessage::Resume(3)).await;
+    assert_eq!(ricevi(&mut bruno).await, ServerMessage::Resumed(Vec::new()));
+
+    // un numero mai assegnato (es. server riavviato) riporta il log completo
+    invia(&mut bruno, &ClientMessage::Resume(99)).await;
+    let ServerMessage::FullHistory(storico) = ricevi(&mut bruno).await else {
+        panic!("atteso il log completo");
+    };
+    assert_eq!(storico.len(), 3);
+}
+
+#[rocket::async_test]
+async fn fine_sessione_e_verifica() {
+    let url = avvia().await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+
+    let mut tiri = Vec::new();
+    for num_dadi in [3, 6, 9] {
+        invia(&mut anna, &richiesta("Anna", num_dadi)).await;
+        tiri.push(ricevi_tiro(&mut anna).await);
+    }
+
+    invia(&mut anna, &ClientMessage::EndSession).await;
+    let ServerMessage::SeedReveal(seme) = ricevi(&mut anna).await else {
+        panic!("atteso il seme");
+    };
+    assert!(matches!(
+        ricevi(&mut anna).await,
+        ServerMessage::SeedCommitment(_)
+    ));
+    assert_eq!(verificati(&seme, &tiri), Ok(3));
+}
+
+#[rocket::async_test]
+async fn presentazione_e_versioni() {
+    let url = avvia().await;
+    let (mut anna, _) = collega(&url).await;
+    invia(&mut anna, &ClientMessage::Hello(Hello::new("test/1.0"))).await;
+    let ServerMessage::Welcome(benvenuto) = ricevi(&mut anna).await else {
+        panic!("atteso il Welcome");
+    };
+    assert_eq!(benvenuto.versione, VERSIONE_PROTOCOLLO);
+    assert!(benvenuto.supporta("tiri"));
+
+    // un client troppo vecchio viene avvisato e scollegato
+    let (mut vecchio, _) = collega(&url).await;
+    let mut hello = Hello::new("test/0.1");
+    hello.versione = 1;
+    invia(&mut vecchio, &ClientMessage::Hello(hello)).await;
+    assert!(matches!(
+        ricevi(&mut vecchio).await,
+        ServerMessage::Incompatible { versione, .. } if versione == VERSIONE_PROTOCOLLO
+    ));
+    let fine = timeout(Duration::from_secs(5), vecchio.next())
+        .await
+        .unwrap();
+    assert!(matches!(
+        fine,
+        None | Some(Ok(Message::Close(_))) | Some(Err(_))
+    ));
+}
+
+#[rocket::async_test]
+async fn messaggi_sconosciuti_non_chiudono() {
+    let url = avvia().await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+
+    let json = r#"{"type":"Teletrasporto","data":{"dove":"Vodacce"}}"#;
+    anna.send(Message::Text(json.into())).await.unwrap();
+    let ServerMessage::System(avviso) = ricevi(&mut anna).await else {
+        panic!("atteso un avviso");
+    };
+    assert!(avviso.contains("Teletrasporto"));
+
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "ancora qui")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "ancora qui");
+}
+
+#[rocket::async_test]
+async fn stanze_separate() {
+    let url = avvia().await;
+    let (mut carla, _) = collega_come(&url, "Carla").await;
+    let (mut anna, _) = collega(&url).await;
+
+    let crea = ClientMessage::CreateRoom {
+        nome: "Campagna".into(),
+        username: "Anna".into(),
+        password_gm: None,
+    };
+    invia(&mut anna, &crea).await;
+    let (stanza, storico, giocatori) = entrata(&mut anna).await;
+    assert_eq!(stanza.nome, "Campagna");
+    assert_eq!(stanza.codice.len(), 6);
+    assert_eq!(stanza.gm.as_deref(), Some("Anna"));
+    assert_eq!(presenti(&giocatori), [("Anna", true)]);
+    assert!(storico.is_empty());
+
+    // il codice si può scrivere anche in minuscolo
+    let indirizzo = format!(
+        "{}?room={}&username=Bruno",
+        url,
+        stanza.codice.to_lowercase()
+    );
+    let (mut bruno, _) = connetti(&indirizzo).await;
+    let (per_bruno, _, giocatori) = entrata(&mut bruno).await;
+    assert_eq!(per_bruno, stanza);
+    assert_eq!(presenti(&giocatori), [("Anna", true), ("Bruno", true)]);
+    assert_eq!(ricevi_presenze(&mut anna).await, giocatori);
+
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "solo qui")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "solo qui");
+    assert_eq!(ricevi_riga(&mut bruno).await.message, "solo qui");
+
+    // Carla è rimasta nella stanza comune, che ha il suo log
+    invia(
+        &mut carla,
+        &ClientMessage::AddMessage(chat("Carla", "ciao")),
+    )
+    .await;
+    let riga = ricevi_riga(&mut carla).await;
+    assert_eq!((riga.message.as_str(), riga.seq), ("ciao", Some(1)));
+
+    invia(&mut anna, &ClientMessage::LeaveRoom).await;
+    let (comune, storico, _) = entrata(&mut anna).await;
+    assert_eq!(comune.codice, STANZA_COMUNE);
+    assert_eq!(storico, vec![riga]);
+    let giocatori = ricevi_presenze(&mut bruno).await;
+    assert_eq!(presenti(&giocatori), [("Anna", false), ("Bruno", true)]);
+
+    // e ci si può rientrare col codice
+    let entra = ClientMessage::JoinRoom {
+        codice: stanza.codice.clone(),
+        username: "Anna".into(),
+        after: Some(1),
+    };
+    invia(&mut anna, &entra).await;
+    let ServerMessage::RoomJoined(di_nuovo) = ricevi(&mut anna).await else {
+        panic!("attesa la stanza");
+    };
+    assert_eq!(di_nuovo, stanza);
+    assert_eq!(ricevi(&mut anna).await, ServerMessage::Resumed(Vec::new()));
+    let giocatori = ricevi_presenze(&mut bruno).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true), ("Bruno", true)]);
+}
+
+#[rocket::async_test]
+async fn codici_sconosciuti() {
+    let url = avvia().await;
+    // chi chiede una stanza che non c'è finisce in quella comune
+    let (mut anna, _) = connetti(&format!("{}?room=ZZZZZZ&username=Anna", url)).await;
+    let (stanza, _, _) = entrata(&mut anna).await;
+    assert_eq!(stanza.codice, STANZA_COMUNE);
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    let entra = ClientMessage::JoinRoom {
+        codice: "ZZZZZZ".into(),
+        username: "Anna".into(),
+        after: None,
+    };
+    invia(&mut anna, &entra).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "ancora qui")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "ancora qui");
+}
+
+#[rocket::async_test]
+async fn presenze_dei_giocatori() {
+    let url = avvia().await;
+    let (mut anna, _) = connetti(&format!("{}?username=Anna", url)).await;
+    let (_, _, giocatori) = entrata(&mut anna).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true)]);
+
+    let (mut bruno, _) = connetti(&format!("{}?username=Bruno", url)).await;
+    entrata(&mut bruno).await;
+    let giocatori = ricevi_presenze(&mut anna).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true), ("Bruno", true)]);
+    let entrato = giocatori[1].ultima_attivita;
+
+    // chi non dice il suo nome non compare
+    let (_carla, _) = collega(&url).await;
+    invia(
+        &mut bruno,
+        &ClientMessage::AddMessage(chat("Bruno", "ciao")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "ciao");
+
+    bruno.close(None).await.unwrap();
+    let giocatori = ricevi_presenze(&mut anna).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true), ("Bruno", false)]);
+    assert!(giocatori[1].ultima_attivita >= entrato);
+}
+
+/// Anna crea una stanza (e ne è il GM), Bruno ci entra
+async fn stanza_col_gm(url: &str) -> (Client, Client, Stanza) {
+    let (mut anna, _) = collega(url).await;
+    let crea = ClientMessage::CreateRoom {
+        nome: "Campagna".into(),
+        username: "Anna".into(),
+        password_gm: Some("segreta".into()),
+    };
+    invia(&mut anna, &crea).await;
+    let (stanza, _, _) = entrata(&mut anna).await;
+
+    let indirizzo = format!("{}?room={}&username=Bruno", url, stanza.codice);
+    let (mut bruno, _) = connetti(&indirizzo).await;
+    entrata(&mut bruno).await;
+    ricevi_presenze(&mut anna).await;
+    (anna, bruno, stanza)
+}
+
+/// La richiesta di entrare in `stanza` come `username`
+fn entra_come(stanza: &Stanza, username: &str) -> ClientMessage {
+    ClientMessage::JoinRoom {
+        codice: stanza.codice.clone(),
+        username: username.into(),
+        after: None,
+    }
+}
+
+#[rocket::async_test]
+async fn il_gm_modera_il_log() {
+    let url = avvia().await;
+    let (mut anna, mut bruno, _) = stanza_col_gm(&url).await;
+
+    invia(
+        &mut bruno,
+        &ClientMessage::AddMessage(chat("Bruno", "oops")),
+    )
+    .await;
+    ricevi_riga(&mut anna).await;
+    ricevi_riga(&mut bruno).await;
+    invia(&mut bruno, &richiesta("Bruno", 4)).await;
+    let tiro = ricevi_tiro(&mut anna).await;
+    ricevi_tiro(&mut bruno).await;
+
+    // solo il GM può toccare il log
+    invia(&mut bruno, &ClientMessage::ClearHistory).await;
+    assert!(matches!(ricevi(&mut bruno).await, ServerMessage::System(_)));
+
+    invia(&mut anna, &ClientMessage::VoidEntry(1)).await;
+    let ServerMessage::EntryUpdated(annullata) = ricevi(&mut bruno).await else {
+        panic!("attesa la riga annullata");
+    };
+    assert_eq!(annullata.seq, Some(1));
+    assert_eq!(annullata.message, "oops");
+    assert_eq!(annullata.moderata, Some(Moderazione::Annullata));
+    assert_eq!(
+        ricevi(&mut anna).await,
+        ServerMessage::EntryUpdated(annullata)
+    );
+
+    // la riga eliminata perde il testo ma non il tiro
+    invia(&mut anna, &ClientMessage::DeleteEntry(2)).await;
+    let ServerMessage::EntryUpdated(eliminata) = ricevi(&mut bruno).await else {
+        panic!("attesa la riga eliminata");
+    };
+    assert_eq!(eliminata.message, "");
+    assert_eq!(eliminata.moderata, Some(Moderazione::Eliminata));
+    assert_eq!(eliminata.tiro.as_deref(), Some(&tiro));
+    assert_eq!(
+        ricevi(&mut anna).await,
+        ServerMessage::EntryUpdated(eliminata)
+    );
+
+    invia(&mut anna, &ClientMessage::DeleteEntry(9)).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    // svuotare il log chiude la sessione: i tiri cancellati si verificano
+    invia(&mut anna, &ClientMessage::ClearHistory).await;
+    let ServerMessage::SeedReveal(seme) = ricevi(&mut bruno).await else {
+        panic!("atteso il seme");
+    };
+    assert_eq!(verificati(&seme, &[tiro]), Ok(1));
+    assert!(matches!(
+        ricevi(&mut bruno).await,
+        ServerMessage::SeedCommitment(_)
+    ));
+    assert_eq!(ricevi(&mut bruno).await, ServerMessage::HistoryCleared);
+
+    // i numeri non ripartono da capo
+    invia(
+        &mut bruno,
+        &ClientMessage::AddMessage(chat("Bruno", "di nuovo")),
+    )
+    .await;
+    let riga = ricevi_riga(&mut bruno).await;
+    assert_eq!(riga.seq, Some(3));
+    invia(&mut bruno, &ClientMessage::Resume(2)).await;
+    assert_eq!(
+        ricevi(&mut bruno).await,
+        ServerMessage::FullHistory(vec![riga.clone()])
+    );
+    invia(&mut bruno, &ClientMessage::Resume(3)).await;
+    assert_eq!(ricevi(&mut bruno).await, ServerMessage::Resumed(Vec::new()));
+}
+
+#[rocket::async_test]
+async fn stanza_bloccata_e_giocatori_mandati_via() {
+    let url = avvia().await;
+    let (mut anna, mut bruno, stanza) = stanza_col_gm(&url).await;
+
+    invia(&mut bruno, &ClientMessage::LockRoom(true)).await;
+    assert!(matches!(ricevi(&mut bruno).await, ServerMessage::System(_)));
+    invia(&mut anna, &ClientMessage::LockRoom(true)).await;
+    let ServerMessage::RoomUpdated(bloccata) = ricevi(&mut bruno).await else {
+        panic!("attesa la stanza aggiornata");
+    };
+    assert!(bloccata.bloccata);
+    ricevi(&mut anna).await;
+
+    // chi non c'era resta fuori
+    let (mut carla, _) = collega(&url).await;
+    invia(&mut carla, &entra_come(&stanza, "Carla")).await;
+    assert!(matches!(ricevi(&mut carla).await, ServerMessage::System(_)));
+
+    invia(
+        &mut anna,
+        &ClientMessage::Kick {
+            username: "Bruno".into(),
+        },
+    )
+    .await;
+    assert_eq!(
+        ricevi(&mut anna).await,
+        ServerMessage::Kicked("Bruno".into())
+    );
+    assert_eq!(
+        presenti(&ricevi_presenze(&mut anna).await),
+        [("Anna", true)]
+    );
+    assert_eq!(
+        ricevi(&mut bruno).await,
+        ServerMessage::Kicked("Bruno".into())
+    );
+    let (comune, _, _) = entrata(&mut bruno).await;
+    assert_eq!(comune.codice, STANZA_COMUNE);
+
+    // e non rientra, nemmeno con la stanza aperta o con un altro nome
+    invia(&mut anna, &ClientMessage::LockRoom(false)).await;
+    ricevi(&mut anna).await;
+    for username in ["Bruno", "Bruno2", ""] {
+        invia(&mut bruno, &entra_come(&stanza, username)).await;
+        assert!(matches!(ricevi(&mut bruno).await, ServerMessage::System(_)));
+    }
+
+    // il GM non può mandare via sé stesso
+    let via = ClientMessage::Kick {
+        username: "Anna".into(),
+    };
+    invia(&mut anna, &via).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    // con la password il ruolo di GM passa a Carla
+    let (mut carla, _) = collega(&url).await;
+    invia(&mut carla, &entra_come(&stanza, "Carla")).await;
+    entrata(&mut carla).await;
+    ricevi_presenze(&mut anna).await;
+    let prendi = |password: &str| ClientMessage::ClaimGm {
+        password: password.into(),
+    };
+    invia(&mut carla, &prendi("sbagliata")).await;
+    assert!(matches!(ricevi(&mut carla).await, ServerMessage::System(_)));
+    invia(&mut carla, &prendi("segreta")).await;
+    let ServerMessage::RoomUpdated(aggiornata) = ricevi(&mut anna).await else {
+        panic!("attesa la stanza aggiornata");
+    };
+    assert_eq!(aggiornata.gm.as_deref(), Some("Carla"));
+    invia(&mut anna, &ClientMessage::ClearHistory).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    // le password non si provano a raffica: dopo tre richieste si aspetta
+    while !matches!(ricevi(&mut carla).await, ServerMessage::RoomUpdated(_)) {}
+    invia(&mut carla, &prendi("sbagliata")).await;
+    assert_eq!(
+        ricevi(&mut carla).await,
+        ServerMessage::System("Password del GM sbagliata".into())
+    );
+    invia(&mut carla, &prendi("segreta")).await;
+    assert!(matches!(
+        ricevi(&mut carla).await,
+        ServerMessage::Rejected(Rifiuto::TroppeRichieste { .. })
+    ));
+
+    // senza nome non si diventa GM, nemmeno con la password giusta
+    let (mut anonimo, _) = collega(&url).await;
+    invia(&mut anonimo, &entra_come(&stanza, "")).await;
+    entrata(&mut anonimo).await;
+    invia(&mut anonimo, &prendi("segreta")).await;
+    assert_eq!(
+        ricevi(&mut anonimo).await,
+        ServerMessage::System("Per diventare GM entra nella stanza con un nome".into())
+    );
+}
+
+#[rocket::async_test]
+async fn tiri_nascosti() {
+    let url = avvia().await;
+    let (mut anna, mut bruno, stanza) = stanza_col_gm(&url).await;
+    let indirizzo = format!("{}?room={}&username=Carla", url, stanza.codice);
+    let (mut carla, _) = connetti(&indirizzo).await;
+    entrata(&mut carla).await;
+    ricevi_presenze(&mut anna).await;
+    ricevi_presenze(&mut bruno).await;
+
+    let nascosto = |visibilita| {
+        let ClientMessage::RollRequest(richiesta) = richiesta("Bruno", 4) else {
+            unreachable!()
+        };
+        ClientMessage::RollRequest(RollRequest {
+            nonce: nonce_casuale(),
+            visibilita: Some(visibilita),
+            ..richiesta
+        })
+    };
+
+    // il GM e chi tira vedono tutto, gli altri solo che c'è stato un tiro
+    invia(&mut bruno, &nascosto(Visibilita::SoloGm)).await;
+    let per_gm = ricevi_tiro(&mut bruno).await;
+    assert_eq!(per_gm.visibilita, Some(Visibilita::SoloGm));
+    assert_eq!(ricevi_tiro(&mut anna).await, per_gm);
+    let coperta = ricevi_riga(&mut carla).await;
+    assert_eq!(coperta.message, "Bruno ha fatto un tiro nascosto");
+    let coperto = coperta.tiro.unwrap();
+    assert!(coperto.coperto);
+    assert!(coperto.esito.risultati_aggiornati.is_empty());
+    // senza opzioni e nonce il seme rivelato non basta a rifarlo
+    assert_eq!(coperto.opzioni, RollOptions::new(0));
+    assert!(coperto.prova.as_ref().unwrap().nonce.is_empty());
+
+    invia(
+        &mut bruno,
+        &nascosto(Visibilita::Giocatori(vec!["Carla".into()])),
+    )
+    .await;
+    let per_carla = ricevi_tiro(&mut carla).await;
+    assert_eq!(ricevi_tiro(&mut bruno).await, per_carla);
+    let riga = ricevi_riga(&mut anna).await;
+    assert!(riga.tiro.unwrap().coperto);
+    // il RollResult non arriva: la prossima cosa per Anna è la chat
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "visto?")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "visto?");
+
+    // anche il log di chi entra dopo è coperto
+    let (_, storico) = collega(&format!("{}?room={}", url, stanza.codice)).await;
+    assert!(storico[..2]
+        .iter()
+        .all(|r| r.tiro.as_ref().unwrap().coperto));
+
+    // la sessione la chiude il GM, non chi vorrebbe vedere i tiri coperti
+    ricevi_riga(&mut carla).await;
+    invia(&mut carla, &ClientMessage::EndSession).await;
+    assert!(matches!(ricevi(&mut carla).await, ServerMessage::System(_)));
+
+    // i tiri coperti contano nella verifica, senza essere rifatti
+    invia(&mut anna, &ClientMessage::EndSession).await;
+    let ServerMessage::SeedReveal(seme) = ricevi(&mut carla).await else {
+        panic!("atteso il seme");
+    };
+    assert_eq!(verificati(&seme, [&*coperto, &per_carla]), Ok(1));
+    assert_eq!(verificati(&seme, [&per_gm, &per_carla]), Ok(2));
+}
+
+#[rocket::async_test]
+async fn pagine_del_log() {
+    // più messaggi di quanti ne passino in una raffica
+    let senza_limiti = rocket::figment::util::map! {
+        "al_secondo" => 10_000.0,
+        "raffica" => 10_000.0,
+    };
+    let url = avvia_con(|figment| {
+        figment
+            .merge(("frequenza.connessione", &senza_limiti))
+            .merge(("frequenza.ip", &senza_limiti))
+    })
+    .await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    let totale = PAGINA_LOG as u64 + 5;
+    for i in 1..=totale {
+        invia(
+            &mut anna,
+            &ClientMessage::AddMessage(chat("Anna", &i.to_string())),
+        )
+        .await;
+        ricevi_riga(&mut anna).await;
+    }
+
+    // entrando arrivano solo le ultime righe
+    let (mut bruno, storico) = collega(&url).await;
+    assert_eq!(storico.len(), PAGINA_LOG);
+    assert_eq!(storico[0].seq, Some(6));
+
+    // le precedenti si chiedono a pagine
+    let prima = |before, limit| ClientMessage::LoadOlder { before, limit };
+    invia(&mut bruno, &prima(50, Some(10))).await;
+    let ServerMessage::OlderHistory { righe, altre } = ricevi(&mut bruno).await else {
+        panic!("attesa una pagina del log");
+    };
+    let seq: Vec<u64> = righe.iter().filter_map(|r| r.seq).collect();
+    assert_eq!(seq, (40..50).collect::<Vec<_>>());
+    assert!(altre);
+
+    invia(&mut bruno, &prima(6, None)).await;
+    let ServerMessage::OlderHistory { righe, altre } = ricevi(&mut bruno).await else {
+        panic!("attesa una pagina del log");
+    };
+    assert_eq!(righe.len(), 5);
+    assert_eq!(righe[0].message, "1");
+    assert!(!altre);
+
+    // chi è rimasto indietro di più di una pagina riparte dalle ultime
+    invia(&mut bruno, &ClientMessage::Resume(2)).await;
+    let ServerMessage::FullHistory(storico) = ricevi(&mut bruno).await else {
+        panic!("attese le ultime righe");
+    };
+    assert_eq!(storico.len(), PAGINA_LOG);
+}
+
+#[rocket::async_test]
+async fn storico_dopo_un_riavvio() {
+    let file = std::env::temp_dir().join(format!("seven_seas_{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let archivio = file.to_str().unwrap();
+
+    let (url, spegni) = avvia_fermabile(|figment| figment.merge(("archivio", archivio))).await;
+    let (mut anna, gettone) = connetti(&url).await;
+    entrata(&mut anna).await;
+    let crea = ClientMessage::CreateRoom {
+        nome: "Campagna".into(),
+        username: "Anna".into(),
+        password_gm: Some("segreta".into()),
+    };
+    invia(&mut anna, &crea).await;
+    let (stanza, _, _) = entrata(&mut anna).await;
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", "ciao"))).await;
+    ricevi_riga(&mut anna).await;
+    invia(&mut anna, &richiesta("Anna", 3)).await;
+    let primo = ricevi_tiro(&mut anna).await;
+    invia(&mut anna, &ClientMessage::VoidEntry(1)).await;
+    let ServerMessage::EntryUpdated(annullata) = ricevi(&mut anna).await else {
+        panic!("attesa la riga annullata");
+    };
+
+    // Bruno e Carla entrano, Carla viene mandata via e la stanza si chiude
+    let mut gettoni = Vec::new();
+    for username in ["Bruno", "Carla"] {
+        let indirizzo = format!("{}?room={}&username={}", url, stanza.codice, username);
+        let (mut client, gettone) = connetti(&indirizzo).await;
+        entrata(&mut client).await;
+        gettoni.push(gettone);
+    }
+    let via = ClientMessage::Kick {
+        username: "Carla".into(),
+    };
+    invia(&mut anna, &via).await;
+    invia(&mut anna, &ClientMessage::LockRoom(true)).await;
+    while !matches!(ricevi(&mut anna).await, ServerMessage::RoomUpdated(_)) {}
+
+    // un altro server sullo stesso archivio ritrova stanza, log, sessione,
+    // gettoni e giocatori mandati via; la password del GM non è nel file
+    drop(anna);
+    spegni.await;
+    let salvato = std::fs::read(&file).unwrap();
+    assert!(!salvato.windows(7).any(|w| w == b"segreta"));
+    let url = avvia_con(|figment| figment.merge(("archivio", archivio))).await;
+    let indirizzo = format!(
+        "{}?room={}&username=Anna&token={}",
+        url, stanza.codice, gettone
+    );
+    let (mut anna, _) = connect_async(indirizzo).await.unwrap();
+    let (ritrovata, storico, _) = entrata(&mut anna).await;
+    let chiusa = Stanza {
+        bloccata: true,
+        ..stanza.clone()
+    };
+    assert_eq!(ritrovata, chiusa);
+    assert_eq!(storico.len(), 2);
+    assert_eq!(storico[0], annullata);
+    assert_eq!(storico[1].tiro.as_deref(), Some(&primo));
+
+    invia(&mut anna, &richiesta("Anna", 3)).await;
+    let secondo = ricevi_tiro(&mut anna).await;
+    assert_eq!(secondo.prova.as_ref().unwrap().contatore, 1);
+    invia(&mut anna, &ClientMessage::EndSession).await;
+    let ServerMessage::SeedReveal(seme) = ricevi(&mut anna).await else {
+        panic!("atteso il seme");
+    };
+    assert_eq!(verificati(&seme, [&primo, &secondo]), Ok(2));
+
+    // nella stanza chiusa rientra chi c'era, ma non chi è stato mandato via
+    let [bruno, carla] = [("Bruno", &gettoni[0]), ("Carla", &gettoni[1])]
+        .map(|(u, g)| format!("{}?room={}&username={}&token={}", url, stanza.codice, u, g));
+    let (mut bruno, _) = connect_async(bruno).await.unwrap();
+    let (dentro, _, _) = entrata(&mut bruno).await;
+    assert_eq!(dentro.codice, stanza.codice);
+    let (mut carla, _) = connect_async(carla).await.unwrap();
+    let (fuori, _, _) = entrata(&mut carla).await;
+    assert_eq!(fuori.codice, STANZA_COMUNE);
+    assert!(matches!(ricevi(&mut carla).await, ServerMessage::System(_)));
+
+    // e la password del GM vale ancora
+    let prendi = ClientMessage::ClaimGm {
+        password: "segreta".into(),
+    };
+    invia(&mut bruno, &prendi).await;
+    while !matches!(ricevi(&mut bruno).await, ServerMessage::RoomUpdated(_)) {}
+    let _ = std::fs::remove_file(&file);
+}
+
+#[rocket::async_test]
+async fn nomi_protetti_dai_gettoni() {
+    let url = avvia().await;
+    let (mut anna, gettone) = connetti(&format!("{}?username=Anna", url)).await;
+    entrata(&mut anna).await;
+
+    // chi si presenta col nome di Anna ma senza il suo gettone entra senza
+    // nome, e non può scrivere né tirare per lei
+    let (mut falsa, altro) = connetti(&format!("{}?username=Anna", url)).await;
+    assert_ne!(altro, gettone);
+    let (_, _, giocatori) = entrata(&mut falsa).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true)]);
+    assert!(matches!(ricevi(&mut falsa).await, ServerMessage::System(_)));
+    invia(
+        &mut falsa,
+        &ClientMessage::AddMessage(chat("Anna", "sono io")),
+    )
+    .await;
+    assert!(matches!(ricevi(&mut falsa).await, ServerMessage::System(_)));
+    invia(&mut falsa, &richiesta("Anna", 3)).await;
+    assert!(matches!(ricevi(&mut falsa).await, ServerMessage::System(_)));
+
+    // e nemmeno Anna può scrivere come un altro
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Bruno", "ciao"))).await;
+    assert!(matches!(ricevi(&mut anna).await, ServerMessage::System(_)));
+
+    // col gettone il nome torna suo, anche da un'altra connessione
+    let indirizzo = format!("{}?username=Anna&token={}", url, gettone);
+    let (mut di_nuovo, _) = connect_async(indirizzo).await.unwrap();
+    entrata(&mut di_nuovo).await;
+    invia(
+        &mut di_nuovo,
+        &ClientMessage::AddMessage(chat("Anna", "ciao")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut di_nuovo).await.username, "Anna");
+
+    // un gettone falso vale come nessun gettone
+    let (_, nuovo) = connetti(&format!("{}?token={}x", url, gettone)).await;
+    assert_ne!(nuovo, gettone);
+}
+
+#[rocket::async_test]
+async fn messaggi_fuori_dai_limiti() {
+    let url = avvia().await;
+
+    // con un nome non valido si entra senza, e lo si viene a sapere
+    let (mut anonimo, _) = connetti(&format!("{}?username=%3Cscript%3E", url)).await;
+    entrata(&mut anonimo).await;
+    assert_eq!(
+        ricevi(&mut anonimo).await,
+        ServerMessage::Rejected(Rifiuto::UsernameNonValido("<script>".into()))
+    );
+
+    // i messaggi troppo lunghi vengono scartati senza chiudere il socket
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    let lungo = "a".repeat(MAX_TESTO + 1);
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", &lungo))).await;
+    assert_eq!(
+        ricevi(&mut anna).await,
+        ServerMessage::Rejected(Rifiuto::TroppoLungo {
+            campo: "message".into(),
+            massimo: MAX_TESTO
+        })
+    );
+    let enorme = format!("\"{}\"", "a".repeat(MAX_BYTE_MESSAGGIO));
+    anna.send(Message::Text(enorme)).await.unwrap();
+    assert_eq!(
+        ricevi(&mut anna).await,
+        ServerMessage::Rejected(Rifiuto::MessaggioTroppoGrande {
+            massimo: MAX_BYTE_MESSAGGIO
+        })
+    );
+    invia(&mut anna, &ClientMessage::AddMessage(chat("Anna", "ciao"))).await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "ciao");
+}
+
+#[rocket::async_test]
+async fn troppi_messaggi() {
+    let url = avvia().await;
+    let raffica = Limiti::default().connessione.raffica as usize;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    for i in 0..raffica {
+        invia(
+            &mut anna,
+            &ClientMessage::AddMessage(chat("Anna", &i.to_string())),
+        )
+        .await;
+        ricevi_riga(&mut anna).await;
+    }
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "ancora")),
+    )
+    .await;
+    let ServerMessage::Rejected(Rifiuto::TroppeRichieste { attesa_ms }) = ricevi(&mut anna).await
+    else {
+        panic!("atteso il rifiuto per troppi messaggi");
+    };
+    assert!(attesa_ms > 0);
+
+    // aspettando si torna a scrivere
+    tokio::time::sleep(Duration::from_millis(attesa_ms + 50)).await;
+    invia(
+        &mut anna,
+        &ClientMessage::AddMessage(chat("Anna", "ancora")),
+    )
+    .await;
+    assert_eq!(ricevi_riga(&mut anna).await.message, "ancora");
+}
+
+#[rocket::async_test]
+async fn frequenze_inutilizzabili_fermano_il_server() {
+    for (al_secondo, raffica) in [(0.0, 10.0), (-1.0, 10.0), (10.0, 0.0)] {
+        let frequenza = rocket::figment::util::map! {
+            "al_secondo" => al_secondo,
+            "raffica" => raffica,
+        };
+        let figment = rocket::Config::figment()
+            .merge(("log_level", "off"))
+            .merge(("archivio", IN_MEMORIA))
+            .merge(("frequenza.ip", &frequenza));
+        let server = seven_seas_backend::rocket().configure(figment);
+        let errore = server
+            .ignite()
+            .await
+            .expect_err("attesa la configurazione rifiutata");
+        assert!(
+            matches!(errore.kind(), ErrorKind::FailedFairings(_)),
+            "{:?}",
+            frequenza
+        );
+    }
+}
+
+#[rocket::async_test]
+async fn connessioni_silenziose_chiuse() {
+    let url = avvia_con(|figment| {
+        figment
+            .merge(("battito.intervallo_ms", 100))
+            .merge(("battito.scadenza_ms", 300))
+    })
+    .await;
+    let (mut anna, _) = collega_come(&url, "Anna").await;
+    invia(&mut anna, &ClientMessage::Hello(Hello::new("test/1.0"))).await;
+    let ServerMessage::Welcome(benvenuto) = ricevi(&mut anna).await else {
+        panic!("atteso il Welcome");
+    };
+    assert_eq!(benvenuto.battito_ms, Some(100));
+    invia(&mut anna, &ClientMessage::Ping(42)).await;
+    assert_eq!(ricevi(&mut anna).await, ServerMessage::Pong(42));
+
+    // Bruno legge, e così risponde ai ping del server; Anna non più
+    let (mut bruno, _) = connetti(&format!("{}?username=Bruno", url)).await;
+    let (_, _, giocatori) = entrata(&mut bruno).await;
+    assert_eq!(presenti(&giocatori), [("Anna", true), ("Bruno", true)]);
+    let giocatori = ricevi_presenze(&mut bruno).await;
+    assert_eq!(presenti(&giocatori), [("Anna", false), ("Bruno", true)]);
+
+    // Bruno invece resta collegato
+    invia(&mut bruno, &ClientMessage::Ping(7)).await;
+    assert_eq!(ricevi(&mut bruno).await, ServerMessage::Pong(7));
+    drop(anna);
+}
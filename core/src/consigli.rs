@@ -0,0 +1,146 @@
+//! Consigli per il rilancio di un dado (Skill a rank 3).
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollError;
+use crate::raises::{raggruppa_con, Regole, FACCE};
+
+/// Dadi oltre i quali non si danno consigli: i tiri da risolvere sono fino
+/// a cento, e sopra questa soglia ognuno passa dal rilassamento lineare e
+/// costa decine di volte di più
+pub const MAX_DADI_CONSIGLIO: usize = 30;
+
+/// Quanti raises ci si può aspettare rilanciando ciascun dado di un tiro
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Consiglio {
+    /// Raises del tiro senza rilanciare niente
+    pub attuali: usize,
+    /// Raises attesi rilanciando il dado nella stessa posizione del tiro
+    pub attesi: Vec<f64>,
+    /// Il dado che conviene rilanciare, se almeno uno migliora i raises
+    /// attuali
+    pub migliore: Option<usize>,
+}
+
+/// Per ogni dado di `dadi` calcola la media dei raises sulle dieci facce che
+/// può dare rilanciandolo, e indica il dado con la media più alta.
+///
+/// I dadi con la stessa faccia hanno la stessa attesa, quindi i tiri da
+/// risolvere sono al più cento. Errore se le regole non sono valide, se i
+/// dadi sono più di [`MAX_DADI_CONSIGLIO`] o se uno non va da 1 a 10 (come
+/// può succedere con una riga del log malformata).
+pub fn consiglia_rilancio(dadi: &[u8], regole: &Regole) -> Result<Consiglio, RollError> {
+    regole.validate()?;
+    if dadi.len() > MAX_DADI_CONSIGLIO {
+        return Err(RollError::TroppiDadi {
+            richiesti: dadi.len(),
+            massimo: MAX_DADI_CONSIGLIO,
+        });
+    }
+    if let Some(&faccia) = dadi.iter().find(|&&d| !(1..=FACCE as u8).contains(&d)) {
+        return Err(RollError::FacciaNonValida { faccia });
+    }
+    let attuali = raggruppa_con(dadi, regole).raises;
+
+    let mut per_faccia: [Option<f64>; FACCE + 1] = [None; FACCE + 1];
+    let attesi: Vec<f64> = dadi
+        .iter()
+        .enumerate()
+        .map(|(i, &d)| {
+            *per_faccia[d as usize].get_or_insert_with(|| {
+                let mut resto = dadi.to_vec();
+                resto.remove(i);
+                let totale: usize = (1..=FACCE as u8)
+                    .map(|nuovo| {
+                        resto.push(nuovo);
+                        let raises = raggruppa_con(&resto, regole).raises;
+                        resto.pop();
+                        raises
+                    })
+                    .sum();
+                totale as f64 / 10.0
+            })
+        })
+        .collect();
+
+    let mut migliore = None;
+    for (i, &atteso) in attesi.iter().enumerate() {
+        if atteso > migliore.map_or(attuali as f64, |m: usize| attesi[m]) {
+            migliore = Some(i);
+        }
+    }
+
+    Ok(Consiglio {
+        attuali,
+        attesi,
+        migliore,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vicino(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn conviene_rilanciare_il_dado_inutile() {
+        // rilanciando l'1 resta il 10 e si fa un altro raise con un 10;
+        // rilanciando il 10 serve almeno un 9 per accompagnare l'1
+        let c = consiglia_rilancio(&[10, 1], &Regole::default()).unwrap();
+        assert_eq!(c.attuali, 1);
+        assert!(vicino(c.attesi[0], 0.2));
+        assert!(vicino(c.attesi[1], 1.1));
+        assert_eq!(c.migliore, Some(1));
+    }
+
+    #[test]
+    fn nessun_consiglio_se_non_conviene() {
+        let c = consiglia_rilancio(&[5, 5], &Regole::default()).unwrap();
+        assert_eq!(c.attuali, 1);
+        assert!(c.attesi.iter().all(|&a| vicino(a, 0.6)));
+        assert_eq!(c.migliore, None);
+    }
+
+    #[test]
+    fn tiene_conto_delle_regole() {
+        // con i set doppi un 10 accanto al 5 vale due raises: dal 5 al 9
+        // se ne fa uno, col 10 due
+        let regole = Regole {
+            set_doppi: true,
+            ..Regole::default()
+        };
+        let c = consiglia_rilancio(&[5, 1], &regole).unwrap();
+        assert_eq!(c.attuali, 0);
+        assert!(vicino(c.attesi[1], 0.7));
+        assert_eq!(c.migliore, Some(1));
+    }
+
+    #[test]
+    fn niente_consigli_fuori_dai_limiti() {
+        let regole = Regole::default();
+        // una faccia che un dado non può dare non fa andare in panico
+        assert_eq!(
+            consiglia_rilancio(&[10, 11], &regole),
+            Err(RollError::FacciaNonValida { faccia: 11 })
+        );
+        assert_eq!(
+            consiglia_rilancio(&[0, 5], &regole),
+            Err(RollError::FacciaNonValida { faccia: 0 })
+        );
+        assert_eq!(
+            consiglia_rilancio(&[5; MAX_DADI_CONSIGLIO + 1], &regole),
+            Err(RollError::TroppiDadi {
+                richiesti: MAX_DADI_CONSIGLIO + 1,
+                massimo: MAX_DADI_CONSIGLIO
+            })
+        );
+        let regole = Regole {
+            target: 40,
+            ..regole
+        };
+        assert!(consiglia_rilancio(&[5, 5], &regole).is_err());
+    }
+}
@@ -0,0 +1,497 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollError;
+use crate::raises::{raggruppa_con, Regole, TARGET_RAISE};
+use crate::sorgente::{SorgenteDadi, SorgenteSicura};
+
+/// Numero massimo di dadi per un singolo tiro.
+pub const MAX_DADI: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiceResponse {
+    pub risultati: Vec<u8>,
+    pub raises: usize,
+    pub combinazioni: Vec<Vec<u8>>,
+    /// Dadi che non fanno parte di nessun set
+    #[serde(default)]
+    pub avanzi: Vec<u8>,
+    /// Nuovo valore del dado rilanciato, se c'è stato un rilancio
+    #[serde(default)]
+    pub rilanciato: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct RollWithRerollResponse {
+    pub risultati_originali: Vec<u8>,
+    pub rilanciato: Option<u8>,
+    pub risultati_aggiornati: Vec<u8>,
+    pub raises: usize,
+    pub combinazioni: Vec<Vec<u8>>,
+    /// Dadi che non fanno parte di nessun set
+    #[serde(default)]
+    pub avanzi: Vec<u8>,
+    /// Dadi aggiunti dai 10 esplosi, nell'ordine in cui sono usciti
+    #[serde(default)]
+    pub esplosi: Vec<u8>,
+    /// Azioni che si possono pagare coi raises, se il tiro ha un costo
+    #[serde(default)]
+    pub azioni: Option<usize>,
+}
+
+impl From<DiceResponse> for RollWithRerollResponse {
+    fn from(dr: DiceResponse) -> Self {
+        RollWithRerollResponse {
+            risultati_originali: dr.risultati.clone(),
+            rilanciato: dr.rilanciato,
+            risultati_aggiornati: dr.risultati,
+            raises: dr.raises,
+            combinazioni: dr.combinazioni,
+            avanzi: dr.avanzi,
+            esplosi: Vec::new(),
+            azioni: None,
+        }
+    }
+}
+
+/// Parametri di un tiro
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct RollOptions {
+    pub num_dadi: usize,
+    #[serde(default)]
+    pub rilancia_uno: bool,
+    /// Skill a rank 4: i set da 15 valgono due raises
+    #[serde(default)]
+    pub set_doppi: bool,
+    /// Skill a rank 5: ogni 10 aggiunge un dado al tiro
+    #[serde(default)]
+    pub esplodi: bool,
+    /// Somma che un set deve raggiungere per valere un raise
+    #[serde(default = "target_standard")]
+    pub target: u32,
+    /// Raises che costa ogni azione (Pressure, regole della casa)
+    #[serde(default)]
+    pub costo: Option<u32>,
+}
+
+fn target_standard() -> u32 {
+    TARGET_RAISE
+}
+
+impl RollOptions {
+    pub fn new(num_dadi: usize) -> Self {
+        RollOptions {
+            num_dadi,
+            rilancia_uno: false,
+            set_doppi: false,
+            esplodi: false,
+            target: TARGET_RAISE,
+            costo: None,
+        }
+    }
+
+    pub fn rilancia_uno(mut self, rilancia_uno: bool) -> Self {
+        self.rilancia_uno = rilancia_uno;
+        self
+    }
+
+    pub fn set_doppi(mut self, set_doppi: bool) -> Self {
+        self.set_doppi = set_doppi;
+        self
+    }
+
+    pub fn esplodi(mut self, esplodi: bool) -> Self {
+        self.esplodi = esplodi;
+        self
+    }
+
+    pub fn target(mut self, target: u32) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn costo(mut self, costo: Option<u32>) -> Self {
+        self.costo = costo;
+        self
+    }
+
+    /// Regole con cui raggruppare i dadi di questo tiro
+    pub fn regole(&self) -> Regole {
+        Regole {
+            target: self.target,
+            set_doppi: self.set_doppi,
+        }
+    }
+
+    /// Azioni che si possono pagare con `raises`, se il tiro ha un costo
+    pub fn azioni(&self, raises: usize) -> Option<usize> {
+        self.costo.map(|costo| raises / costo.max(1) as usize)
+    }
+
+    /// Controlla che numero di dadi, target e costo siano accettabili
+    pub fn validate(&self) -> Result<(), RollError> {
+        if self.num_dadi == 0 {
+            return Err(RollError::NessunDado);
+        }
+        if self.num_dadi > MAX_DADI {
+            return Err(RollError::TroppiDadi {
+                richiesti: self.num_dadi,
+                massimo: MAX_DADI,
+            });
+        }
+        if self.costo == Some(0) {
+            return Err(RollError::CostoNonValido);
+        }
+        self.regole().validate()
+    }
+}
+
+/// Esegue un tiro secondo le opzioni indicate: lancia i dadi, se richiesto
+/// rilancia il primo `1` e fa esplodere i 10, poi ordina in decrescente e
+/// calcola i raises
+pub fn roll(options: &RollOptions) -> Result<RollWithRerollResponse, RollError> {
+    roll_con(options, &mut SorgenteSicura::new())
+}
+
+/// Come [`roll`], prendendo i dadi da `sorgente`
+pub fn roll_con<S: SorgenteDadi + ?Sized>(
+    options: &RollOptions,
+    sorgente: &mut S,
+) -> Result<RollWithRerollResponse, RollError> {
+    options.validate()?;
+
+    let mut ris: Vec<u8> = (0..options.num_dadi).map(|_| sorgente.tira()).collect();
+    let originali = ris.clone();
+
+    let mut rilanciato = None;
+    if options.rilancia_uno {
+        if let Some(idx) = ris.iter().position(|&x| x == 1) {
+            let nuovo = sorgente.tira();
+            ris[idx] = nuovo;
+            rilanciato = Some(nuovo);
+        }
+    }
+
+    let esplosi = if options.esplodi {
+        esplodi(sorgente, &ris)
+    } else {
+        Vec::new()
+    };
+    ris.extend(&esplosi);
+
+    ris.sort_unstable_by(|a, b| b.cmp(a));
+    let r = raggruppa_con(&ris, &options.regole());
+
+    Ok(RollWithRerollResponse {
+        risultati_originali: originali,
+        rilanciato,
+        risultati_aggiornati: ris,
+        raises: r.raises,
+        combinazioni: r.gruppi,
+        avanzi: r.avanzi,
+        esplosi,
+        azioni: options.azioni(r.raises),
+    })
+}
+
+/// Per ogni 10 in `dadi` lancia un dado in più, e così via per i 10 usciti
+/// dalle esplosioni; restituisce i dadi aggiunti
+fn esplodi<S: SorgenteDadi + ?Sized>(sorgente: &mut S, dadi: &[u8]) -> Vec<u8> {
+    let mut da_esplodere = dadi.iter().filter(|&&d| d == 10).count();
+    let mut esplosi = Vec::new();
+    while da_esplodere > 0 {
+        let nuovo = sorgente.tira();
+        if nuovo != 10 {
+            da_esplodere -= 1;
+        }
+        esplosi.push(nuovo);
+    }
+    esplosi
+}
+
+/// Lancia `num_dadi` e ordina i risultati in decrescente
+pub fn roll_dice(num_dadi: usize) -> Result<DiceResponse, RollError> {
+    let r = roll(&RollOptions::new(num_dadi))?;
+    Ok(DiceResponse {
+        risultati: r.risultati_aggiornati,
+        raises: r.raises,
+        combinazioni: r.combinazioni,
+        avanzi: r.avanzi,
+        rilanciato: r.rilanciato,
+    })
+}
+
+/// Lancia `num_dadi`, e se richiesto rilancia un `1`
+pub fn roll_with_reroll(
+    num_dadi: usize,
+    rilancia_uno: bool,
+) -> Result<RollWithRerollResponse, RollError> {
+    roll(&RollOptions::new(num_dadi).rilancia_uno(rilancia_uno))
+}
+
+/// Rerolla un dado `1`, riordina e ricalcola i raises
+pub fn reroll_dice(ris: Vec<u8>, regole: &Regole) -> Result<DiceResponse, RollError> {
+    reroll_dice_con(ris, regole, &mut SorgenteSicura::new())
+}
+
+/// Come [`reroll_dice`], prendendo il nuovo dado da `sorgente`
+pub fn reroll_dice_con<S: SorgenteDadi + ?Sized>(
+    ris: Vec<u8>,
+    regole: &Regole,
+    sorgente: &mut S,
+) -> Result<DiceResponse, RollError> {
+    let idx = ris
+        .iter()
+        .position(|&x| x == 1)
+        .ok_or(RollError::NessunUnoDaRilanciare)?;
+    rilancia_dado_con(ris, idx, regole, sorgente)
+}
+
+/// Skill a rank 3: rilancia il dado in posizione `indice`, riordina e
+/// ricalcola i raises
+pub fn rilancia_dado(
+    ris: Vec<u8>,
+    indice: usize,
+    regole: &Regole,
+) -> Result<DiceResponse, RollError> {
+    rilancia_dado_con(ris, indice, regole, &mut SorgenteSicura::new())
+}
+
+/// Come [`rilancia_dado`], prendendo il nuovo dado da `sorgente`
+pub fn rilancia_dado_con<S: SorgenteDadi + ?Sized>(
+    mut ris: Vec<u8>,
+    indice: usize,
+    regole: &Regole,
+    sorgente: &mut S,
+) -> Result<DiceResponse, RollError> {
+    regole.validate()?;
+    let dadi = ris.len();
+    let dado = ris
+        .get_mut(indice)
+        .ok_or(RollError::IndiceNonValido { indice, dadi })?;
+    let nuovo = sorgente.tira();
+    *dado = nuovo;
+    ris.sort_unstable_by(|a, b| b.cmp(a));
+    let r = raggruppa_con(&ris, regole);
+
+    Ok(DiceResponse {
+        risultati: ris,
+        raises: r.raises,
+        combinazioni: r.gruppi,
+        avanzi: r.avanzi,
+        rilanciato: Some(nuovo),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raises::MAX_TARGET;
+    use crate::sorgente::{SorgenteConSeme, SorgenteFissa};
+
+    fn decrescente(dadi: &[u8]) -> bool {
+        dadi.windows(2).all(|w| w[0] >= w[1])
+    }
+
+    #[test]
+    fn numero_di_dadi_fuori_range() {
+        assert_eq!(roll_dice(0), Err(RollError::NessunDado));
+        assert_eq!(
+            roll_dice(MAX_DADI + 1),
+            Err(RollError::TroppiDadi {
+                richiesti: MAX_DADI + 1,
+                massimo: MAX_DADI
+            })
+        );
+        assert!(roll_with_reroll(0, true).is_err());
+        assert!(roll(&RollOptions::new(MAX_DADI + 1)).is_err());
+    }
+
+    #[test]
+    fn roll_dice_restituisce_dadi_ordinati_e_validi() {
+        for _ in 0..50 {
+            let dr = roll_dice(12).unwrap();
+            assert_eq!(dr.risultati.len(), 12);
+            assert!(dr.risultati.iter().all(|d| (1..=10).contains(d)));
+            assert!(decrescente(&dr.risultati));
+            let r = raggruppa_con(&dr.risultati, &Regole::default());
+            assert_eq!(dr.raises, r.raises);
+            assert_eq!(dr.combinazioni, r.gruppi);
+            assert_eq!(dr.avanzi, r.avanzi);
+        }
+    }
+
+    #[test]
+    fn roll_with_reroll_rilancia_solo_un_uno() {
+        for _ in 0..200 {
+            let r = roll_with_reroll(10, true).unwrap();
+            assert_eq!(r.risultati_originali.len(), 10);
+            assert!(decrescente(&r.risultati_aggiornati));
+
+            let mut prima = r.risultati_originali.clone();
+            prima.sort_unstable_by(|a, b| b.cmp(a));
+            match r.rilanciato {
+                None => {
+                    assert!(!r.risultati_originali.contains(&1));
+                    assert_eq!(prima, r.risultati_aggiornati);
+                }
+                Some(nuovo) => {
+                    let pos = prima.iter().rposition(|&d| d == 1).unwrap();
+                    prima.remove(pos);
+                    prima.push(nuovo);
+                    prima.sort_unstable_by(|a, b| b.cmp(a));
+                    assert_eq!(prima, r.risultati_aggiornati);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roll_with_reroll_senza_rilancio() {
+        let r = roll_with_reroll(8, false).unwrap();
+        assert_eq!(r.rilanciato, None);
+        let mut prima = r.risultati_originali.clone();
+        prima.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(prima, r.risultati_aggiornati);
+    }
+
+    #[test]
+    fn reroll_dice_richiede_un_uno() {
+        let regole = Regole::default();
+        assert_eq!(
+            reroll_dice(vec![10, 5, 2], &regole),
+            Err(RollError::NessunUnoDaRilanciare)
+        );
+        let dr = reroll_dice(vec![10, 5, 1], &regole).unwrap();
+        assert_eq!(dr.risultati.len(), 3);
+        assert!(dr.risultati.contains(&10) && dr.risultati.contains(&5));
+        assert!(dr.risultati.contains(&dr.rilanciato.unwrap()));
+        assert!(decrescente(&dr.risultati));
+    }
+
+    #[test]
+    fn rilancia_dado_sceglie_il_dado() {
+        let regole = Regole::default();
+        assert_eq!(
+            rilancia_dado(vec![10, 5, 2], 3, &regole),
+            Err(RollError::IndiceNonValido { indice: 3, dadi: 3 })
+        );
+        for _ in 0..50 {
+            let dr = rilancia_dado(vec![10, 5, 2], 0, &regole).unwrap();
+            let mut attesi = vec![5, 2, dr.rilanciato.unwrap()];
+            attesi.sort_unstable_by(|a, b| b.cmp(a));
+            assert_eq!(dr.risultati, attesi);
+            assert_eq!(dr.raises, raggruppa_con(&attesi, &regole).raises);
+        }
+    }
+
+    #[test]
+    fn set_doppi_nel_tiro() {
+        for _ in 0..50 {
+            let opts = RollOptions::new(12).set_doppi(true);
+            let r = roll(&opts).unwrap();
+            let atteso = raggruppa_con(&r.risultati_aggiornati, &opts.regole());
+            assert_eq!(r.raises, atteso.raises);
+            assert!(r.raises >= raggruppa_con(&r.risultati_aggiornati, &Regole::default()).raises);
+        }
+    }
+
+    #[test]
+    fn i_dieci_esplodono() {
+        for _ in 0..200 {
+            let r = roll(&RollOptions::new(10).esplodi(true)).unwrap();
+            let dieci = r.risultati_originali.iter().filter(|&&d| d == 10).count()
+                + r.esplosi.iter().filter(|&&d| d == 10).count();
+            assert_eq!(r.esplosi.len(), dieci);
+
+            let mut tutti = r.risultati_originali.clone();
+            tutti.extend(&r.esplosi);
+            tutti.sort_unstable_by(|a, b| b.cmp(a));
+            assert_eq!(tutti, r.risultati_aggiornati);
+            assert_eq!(r.raises, raggruppa_con(&tutti, &Regole::default()).raises);
+        }
+        let r = roll(&RollOptions::new(10)).unwrap();
+        assert!(r.esplosi.is_empty());
+    }
+
+    #[test]
+    fn target_e_costo() {
+        let opts = RollOptions::new(12).target(15).costo(Some(2));
+        for _ in 0..50 {
+            let r = roll(&opts).unwrap();
+            assert!(r
+                .combinazioni
+                .iter()
+                .all(|c| c.iter().map(|&d| d as u32).sum::<u32>() >= 15));
+            assert_eq!(
+                r.raises,
+                raggruppa_con(&r.risultati_aggiornati, &opts.regole()).raises
+            );
+            assert_eq!(r.azioni, Some(r.raises / 2));
+        }
+        assert_eq!(roll(&RollOptions::new(3)).unwrap().azioni, None);
+    }
+
+    #[test]
+    fn target_e_costo_fuori_range() {
+        assert_eq!(
+            roll(&RollOptions::new(3).target(0)),
+            Err(RollError::TargetNonValido {
+                target: 0,
+                massimo: MAX_TARGET
+            })
+        );
+        assert!(roll(&RollOptions::new(3).target(MAX_TARGET + 1)).is_err());
+        assert!(roll(&RollOptions::new(3).target(MAX_TARGET)).is_ok());
+        assert_eq!(
+            roll(&RollOptions::new(3).costo(Some(0))),
+            Err(RollError::CostoNonValido)
+        );
+        let regole = Regole {
+            target: 0,
+            set_doppi: false,
+        };
+        assert!(rilancia_dado(vec![1, 2], 0, &regole).is_err());
+    }
+
+    #[test]
+    fn tiro_riproducibile() {
+        // 1 rilanciato in 10, che esplode in un 10 e poi in un 4
+        let mut sorgente = SorgenteFissa::new([7, 1, 3, 10, 10, 4]);
+        let opts = RollOptions::new(3).rilancia_uno(true).esplodi(true);
+        let r = roll_con(&opts, &mut sorgente).unwrap();
+        assert_eq!(r.risultati_originali, vec![7, 1, 3]);
+        assert_eq!(r.rilanciato, Some(10));
+        assert_eq!(r.esplosi, vec![10, 4]);
+        assert_eq!(r.risultati_aggiornati, vec![10, 10, 7, 4, 3]);
+        assert_eq!(r.raises, 3);
+        assert_eq!(sorgente.rimasti(), 0);
+
+        let a = roll_con(&RollOptions::new(20), &mut SorgenteConSeme::new(7)).unwrap();
+        let b = roll_con(&RollOptions::new(20), &mut SorgenteConSeme::new(7)).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn rilancio_riproducibile() {
+        let regole = Regole::default();
+        let dr =
+            rilancia_dado_con(vec![9, 6, 2], 2, &regole, &mut SorgenteFissa::new([4])).unwrap();
+        assert_eq!(dr.risultati, vec![9, 6, 4]);
+        assert_eq!(dr.rilanciato, Some(4));
+        assert_eq!(dr.raises, 1);
+
+        let dr = reroll_dice_con(vec![9, 1], &regole, &mut SorgenteFissa::new([1])).unwrap();
+        assert_eq!(dr.risultati, vec![9, 1]);
+        assert_eq!(dr.raises, 1);
+    }
+
+    #[test]
+    fn roll_options_da_json() {
+        let opts: RollOptions = serde_json::from_str(r#"{"num_dadi":4}"#).unwrap();
+        assert_eq!(opts, RollOptions::new(4));
+        let r = roll(&opts.rilancia_uno(true)).unwrap();
+        assert_eq!(r.risultati_aggiornati.len(), 4);
+    }
+}
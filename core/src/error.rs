@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// Errori restituiti dalle funzioni di tiro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RollError {
+    /// È stato richiesto un tiro con zero dadi.
+    NessunDado,
+    /// Il numero di dadi richiesto supera il massimo consentito.
+    TroppiDadi { richiesti: usize, massimo: usize },
+    /// È stato chiesto di rilanciare un `1`, ma nel tiro non ce ne sono.
+    NessunUnoDaRilanciare,
+    /// È stato chiesto di rilanciare un dado che non c'è.
+    IndiceNonValido { indice: usize, dadi: usize },
+    /// Il target di un raise è zero o troppo alto.
+    TargetNonValido { target: u32, massimo: u32 },
+    /// Il costo di un'azione è di zero raises.
+    CostoNonValido,
+    /// È stato chiesto un rilancio a chi non ha ancora tirato.
+    NessunTiro,
+    /// Il tiro ha già usato il suo rilancio.
+    GiaRilanciato,
+    /// Il nonce di un tiro nascosto è troppo corto per tenerlo segreto.
+    NonceCorto { minimo: usize },
+    /// Un dado ha una faccia che non va da 1 a 10.
+    FacciaNonValida { faccia: u8 },
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollError::NessunDado => write!(f, "bisogna tirare almeno un dado"),
+            RollError::TroppiDadi { richiesti, massimo } => write!(
+                f,
+                "troppi dadi: richiesti {}, massimo {}",
+                richiesti, massimo
+            ),
+            RollError::NessunUnoDaRilanciare => write!(f, "nessun dado da 1 da rilanciare"),
+            RollError::IndiceNonValido { indice, dadi } => write!(
+                f,
+                "nessun dado in posizione {} (il tiro ha {} dadi)",
+                indice, dadi
+            ),
+            RollError::TargetNonValido { target, massimo } => write!(
+                f,
+                "target {} non valido: deve essere tra 1 e {}",
+                target, massimo
+            ),
+            RollError::CostoNonValido => write!(f, "un'azione deve costare almeno un raise"),
+            RollError::NessunTiro => write!(f, "non c'è un tiro da rilanciare"),
+            RollError::GiaRilanciato => write!(f, "questo tiro ha già usato il suo rilancio"),
+            RollError::NonceCorto { minimo } => write!(
+                f,
+                "i tiri nascosti vogliono un nonce di almeno {} caratteri",
+                minimo
+            ),
+            RollError::FacciaNonValida { faccia } => {
+                write!(f, "faccia {} non valida: un dado va da 1 a 10", faccia)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RollError {}
+
+/// Motivi per cui i tiri di una sessione non tornano col seme rivelato.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErroreVerifica {
+    /// Il seme non è una stringa esadecimale di 32 byte.
+    SemeNonValido,
+    /// Manca il tiro con questo contatore: il server potrebbe aver scartato
+    /// dei tiri.
+    TiroMancante { contatore: u64 },
+    /// Il tiro con questo contatore non è quello che esce dal seme.
+    TiroDiverso { contatore: u64 },
+}
+
+impl fmt::Display for ErroreVerifica {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErroreVerifica::SemeNonValido => write!(f, "il seme rivelato non è valido"),
+            ErroreVerifica::TiroMancante { contatore } => {
+                write!(f, "manca il tiro numero {} della sessione", contatore)
+            }
+            ErroreVerifica::TiroDiverso { contatore } => write!(
+                f,
+                "il tiro numero {} non corrisponde al seme rivelato",
+                contatore
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ErroreVerifica {}
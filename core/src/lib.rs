@@ -0,0 +1,32 @@
+//! Motore dei dadi di 7th Sea, indipendente dalla piattaforma.
+//!
+//! Il crate contiene tutta la logica dei tiri (lancio, rilancio, calcolo dei
+//! raises) ed è condiviso dal frontend Yew, dal backend e da eventuali tool
+//! a riga di comando. I messaggi scambiati sul WebSocket sono nel crate
+//! `seven_seas_protocol`.
+
+pub mod consigli;
+pub mod dice;
+pub mod error;
+pub mod raises;
+mod simplex;
+pub mod sorgente;
+pub mod tavolo;
+pub mod tiri;
+pub mod verifica;
+
+pub use consigli::{consiglia_rilancio, Consiglio, MAX_DADI_CONSIGLIO};
+pub use dice::{
+    reroll_dice, reroll_dice_con, rilancia_dado, rilancia_dado_con, roll, roll_con, roll_dice,
+    roll_with_reroll, DiceResponse, RollOptions, RollWithRerollResponse, MAX_DADI,
+};
+pub use error::{ErroreVerifica, RollError};
+pub use raises::{
+    massimizza_raises, raggruppa, raggruppa_con, Raggruppamento, Regole, MAX_TARGET, TARGET_RAISE,
+};
+pub use sorgente::{SorgenteConSeme, SorgenteDadi, SorgenteFissa, SorgenteSicura};
+pub use tavolo::{Tavolo, TiroPrenotato};
+pub use tiri::{RerollRequest, RollRequest, RollResult, Visibilita};
+pub use verifica::{
+    nonce_casuale, verifica_sessione, Prova, SemeSegreto, Verifica, MIN_NONCE_NASCOSTO,
+};
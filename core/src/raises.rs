@@ -0,0 +1,839 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RollError;
+use crate::simplex;
+
+/// Somma che un set di dadi deve raggiungere per valere un raise
+pub const TARGET_RAISE: u32 = 10;
+
+/// Soglia massima di un set accettata da [`Regole::validate`]: il target o,
+/// coi set doppi, la soglia doppia (vedi [`Regole::max_target`]). Il costo
+/// dei raises cresce con la soglia più alta, non col numero di dadi: fino
+/// a 30 un tiro si risolve in qualche decina di millisecondi, a 45 servono
+/// secondi
+pub const MAX_TARGET: u32 = 30;
+
+/// Faccia più alta di un dado
+pub const FACCE: usize = 10;
+
+/// Quanti dadi sono usciti per ogni faccia (l'indice 0 non è usato)
+type Istogramma = [u32; FACCE + 1];
+
+/// Regole con cui i dadi formano i set
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Regole {
+    /// Somma che un set deve raggiungere per valere un raise
+    pub target: u32,
+    /// Skill a rank 4: un set che arriva a 15 vale due raises
+    #[serde(default)]
+    pub set_doppi: bool,
+}
+
+impl Default for Regole {
+    fn default() -> Self {
+        Regole {
+            target: TARGET_RAISE,
+            set_doppi: false,
+        }
+    }
+}
+
+impl Regole {
+    /// Controlla che il target sia accettabile
+    pub fn validate(&self) -> Result<(), RollError> {
+        let massimo = self.max_target();
+        if !(1..=massimo).contains(&self.target) {
+            return Err(RollError::TargetNonValido {
+                target: self.target,
+                massimo,
+            });
+        }
+        Ok(())
+    }
+
+    /// Il target più alto ammesso: [`MAX_TARGET`], o coi set doppi quello
+    /// la cui soglia doppia non lo supera (20)
+    pub fn max_target(&self) -> u32 {
+        if self.set_doppi {
+            (1..=MAX_TARGET)
+                .rev()
+                .find(|&target| target + target / 2 <= MAX_TARGET)
+                .unwrap_or(1)
+        } else {
+            MAX_TARGET
+        }
+    }
+
+    /// Somma di un set che vale due raises (15 col target standard)
+    pub fn soglia_doppia(&self) -> u32 {
+        self.target + self.target / 2
+    }
+
+    /// Raises che vale un set con somma `somma`
+    pub fn valore(&self, somma: u32) -> u32 {
+        if self.set_doppi && somma >= self.soglia_doppia() {
+            2
+        } else if somma >= self.target {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Le soglie dei set ammessi, dalla più bassa
+    fn soglie(&self) -> Vec<u32> {
+        if self.set_doppi {
+            vec![self.target, self.soglia_doppia()]
+        } else {
+            vec![self.target]
+        }
+    }
+}
+
+/// Suddivisione di un tiro in set disgiunti, ognuno dei quali raggiunge il
+/// target, più i dadi rimasti fuori.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Raggruppamento {
+    pub gruppi: Vec<Vec<u8>>,
+    pub avanzi: Vec<u8>,
+    /// Raises totali: ogni set vale uno, o due se è un set doppio
+    pub raises: usize,
+}
+
+/// Calcolo dei raises
+pub fn massimizza_raises(dadi: &[u8]) -> (usize, Vec<Vec<u8>>) {
+    let r = raggruppa(dadi, TARGET_RAISE);
+    (r.raises, r.gruppi)
+}
+
+/// Divide `dadi` (facce da 1 a 10) nel massimo numero possibile di set
+/// disgiunti con somma almeno `target`.
+///
+/// # Panics
+///
+/// Se un dado vale 0 o più di 10.
+pub fn raggruppa(dadi: &[u8], target: u32) -> Raggruppamento {
+    raggruppa_con(
+        dadi,
+        &Regole {
+            target,
+            set_doppi: false,
+        },
+    )
+}
+
+/// Divide `dadi` (facce da 1 a 10) in set disgiunti che valgono in totale
+/// il massimo numero di raises secondo `regole`.
+///
+/// Il calcolo lavora sull'istogramma delle facce: prima forma i set che
+/// compaiono sicuramente in una soluzione ottima (vedi [`riduci`]), poi
+/// risolve il resto con un branch-and-bound sui conteggi: si cerca il numero
+/// di raises indicato dal rilassamento lineare e ci si ferma appena lo si
+/// raggiunge. Gli stati possibili sono al più il prodotto dei conteggi per
+/// faccia, quindi il costo cresce in modo polinomiale col numero di dadi.
+///
+/// # Panics
+///
+/// Se un dado vale 0 o più di 10.
+pub fn raggruppa_con(dadi: &[u8], regole: &Regole) -> Raggruppamento {
+    let regole = Regole {
+        target: regole.target.max(1),
+        ..*regole
+    };
+    let mut h: Istogramma = [0; FACCE + 1];
+    for &d in dadi {
+        assert!((1..=FACCE as u8).contains(&d), "faccia non valida: {}", d);
+        h[d as usize] += 1;
+    }
+
+    let mut gruppi = riduci(&mut h, &regole);
+    if h.iter().any(|&n| n > 0) {
+        gruppi.extend(Risolutore::new(regole).risolvi(&mut h));
+    }
+    gruppi.sort_unstable_by(|a, b| b.cmp(a));
+
+    let raises = gruppi
+        .iter()
+        .map(|set| regole.valore(set.iter().map(|&d| d as u32).sum()) as usize)
+        .sum();
+    let avanzi = (1..=FACCE)
+        .rev()
+        .flat_map(|f| std::iter::repeat_n(f as u8, h[f] as usize))
+        .collect();
+    Raggruppamento {
+        gruppi,
+        avanzi,
+        raises,
+    }
+}
+
+/// Toglie dall'istogramma i set che possono sempre far parte di una
+/// soluzione ottima. Con un solo tipo di set:
+///
+/// - un dado che da solo raggiunge il target;
+/// - due dadi che sommano esattamente il target: se in una soluzione ottima
+///   stanno in set diversi, i due resti uniti valgono ancora un set;
+/// - il dado più alto col più basso, se insieme bastano: qualunque altro
+///   compagno del dado più alto vale almeno quanto il più basso.
+///
+/// Con i set doppi vale solo la regola delle coppie, applicata alla soglia
+/// doppia: la coppia vale già il massimo e i resti uniti valgono almeno
+/// quanto i due set da cui provengono, meno due.
+fn riduci(h: &mut Istogramma, regole: &Regole) -> Vec<Vec<u8>> {
+    let mut gruppi = Vec::new();
+    let mut aggiungi = |h: &mut Istogramma, set: &[usize], volte: u32| {
+        for _ in 0..volte {
+            gruppi.push(set.iter().map(|&f| f as u8).collect());
+        }
+        for &f in set {
+            h[f] -= volte;
+        }
+    };
+
+    let target = if regole.set_doppi {
+        regole.soglia_doppia()
+    } else {
+        for f in (regole.target as usize).min(FACCE + 1)..=FACCE {
+            let volte = h[f];
+            aggiungi(h, &[f], volte);
+        }
+        regole.target
+    };
+
+    for a in 1..=FACCE {
+        let Some(b) = (target as usize).checked_sub(a) else {
+            break;
+        };
+        if b < a || b > FACCE {
+            continue;
+        }
+        let volte = if a == b { h[a] / 2 } else { h[a].min(h[b]) };
+        aggiungi(h, &[b, a], volte);
+    }
+
+    if !regole.set_doppi {
+        while let (Some(alto), Some(basso)) = (faccia_max(h), (1..=FACCE).find(|&f| h[f] > 0)) {
+            if (alto + basso) < target as usize || (alto == basso && h[alto] < 2) {
+                break;
+            }
+            aggiungi(h, &[alto, basso], 1);
+        }
+    }
+
+    gruppi
+}
+
+fn faccia_max(h: &Istogramma) -> Option<usize> {
+    (1..=FACCE).rev().find(|&f| h[f] > 0)
+}
+
+/// Dadi oltre i quali conviene usare il rilassamento lineare: sugli stati
+/// piccoli costa più di quanto fa risparmiare
+const STATO_GRANDE: u32 = 32;
+
+fn grande(h: &Istogramma) -> bool {
+    h.iter().sum::<u32>() > STATO_GRANDE
+}
+
+/// Ricerca esatta sull'istogramma dei dadi non ancora usati
+struct Risolutore {
+    regole: Regole,
+    /// Tutti i set minimi possibili (togliendo un dado si scende sotto la
+    /// soglia), come istogrammi, con i raises che valgono.
+    configurazioni: OnceCell<Vec<(Istogramma, u32)>>,
+    /// Pesi per il limite rapido: peso di ogni faccia e, per ogni soglia,
+    /// il peso minimo di un set che la raggiunge coi raises che vale.
+    pesi: Vec<(Istogramma, Vec<(u32, u32)>)>,
+    /// Limite superiore noto ai raises ottenibili da uno stato.
+    limiti: HashMap<Istogramma, u32>,
+}
+
+impl Risolutore {
+    fn new(regole: Regole) -> Self {
+        let soglie = regole.soglie();
+        let scala = *soglie
+            .iter()
+            .max_by(|&&a, &&b| (regole.valore(a) * b).cmp(&(regole.valore(b) * a)))
+            .unwrap();
+        let pesi = (1..=scala)
+            .map(|k| {
+                let mut peso = [0; FACCE + 1];
+                for (f, p) in peso.iter_mut().enumerate() {
+                    *p = (k * f as u32).div_ceil(scala);
+                }
+                let minimi = soglie
+                    .iter()
+                    .map(|&soglia| (peso_minimo(&peso, soglia), regole.valore(soglia)))
+                    .collect();
+                (peso, minimi)
+            })
+            .collect();
+        Risolutore {
+            regole,
+            configurazioni: OnceCell::new(),
+            pesi,
+            limiti: HashMap::new(),
+        }
+    }
+
+    /// Tutti i set minimi possibili, calcolati alla prima richiesta
+    fn configurazioni(&self) -> &[(Istogramma, u32)] {
+        self.configurazioni.get_or_init(|| {
+            let mut configurazioni = Vec::new();
+            for soglia in self.regole.soglie() {
+                let mut minimi = Vec::new();
+                completa(
+                    &[u32::MAX; FACCE + 1],
+                    FACCE,
+                    soglia as i32,
+                    &mut Vec::new(),
+                    &mut minimi,
+                );
+                for set in minimi {
+                    let mut conf = [0; FACCE + 1];
+                    for &d in &set {
+                        conf[d as usize] += 1;
+                    }
+                    let valore = self.regole.valore(set.iter().map(|&d| d as u32).sum());
+                    configurazioni.push((conf, valore));
+                }
+            }
+            configurazioni.sort_unstable();
+            configurazioni.dedup();
+            configurazioni
+        })
+    }
+
+    /// Trova un raggruppamento ottimo di `h` e toglie da `h` i dadi usati.
+    ///
+    /// Sui tiri grandi la ricerca esatta può perdersi tra moltissimi stati
+    /// quasi equivalenti, quindi prima si staccano i set che il
+    /// rilassamento lineare usa molte volte, lasciandone `margine` per
+    /// tipo, e si risolve esattamente solo il resto. Se il totale raggiunge
+    /// il limite superiore dell'intero tiro la soluzione è ottima;
+    /// altrimenti si riprova con un margine doppio, fino a cercare su tutti
+    /// i dadi.
+    fn risolvi(&mut self, h: &mut Istogramma) -> Vec<Vec<u8>> {
+        let limite = self.limite(h);
+        let usi: Vec<(Istogramma, u32, u32)> = if grande(h) {
+            let usi = self.rilassamento(h).1;
+            usi.into_iter()
+                .map(|(conf, valore, volte)| (*conf, valore, volte.floor() as u32))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut margine = 1;
+        loop {
+            let mut resto = *h;
+            let mut gruppi = Vec::new();
+            let mut staccati = 0;
+            for &(conf, valore, volte) in &usi {
+                let disponibili = (1..=FACCE)
+                    .filter(|&f| conf[f] > 0)
+                    .map(|f| resto[f] / conf[f])
+                    .min()
+                    .unwrap_or(0);
+                let volte = volte.saturating_sub(margine).min(disponibili);
+                let mut set = Vec::new();
+                for f in (1..=FACCE).rev() {
+                    resto[f] -= conf[f] * volte;
+                    set.extend(std::iter::repeat_n(f as u8, conf[f] as usize));
+                }
+                gruppi.extend(std::iter::repeat_n(set, volte as usize));
+                staccati += valore * volte;
+            }
+            let (raises, trovati) = self.ottimo(&resto);
+            if gruppi.is_empty() || staccati + raises == limite {
+                gruppi.extend(trovati);
+                for set in &gruppi {
+                    for &d in set {
+                        h[d as usize] -= 1;
+                    }
+                }
+                return gruppi;
+            }
+            margine *= 2;
+        }
+    }
+
+    /// Raggruppamento ottimo di `h` con i raises che vale: si cercano prima
+    /// i raises indicati dal limite superiore, scendendo finché non ci si
+    /// riesce.
+    fn ottimo(&mut self, h: &Istogramma) -> (u32, Vec<Vec<u8>>) {
+        let mut serve = self.limite(h);
+        loop {
+            let mut gruppi = Vec::new();
+            if self.cerca(h, serve, &mut gruppi) {
+                return (serve, gruppi);
+            }
+            serve -= 1;
+        }
+    }
+
+    /// Dice se da `h` si possono ottenere almeno `serve` raises; in caso
+    /// affermativo mette in `gruppi` i set trovati.
+    fn cerca(&mut self, h: &Istogramma, serve: u32, gruppi: &mut Vec<Vec<u8>>) -> bool {
+        if serve == 0 {
+            return true;
+        }
+        let noto = match self.limiti.get(h) {
+            Some(&limite) => limite,
+            None => {
+                let limite = self.limite_rapido(h);
+                self.limiti.insert(*h, limite);
+                limite
+            }
+        };
+        if noto < serve {
+            return false;
+        }
+
+        let alto = faccia_max(h).unwrap();
+        let mut senza = *h;
+        senza[alto] -= 1;
+
+        // il dado più alto forma un set con un completamento minimo...
+        let soglie = self.regole.soglie();
+        let mut completamenti = Vec::new();
+        for &soglia in &soglie {
+            completa(
+                &senza,
+                alto,
+                soglia as i32 - alto as i32,
+                &mut Vec::new(),
+                &mut completamenti,
+            );
+        }
+        completamenti.sort_unstable();
+        completamenti.dedup();
+        let mut opzioni: Vec<(Vec<u8>, u32, u32)> = completamenti
+            .into_iter()
+            .map(|comp| {
+                let somma = alto as u32 + comp.iter().map(|&d| d as u32).sum::<u32>();
+                let valore = self.regole.valore(somma);
+                let spreco = somma - soglie[valore as usize - 1];
+                (comp, valore, spreco)
+            })
+            .collect();
+        opzioni.sort_by_key(|(comp, valore, spreco)| (*spreco, u32::MAX - valore, comp.len()));
+
+        let mut opzioni = opzioni.into_iter().map(Some).chain([None]);
+        let mut primo_tentativo = true;
+        let trovato = loop {
+            let Some(opzione) = opzioni.next() else {
+                break false;
+            };
+            // il limite del programma lineare costa di più: lo si calcola
+            // solo sugli stati grandi, e solo se la prima scelta, la più
+            // promettente, non basta
+            if !primo_tentativo && grande(h) && noto == self.limite_rapido(h) {
+                let lineare = self.limite_lineare(h);
+                if lineare < noto {
+                    self.limiti.insert(*h, lineare);
+                    if lineare < serve {
+                        return false;
+                    }
+                }
+            }
+            primo_tentativo = false;
+
+            match opzione {
+                // ...oppure resta fuori da ogni set
+                None => break self.cerca(&senza, serve, gruppi),
+                Some((comp, valore, _)) => {
+                    let mut resto = senza;
+                    for &d in &comp {
+                        resto[d as usize] -= 1;
+                    }
+                    if self.cerca(&resto, serve.saturating_sub(valore), gruppi) {
+                        let mut set = vec![alto as u8];
+                        set.extend(comp);
+                        gruppi.push(set);
+                        break true;
+                    }
+                }
+            }
+        };
+
+        if !trovato {
+            self.limiti.insert(*h, serve - 1);
+        }
+        trovato
+    }
+
+    /// Il migliore dei limiti superiori; quello lineare solo sugli stati
+    /// grandi
+    fn limite(&self, h: &Istogramma) -> u32 {
+        let rapido = self.limite_rapido(h);
+        if grande(h) {
+            rapido.min(self.limite_lineare(h))
+        } else {
+            rapido
+        }
+    }
+
+    /// Limite superiore rapido ai raises. Per ogni `k` un dado di faccia
+    /// `f` pesa `ceil(k * f / s)`, con `s` la soglia del set più conveniente:
+    /// un set pesa almeno quanto il più leggero dei set minimi che valgono
+    /// come lui, quindi i raises non superano il massimo di uno zaino con
+    /// la somma dei pesi come capienza. Con `k = 1` conta i dadi, con
+    /// `k = s` usa la somma dei dadi.
+    fn limite_rapido(&self, h: &Istogramma) -> u32 {
+        self.pesi
+            .iter()
+            .map(|(peso, minimi)| {
+                let totale = (1..=FACCE).map(|f| h[f] * peso[f]).sum();
+                zaino(minimi, totale)
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Limite superiore dato dal rilassamento lineare: quanti raises valgono
+    /// i set minimi, anche presi in quantità frazionarie, che si possono
+    /// formare con i dadi di `h`.
+    fn limite_lineare(&self, h: &Istogramma) -> u32 {
+        (self.rilassamento(h).0 + 1e-6).floor() as u32
+    }
+
+    /// Ottimo del rilassamento lineare di `h`: il valore e quante volte
+    /// (anche in modo frazionario) si usa ogni set minimo.
+    fn rilassamento(&self, h: &Istogramma) -> (f64, Vec<(&Istogramma, u32, f64)>) {
+        let facce: Vec<usize> = (1..=FACCE).filter(|&f| h[f] > 0).collect();
+        let colonne: Vec<&(Istogramma, u32)> = self
+            .configurazioni()
+            .iter()
+            .filter(|(conf, _)| (1..=FACCE).all(|f| conf[f] == 0 || h[f] > 0))
+            .collect();
+        if colonne.is_empty() {
+            return (0.0, Vec::new());
+        }
+        let vincoli: Vec<Vec<f64>> = facce
+            .iter()
+            .map(|&f| colonne.iter().map(|(conf, _)| conf[f] as f64).collect())
+            .collect();
+        let limiti: Vec<f64> = facce.iter().map(|&f| h[f] as f64).collect();
+        let obiettivo: Vec<f64> = colonne.iter().map(|(_, valore)| *valore as f64).collect();
+        let (valore, x) = simplex::massimo(&vincoli, &limiti, &obiettivo);
+        let usi = colonne
+            .into_iter()
+            .zip(x)
+            .map(|((conf, valore), volte)| (conf, *valore, volte))
+            .collect();
+        (valore, usi)
+    }
+}
+
+/// Peso minimo di un set che arriva almeno a `soglia`, se la faccia `f`
+/// pesa `peso[f]`
+fn peso_minimo(peso: &Istogramma, soglia: u32) -> u32 {
+    // minimo[s]: peso minimo per arrivare almeno a s
+    let mut minimo = vec![0; soglia as usize + 1];
+    for s in 1..minimo.len() {
+        minimo[s] = (1..=FACCE)
+            .map(|f| peso[f] + minimo[s.saturating_sub(f)])
+            .min()
+            .unwrap();
+    }
+    minimo[soglia as usize]
+}
+
+/// Massimo dei raises ottenibili con oggetti `(peso, raises)` e capienza
+/// `capienza`, con al più due tipi di oggetto: conviene riempire col tipo
+/// che rende di più per unità di peso, e dell'altro ne bastano meno del
+/// peso del primo (altrimenti si scambiano con pari peso e resa maggiore).
+fn zaino(oggetti: &[(u32, u32)], capienza: u32) -> u32 {
+    let Some(&(peso, valore)) = oggetti
+        .iter()
+        .max_by(|a, b| (a.1 * b.0).cmp(&(b.1 * a.0)).then(b.0.cmp(&a.0)))
+    else {
+        return 0;
+    };
+    match oggetti.iter().find(|&&o| o != (peso, valore)) {
+        None => capienza / peso * valore,
+        Some(&(peso_altro, valore_altro)) => (0..peso)
+            .take_while(|n| n * peso_altro <= capienza)
+            .map(|n| n * valore_altro + (capienza - n * peso_altro) / peso * valore)
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+/// Elenca i completamenti minimi (in ordine non crescente, facce al più
+/// `max`) che aggiungono almeno `bisogno` alla somma di un set.
+fn completa(
+    h: &Istogramma,
+    max: usize,
+    bisogno: i32,
+    corrente: &mut Vec<u8>,
+    out: &mut Vec<Vec<u8>>,
+) {
+    if bisogno <= 0 {
+        out.push(corrente.clone());
+        return;
+    }
+    for f in (1..=max).rev() {
+        let usati = corrente.iter().filter(|&&d| d as usize == f).count() as u32;
+        if h[f] > usati {
+            corrente.push(f as u8);
+            completa(h, f, bisogno - f as i32, corrente, out);
+            corrente.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::sorgente::{SorgenteConSeme, SorgenteDadi};
+
+    fn somma(combo: &[u8]) -> u32 {
+        combo.iter().map(|&d| d as u32).sum()
+    }
+
+    #[test]
+    fn nessun_dado_nessun_raise() {
+        assert_eq!(massimizza_raises(&[]), (0, vec![]));
+    }
+
+    #[test]
+    fn somma_insufficiente_nessun_raise() {
+        let (raises, combo) = massimizza_raises(&[4, 3, 1, 1]);
+        assert_eq!(raises, 0);
+        assert!(combo.is_empty());
+    }
+
+    #[test]
+    fn un_dieci_vale_un_raise() {
+        assert_eq!(massimizza_raises(&[10]), (1, vec![vec![10]]));
+        assert_eq!(massimizza_raises(&[10, 10, 10]).0, 3);
+    }
+
+    #[test]
+    fn coppie_che_fanno_dieci() {
+        let (raises, combo) = massimizza_raises(&[5, 5, 7, 3]);
+        assert_eq!(raises, 2);
+        assert!(combo.iter().all(|c| somma(c) >= 10));
+    }
+
+    #[test]
+    fn le_combinazioni_usano_solo_dadi_del_tiro() {
+        let dadi = [9, 8, 6, 4, 3, 2, 1];
+        let (raises, combo) = massimizza_raises(&dadi);
+        assert_eq!(raises, combo.len());
+
+        let mut restanti = dadi.to_vec();
+        for c in &combo {
+            assert!(somma(c) >= 10);
+            for d in c {
+                let pos = restanti
+                    .iter()
+                    .position(|x| x == d)
+                    .expect("dado non nel tiro");
+                restanti.remove(pos);
+            }
+        }
+    }
+
+    #[test]
+    fn non_spreca_dadi_in_set_troppo_lunghi() {
+        // l'algoritmo greedy usava 4+3+2+1 per un solo raise
+        let r = raggruppa(&[9, 9, 9, 4, 3, 2, 1], TARGET_RAISE);
+        assert_eq!(r.raises, 3);
+        assert_eq!(r.avanzi, vec![4]);
+    }
+
+    #[test]
+    fn raggruppa_restituisce_gli_avanzi() {
+        let r = raggruppa(&[2, 10, 1, 8], TARGET_RAISE);
+        assert_eq!(r.gruppi, vec![vec![10], vec![8, 2]]);
+        assert_eq!(r.avanzi, vec![1]);
+    }
+
+    #[test]
+    fn tiri_enormi() {
+        assert_eq!(raggruppa(&[3; 3000], TARGET_RAISE).raises, 750);
+
+        let dadi: Vec<u8> = (1..=4).flat_map(|f| vec![f; 250]).collect();
+        let r = raggruppa(&dadi, TARGET_RAISE);
+        assert_eq!(r.raises, 250);
+        assert!(r.avanzi.is_empty());
+
+        let dadi: Vec<u8> = (0..10_000).map(|i| (i * 7 % 10 + 1) as u8).collect();
+        let r = raggruppa(&dadi, TARGET_RAISE);
+        verifica(&dadi, &Regole::default(), &r);
+        assert_eq!(r.raises, 5500);
+    }
+
+    #[test]
+    fn set_da_quindici_valgono_doppio() {
+        let regole = Regole {
+            target: TARGET_RAISE,
+            set_doppi: true,
+        };
+        // 10+5 e 9+6 valgono due raises l'uno, meglio di 10, 9+6 e 5
+        let r = raggruppa_con(&[10, 9, 6, 5], &regole);
+        assert_eq!(r.raises, 4);
+        assert_eq!(r.gruppi, vec![vec![10, 5], vec![9, 6]]);
+
+        // senza la regola lo stesso tiro vale meno
+        assert_eq!(raggruppa(&[10, 9, 6, 5], TARGET_RAISE).raises, 2);
+
+        // un set da 10 resta utile quando non si arriva a 15
+        let r = raggruppa_con(&[8, 7, 6, 4], &regole);
+        assert_eq!(r.raises, 3);
+    }
+
+    #[test]
+    fn target_massimo_coi_set_doppi() {
+        let regole = |target, set_doppi| Regole { target, set_doppi };
+        assert_eq!(regole(10, true).max_target(), 20);
+        assert_eq!(regole(10, false).max_target(), MAX_TARGET);
+        assert_eq!(regole(20, true).soglia_doppia(), MAX_TARGET);
+        assert_eq!(regole(20, true).validate(), Ok(()));
+        assert_eq!(
+            regole(21, true).validate(),
+            Err(RollError::TargetNonValido {
+                target: 21,
+                massimo: 20
+            })
+        );
+        assert_eq!(regole(MAX_TARGET, false).validate(), Ok(()));
+    }
+
+    #[test]
+    fn tiri_grandi_al_target_massimo() {
+        // con la soglia più alta ammessa un tiro al tavolo resta rapido
+        // (in release qualche decina di millisecondi, qui il margine è per
+        // le build di debug)
+        for set_doppi in [true, false] {
+            let regole = Regole {
+                target: MAX_TARGET,
+                set_doppi,
+            };
+            let regole = Regole {
+                target: regole.max_target(),
+                ..regole
+            };
+            let mut sorgente = SorgenteConSeme::new(7);
+            let dadi: Vec<u8> = (0..120).map(|_| sorgente.tira()).collect();
+            let inizio = Instant::now();
+            let r = raggruppa_con(&dadi, &regole);
+            let durata = inizio.elapsed();
+            verifica(&dadi, &regole, &r);
+            assert!(
+                durata < Duration::from_secs(5),
+                "{:?} con {:?}",
+                durata,
+                regole
+            );
+        }
+    }
+
+    #[test]
+    fn set_doppi_su_tiri_enormi() {
+        let regole = Regole {
+            target: TARGET_RAISE,
+            set_doppi: true,
+        };
+        let dadi: Vec<u8> = (0..10_000).map(|i| (i * 7 % 10 + 1) as u8).collect();
+        let r = raggruppa_con(&dadi, &regole);
+        verifica(&dadi, &regole, &r);
+        // 55000 / 15 = 3666 set doppi, più un set da 10 col resto
+        assert_eq!(r.raises, 2 * 3666 + 1);
+    }
+
+    /// Assegna ogni dado a un set già aperto, a un set nuovo oppure lo
+    /// scarta, provando tutte le possibilità.
+    fn oracolo(dadi: &[u8], somme: &mut Vec<u32>, regole: &Regole) -> usize {
+        let Some((&d, resto)) = dadi.split_first() else {
+            return somme.iter().map(|&s| regole.valore(s) as usize).sum();
+        };
+        let mut migliore = oracolo(resto, somme, regole);
+        for i in 0..somme.len() {
+            somme[i] += d as u32;
+            migliore = migliore.max(oracolo(resto, somme, regole));
+            somme[i] -= d as u32;
+        }
+        somme.push(d as u32);
+        migliore = migliore.max(oracolo(resto, somme, regole));
+        somme.pop();
+        migliore
+    }
+
+    fn tutti_i_tiri(max_dadi: usize) -> Vec<Vec<u8>> {
+        fn estendi(tiro: &mut Vec<u8>, min: u8, max_dadi: usize, tutti: &mut Vec<Vec<u8>>) {
+            tutti.push(tiro.clone());
+            if tiro.len() == max_dadi {
+                return;
+            }
+            for d in min..=10 {
+                tiro.push(d);
+                estendi(tiro, d, max_dadi, tutti);
+                tiro.pop();
+            }
+        }
+        let mut tutti = Vec::new();
+        estendi(&mut Vec::new(), 1, max_dadi, &mut tutti);
+        tutti
+    }
+
+    fn verifica(dadi: &[u8], regole: &Regole, r: &Raggruppamento) {
+        let mut restanti = dadi.to_vec();
+        let mut raises = 0;
+        for gruppo in &r.gruppi {
+            assert!(
+                somma(gruppo) >= regole.target,
+                "{:?} sotto il target",
+                gruppo
+            );
+            raises += regole.valore(somma(gruppo)) as usize;
+            for d in gruppo {
+                let pos = restanti
+                    .iter()
+                    .position(|x| x == d)
+                    .expect("dado non nel tiro");
+                restanti.remove(pos);
+            }
+        }
+        assert_eq!(raises, r.raises);
+        restanti.sort_unstable();
+        let mut avanzi = r.avanzi.clone();
+        avanzi.sort_unstable();
+        assert_eq!(restanti, avanzi);
+    }
+
+    #[test]
+    fn ottimo_come_la_forza_bruta() {
+        let tutte = [
+            Regole::default(),
+            Regole {
+                target: 15,
+                set_doppi: false,
+            },
+            Regole {
+                target: TARGET_RAISE,
+                set_doppi: true,
+            },
+        ];
+        for dadi in tutti_i_tiri(6) {
+            for regole in &tutte {
+                let r = raggruppa_con(&dadi, regole);
+                verifica(&dadi, regole, &r);
+                assert_eq!(
+                    r.raises,
+                    oracolo(&dadi, &mut Vec::new(), regole),
+                    "tiro {:?}, regole {:?}",
+                    dadi,
+                    regole
+                );
+            }
+        }
+    }
+}
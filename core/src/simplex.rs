@@ -0,0 +1,119 @@
+//! Metodo del simplesso per i piccoli programmi lineari usati come limite
+//! superiore dal calcolo dei raises.
+
+const EPS: f64 = 1e-9;
+
+/// Valore massimo di `obiettivo · x` con `vincoli · x <= limiti` e `x >= 0`,
+/// insieme a un `x` che lo raggiunge.
+///
+/// Richiede `limiti >= 0` (l'origine è ammissibile) e che ogni colonna abbia
+/// almeno un coefficiente positivo (il problema è limitato). Di solito entra
+/// la colonna col costo ridotto più negativo, che arriva all'ottimo in pochi
+/// passi; dopo un passo degenere, che non fa crescere l'obiettivo, si segue
+/// la regola di Bland (entra la prima colonna col costo ridotto negativo)
+/// finché l'obiettivo non torna a crescere. I pareggi nel test del rapporto
+/// si rompono con l'indice più basso in base, così il metodo non cicla sui
+/// problemi degeneri dei raises.
+pub(crate) fn massimo(vincoli: &[Vec<f64>], limiti: &[f64], obiettivo: &[f64]) -> (f64, Vec<f64>) {
+    let righe = vincoli.len();
+    let colonne = obiettivo.len() + righe;
+
+    // tableau: una riga per vincolo (con le variabili di scarto) più la
+    // riga dell'obiettivo; l'ultima colonna è il termine noto
+    let mut t: Vec<Vec<f64>> = vincoli
+        .iter()
+        .zip(limiti)
+        .enumerate()
+        .map(|(i, (riga, &b))| {
+            let mut r = riga.clone();
+            r.extend((0..righe).map(|j| if i == j { 1.0 } else { 0.0 }));
+            r.push(b);
+            r
+        })
+        .collect();
+    let mut z: Vec<f64> = obiettivo.iter().map(|c| -c).collect();
+    z.resize(colonne + 1, 0.0);
+    let mut base: Vec<usize> = (obiettivo.len()..colonne).collect();
+
+    let mut degenere = false;
+    loop {
+        let mut negativi = (0..colonne).filter(|&j| z[j] < -EPS);
+        let entra = if degenere {
+            negativi.next()
+        } else {
+            negativi.min_by(|&a, &b| z[a].total_cmp(&z[b]))
+        };
+        let Some(entra) = entra else {
+            break;
+        };
+        let Some(esce) = (0..righe).filter(|&i| t[i][entra] > EPS).min_by(|&a, &b| {
+            let ra = t[a][colonne] / t[a][entra];
+            let rb = t[b][colonne] / t[b][entra];
+            ra.total_cmp(&rb).then(base[a].cmp(&base[b]))
+        }) else {
+            return (f64::INFINITY, Vec::new());
+        };
+
+        degenere = t[esce][colonne] < EPS;
+        let pivot = t[esce][entra];
+        for v in t[esce].iter_mut() {
+            *v /= pivot;
+        }
+        let riga_pivot = t[esce].clone();
+        for (i, riga) in t.iter_mut().enumerate() {
+            let f = riga[entra];
+            if i != esce && f.abs() > EPS {
+                for (v, p) in riga.iter_mut().zip(&riga_pivot) {
+                    *v -= f * p;
+                }
+            }
+        }
+        let f = z[entra];
+        for (v, p) in z.iter_mut().zip(&riga_pivot) {
+            *v -= f * p;
+        }
+        base[esce] = entra;
+    }
+
+    let mut x = vec![0.0; obiettivo.len()];
+    for (riga, &b) in t.iter().zip(&base) {
+        if b < x.len() {
+            x[b] = riga[colonne];
+        }
+    }
+    (z[colonne], x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problema_classico() {
+        // max 3x + 5y con x <= 4, 2y <= 12, 3x + 2y <= 18 => 36 in (2, 6)
+        let vincoli = vec![vec![1.0, 0.0], vec![0.0, 2.0], vec![3.0, 2.0]];
+        let (valore, x) = massimo(&vincoli, &[4.0, 12.0, 18.0], &[3.0, 5.0]);
+        assert!((valore - 36.0).abs() < 1e-9);
+        assert!((x[0] - 2.0).abs() < 1e-9 && (x[1] - 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn niente_cicli_sui_problemi_degeneri() {
+        // l'esempio di Beale: con la sola regola del costo più negativo il
+        // simplesso torna sempre alla stessa base
+        let vincoli = vec![
+            vec![0.25, -8.0, -1.0, 9.0],
+            vec![0.5, -12.0, -0.5, 3.0],
+            vec![0.0, 0.0, 1.0, 0.0],
+        ];
+        let (valore, _) = massimo(&vincoli, &[0.0, 0.0, 1.0], &[0.75, -20.0, 0.5, -6.0]);
+        assert!((valore - 1.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn soluzione_frazionaria() {
+        // max x con 2x <= 3
+        let (valore, _) = massimo(&[vec![2.0]], &[3.0], &[1.0]);
+        assert!((valore - 1.5).abs() < 1e-9);
+    }
+}
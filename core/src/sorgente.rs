@@ -0,0 +1,138 @@
+//! Da dove vengono i risultati dei dadi.
+//!
+//! Tutte le funzioni di tiro hanno una variante `_con` che riceve la
+//! sorgente: così i tiri si possono riprodurre a partire da un seme o
+//! scrivere in anticipo nei test.
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Qualcosa che sa tirare un dado da 10 facce.
+pub trait SorgenteDadi {
+    /// Un risultato da 1 a 10
+    fn tira(&mut self) -> u8;
+}
+
+/// Sorgente predefinita: generatore crittografico con seme preso dal
+/// sistema operativo, quindi imprevedibile.
+pub struct SorgenteSicura(ChaCha20Rng);
+
+impl SorgenteSicura {
+    pub fn new() -> Self {
+        SorgenteSicura(ChaCha20Rng::from_entropy())
+    }
+}
+
+impl Default for SorgenteSicura {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SorgenteDadi for SorgenteSicura {
+    fn tira(&mut self) -> u8 {
+        self.0.gen_range(1..=10)
+    }
+}
+
+/// Generatore pseudo-casuale con seme: a parità di seme dà sempre gli
+/// stessi tiri, anche su piattaforme diverse.
+pub struct SorgenteConSeme(ChaCha20Rng);
+
+impl SorgenteConSeme {
+    pub fn new(seme: u64) -> Self {
+        SorgenteConSeme(ChaCha20Rng::seed_from_u64(seme))
+    }
+
+    /// Usa direttamente i 32 byte del seme di ChaCha20
+    pub fn da_byte(seme: [u8; 32]) -> Self {
+        SorgenteConSeme(ChaCha20Rng::from_seed(seme))
+    }
+}
+
+impl SorgenteDadi for SorgenteConSeme {
+    fn tira(&mut self) -> u8 {
+        self.0.gen_range(1..=10)
+    }
+}
+
+/// Risultati scritti in anticipo, restituiti nell'ordine dato.
+pub struct SorgenteFissa {
+    dadi: Vec<u8>,
+    prossimo: usize,
+}
+
+impl SorgenteFissa {
+    /// # Panics
+    ///
+    /// Se un risultato vale 0 o più di 10.
+    pub fn new(dadi: impl Into<Vec<u8>>) -> Self {
+        let dadi = dadi.into();
+        assert!(
+            dadi.iter().all(|d| (1..=10).contains(d)),
+            "risultati non validi: {:?}",
+            dadi
+        );
+        SorgenteFissa { dadi, prossimo: 0 }
+    }
+
+    /// Risultati non ancora usati
+    pub fn rimasti(&self) -> usize {
+        self.dadi.len() - self.prossimo
+    }
+}
+
+impl SorgenteDadi for SorgenteFissa {
+    /// # Panics
+    ///
+    /// Se i risultati sono finiti.
+    fn tira(&mut self) -> u8 {
+        let dado = *self
+            .dadi
+            .get(self.prossimo)
+            .expect("la sequenza di risultati è finita");
+        self.prossimo += 1;
+        dado
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stesso_seme_stessi_tiri() {
+        let mut a = SorgenteConSeme::new(42);
+        let mut b = SorgenteConSeme::new(42);
+        let tiri_a: Vec<u8> = (0..100).map(|_| a.tira()).collect();
+        let tiri_b: Vec<u8> = (0..100).map(|_| b.tira()).collect();
+        assert_eq!(tiri_a, tiri_b);
+        assert!(tiri_a.iter().all(|d| (1..=10).contains(d)));
+
+        let mut c = SorgenteConSeme::new(43);
+        let tiri_c: Vec<u8> = (0..100).map(|_| c.tira()).collect();
+        assert_ne!(tiri_a, tiri_c);
+    }
+
+    #[test]
+    fn sorgente_fissa_in_ordine() {
+        let mut s = SorgenteFissa::new([3, 10, 1]);
+        assert_eq!(s.rimasti(), 3);
+        assert_eq!((s.tira(), s.tira(), s.tira()), (3, 10, 1));
+        assert_eq!(s.rimasti(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "finita")]
+    fn sorgente_fissa_esaurita() {
+        let mut s = SorgenteFissa::new([5]);
+        s.tira();
+        s.tira();
+    }
+
+    #[test]
+    fn sorgente_sicura_nel_range() {
+        let mut s = SorgenteSicura::new();
+        assert!((0..1000).all(|_| (1..=10).contains(&s.tira())));
+    }
+}
@@ -0,0 +1,439 @@
+//! Tiri fatti dal server per conto dei giocatori di un tavolo.
+//!
+//! I tiri di [`Tavolo::tira`] e [`Tavolo::rilancia`] prendono i dadi dal
+//! seme segreto della sessione, così si possono verificare quando il seme
+//! viene rivelato (vedi [`crate::verifica`]).
+
+use std::collections::HashMap;
+
+use crate::dice::{rilancia_dado_con, roll_con, RollWithRerollResponse};
+use crate::error::RollError;
+use crate::sorgente::{SorgenteConSeme, SorgenteDadi};
+use crate::tiri::{RerollRequest, RollRequest, RollResult, Visibilita};
+use crate::verifica::{Prova, SemeSegreto, MIN_NONCE_NASCOSTO};
+
+/// Ricorda l'ultimo tiro di ogni giocatore, così i rilanci partono sempre
+/// da risultati usciti sul server.
+#[derive(Debug)]
+pub struct Tavolo {
+    ultimi: HashMap<String, RollResult>,
+    seme: SemeSegreto,
+    /// Tiri fatti nella sessione
+    contatore: u64,
+}
+
+impl Tavolo {
+    /// Un tavolo con una sessione nuova
+    pub fn new() -> Self {
+        Self::con_seme(SemeSegreto::nuovo())
+    }
+
+    pub fn con_seme(seme: SemeSegreto) -> Self {
+        Tavolo {
+            ultimi: HashMap::new(),
+            seme,
+            contatore: 0,
+        }
+    }
+
+    /// Riprende una sessione salvata: `contatore` è il numero di tiri già
+    /// fatti col seme, che non vanno ripetuti
+    pub fn riprendi(seme: SemeSegreto, contatore: u64) -> Self {
+        Tavolo {
+            contatore,
+            ..Self::con_seme(seme)
+        }
+    }
+
+    /// Il seme della sessione in corso, per salvarla: non va mostrato ai
+    /// giocatori prima di [`Tavolo::nuova_sessione`]
+    pub fn seme(&self) -> &SemeSegreto {
+        &self.seme
+    }
+
+    /// Tiri fatti nella sessione in corso
+    pub fn contatore(&self) -> u64 {
+        self.contatore
+    }
+
+    /// L'impegno della sessione in corso, da mandare ai giocatori
+    pub fn impegno(&self) -> String {
+        self.seme.impegno()
+    }
+
+    /// Chiude la sessione e ne apre una con un seme nuovo; restituisce il
+    /// seme della sessione chiusa, da rivelare. I tiri della sessione chiusa
+    /// non si rilanciano più: un rilancio si verifica solo col suo tiro
+    pub fn nuova_sessione(&mut self) -> String {
+        let vecchio = std::mem::replace(&mut self.seme, SemeSegreto::nuovo());
+        self.contatore = 0;
+        self.ultimi.clear();
+        vecchio.rivela()
+    }
+
+    /// Tira i dadi richiesti e li registra come ultimo tiro del giocatore.
+    /// I tiri nascosti vogliono un nonce lungo almeno
+    /// [`MIN_NONCE_NASCOSTO`]
+    pub fn tira(&mut self, richiesta: RollRequest) -> Result<RollResult, RollError> {
+        let tiro = self.prenota(richiesta)?.esegui()?;
+        self.registra(&tiro);
+        Ok(tiro)
+    }
+
+    /// Come [`Tavolo::tira`], prendendo i dadi da `sorgente`: il tiro non
+    /// avrà una prova
+    pub fn tira_con<S: SorgenteDadi + ?Sized>(
+        &mut self,
+        richiesta: RollRequest,
+        sorgente: &mut S,
+    ) -> Result<RollResult, RollError> {
+        let tiro = tira_richiesta(richiesta, sorgente)?;
+        self.ultimi.insert(tiro.username.clone(), tiro.clone());
+        Ok(tiro)
+    }
+
+    /// Skill a rank 3: rilancia un dado dell'ultimo tiro del giocatore. Ogni
+    /// tiro ha un solo rilancio, e nessuno se ha già rilanciato un 1 da sé
+    pub fn rilancia(&mut self, richiesta: RerollRequest) -> Result<RollResult, RollError> {
+        let tiro = self.prenota_rilancio(richiesta)?.esegui()?;
+        self.registra(&tiro);
+        Ok(tiro)
+    }
+
+    /// Come [`Tavolo::rilancia`], prendendo il dado da `sorgente`
+    pub fn rilancia_con<S: SorgenteDadi + ?Sized>(
+        &mut self,
+        richiesta: RerollRequest,
+        sorgente: &mut S,
+    ) -> Result<RollResult, RollError> {
+        let ultimo = self
+            .ultimi
+            .get_mut(&richiesta.username)
+            .ok_or(RollError::NessunTiro)?;
+        rilanciabile(ultimo)?;
+        rilancia_tiro(ultimo, richiesta.indice, sorgente)?;
+        ultimo.prova = None;
+        Ok(ultimo.clone())
+    }
+
+    /// Prima metà di [`Tavolo::tira`]: controlla la richiesta e le riserva il
+    /// prossimo numero della sessione, senza tirare. Il tiro, che coi set
+    /// grandi può chiedere parecchio calcolo, si fa poi con
+    /// [`TiroPrenotato::esegui`] anche senza il tavolo in mano, e l'esito
+    /// torna al tavolo con [`Tavolo::registra`]
+    pub fn prenota(&mut self, richiesta: RollRequest) -> Result<TiroPrenotato, RollError> {
+        nonce_segreto(richiesta.visibilita.as_ref(), &richiesta.nonce)?;
+        richiesta.opzioni.validate()?;
+        let nonce = richiesta.nonce.clone();
+        Ok(self.prossimo(&nonce, Lavoro::Tiro(richiesta)))
+    }
+
+    /// Come [`Tavolo::prenota`], per [`Tavolo::rilancia`]. Il tiro da
+    /// rilanciare lascia subito il tavolo, così un secondo rilancio prenotato
+    /// prima che il primo sia registrato non trova niente da rilanciare
+    pub fn prenota_rilancio(
+        &mut self,
+        richiesta: RerollRequest,
+    ) -> Result<TiroPrenotato, RollError> {
+        let ultimo = self
+            .ultimi
+            .get(&richiesta.username)
+            .ok_or(RollError::NessunTiro)?;
+        // il rilancio è nascosto quanto il tiro
+        nonce_segreto(ultimo.visibilita.as_ref(), &richiesta.nonce)?;
+        rilanciabile(ultimo)?;
+        ultimo.opzioni.regole().validate()?;
+        let dadi = ultimo.esito.risultati_aggiornati.len();
+        if richiesta.indice >= dadi {
+            return Err(RollError::IndiceNonValido {
+                indice: richiesta.indice,
+                dadi,
+            });
+        }
+        let ultimo = self
+            .ultimi
+            .remove(&richiesta.username)
+            .expect("appena trovato");
+        let lavoro = Lavoro::Rilancio {
+            ultimo,
+            indice: richiesta.indice,
+        };
+        Ok(self.prossimo(&richiesta.nonce, lavoro))
+    }
+
+    /// Registra un tiro prenotato ed eseguito come ultimo tiro del giocatore.
+    /// Non tocca niente se nel frattempo la sessione è cambiata o il
+    /// giocatore ha già registrato un tiro prenotato dopo
+    pub fn registra(&mut self, tiro: &RollResult) {
+        let Some(prova) = &tiro.prova else {
+            return;
+        };
+        if prova.impegno != self.seme.impegno() {
+            return;
+        }
+        let successivo = self
+            .ultimi
+            .get(&tiro.username)
+            .and_then(|t| t.prova.as_ref())
+            .is_none_or(|p| p.contatore < prova.contatore);
+        if successivo {
+            self.ultimi.insert(tiro.username.clone(), tiro.clone());
+        }
+    }
+
+    /// Riserva il prossimo numero della sessione a `lavoro`
+    fn prossimo(&mut self, nonce: &str, lavoro: Lavoro) -> TiroPrenotato {
+        let prova = Prova {
+            impegno: self.seme.impegno(),
+            contatore: self.contatore,
+            nonce: nonce.to_string(),
+        };
+        let sorgente = self.seme.sorgente(self.contatore, nonce);
+        self.contatore += 1;
+        TiroPrenotato {
+            lavoro,
+            sorgente,
+            prova,
+        }
+    }
+}
+
+/// Un tiro che ha già il suo numero nella sessione, ma non è ancora stato
+/// fatto (vedi [`Tavolo::prenota`])
+pub struct TiroPrenotato {
+    lavoro: Lavoro,
+    sorgente: SorgenteConSeme,
+    prova: Prova,
+}
+
+enum Lavoro {
+    Tiro(RollRequest),
+    Rilancio { ultimo: RollResult, indice: usize },
+}
+
+impl TiroPrenotato {
+    /// Fa il tiro, con la sua prova. Non tiene il tavolo: si può fare su
+    /// un altro thread
+    pub fn esegui(mut self) -> Result<RollResult, RollError> {
+        let mut tiro = match self.lavoro {
+            Lavoro::Tiro(richiesta) => tira_richiesta(richiesta, &mut self.sorgente)?,
+            Lavoro::Rilancio { mut ultimo, indice } => {
+                rilancia_tiro(&mut ultimo, indice, &mut self.sorgente)?;
+                ultimo
+            }
+        };
+        tiro.prova = Some(self.prova);
+        Ok(tiro)
+    }
+}
+
+fn tira_richiesta<S: SorgenteDadi + ?Sized>(
+    richiesta: RollRequest,
+    sorgente: &mut S,
+) -> Result<RollResult, RollError> {
+    let esito = roll_con(&richiesta.opzioni, sorgente)?;
+    Ok(RollResult {
+        username: richiesta.username,
+        opzioni: richiesta.opzioni,
+        esito,
+        rilanciato_da: None,
+        prova: None,
+        visibilita: richiesta.visibilita,
+        coperto: false,
+    })
+}
+
+/// Ogni tiro ha un solo rilancio, e nessuno se ha già rilanciato un 1 da sé
+fn rilanciabile(ultimo: &RollResult) -> Result<(), RollError> {
+    if ultimo.opzioni.rilancia_uno || ultimo.esito.rilanciato.is_some() {
+        return Err(RollError::GiaRilanciato);
+    }
+    Ok(())
+}
+
+/// Rilancia il dado `indice` di `ultimo`, che diventa il tiro rilanciato
+fn rilancia_tiro<S: SorgenteDadi + ?Sized>(
+    ultimo: &mut RollResult,
+    indice: usize,
+    sorgente: &mut S,
+) -> Result<(), RollError> {
+    let dadi = ultimo.esito.risultati_aggiornati.clone();
+    let vecchio = dadi.get(indice).copied();
+    let dr = rilancia_dado_con(dadi, indice, &ultimo.opzioni.regole(), sorgente)?;
+
+    ultimo.esito = RollWithRerollResponse {
+        risultati_originali: ultimo.esito.risultati_originali.clone(),
+        rilanciato: dr.rilanciato,
+        risultati_aggiornati: dr.risultati,
+        raises: dr.raises,
+        combinazioni: dr.combinazioni,
+        avanzi: dr.avanzi,
+        esplosi: ultimo.esito.esplosi.clone(),
+        azioni: ultimo.opzioni.azioni(dr.raises),
+    };
+    ultimo.rilanciato_da = vecchio;
+    Ok(())
+}
+
+/// Controlla che un tiro con questa visibilità abbia un nonce abbastanza
+/// lungo da restare segreto
+fn nonce_segreto(visibilita: Option<&Visibilita>, nonce: &str) -> Result<(), RollError> {
+    if visibilita.is_some() && nonce.len() < MIN_NONCE_NASCOSTO {
+        return Err(RollError::NonceCorto {
+            minimo: MIN_NONCE_NASCOSTO,
+        });
+    }
+    Ok(())
+}
+
+impl Default for Tavolo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::RollOptions;
+    use crate::sorgente::SorgenteFissa;
+    use crate::verifica::nonce_casuale;
+
+    fn richiesta(username: &str, opzioni: RollOptions) -> RollRequest {
+        RollRequest {
+            username: username.into(),
+            opzioni,
+            nonce: String::new(),
+            visibilita: None,
+        }
+    }
+
+    #[test]
+    fn rilancio_dell_ultimo_tiro() {
+        let mut tavolo = Tavolo::new();
+        let mut sorgente = SorgenteFissa::new([6, 2, 1, 8]);
+
+        let tiro = tavolo
+            .tira_con(richiesta("Anna", RollOptions::new(3)), &mut sorgente)
+            .unwrap();
+        assert_eq!(tiro.esito.risultati_aggiornati, vec![6, 2, 1]);
+        assert_eq!(tiro.esito.raises, 0);
+
+        let rilancio = RerollRequest {
+            username: "Anna".into(),
+            indice: 2,
+            nonce: String::new(),
+        };
+        let nuovo = tavolo
+            .rilancia_con(rilancio.clone(), &mut sorgente)
+            .unwrap();
+        assert_eq!(nuovo.rilanciato_da, Some(1));
+        assert_eq!(nuovo.esito.rilanciato, Some(8));
+        assert_eq!(nuovo.esito.risultati_originali, vec![6, 2, 1]);
+        assert_eq!(nuovo.esito.risultati_aggiornati, vec![8, 6, 2]);
+        assert_eq!(nuovo.esito.raises, 1);
+
+        assert_eq!(
+            tavolo.rilancia_con(rilancio, &mut sorgente),
+            Err(RollError::GiaRilanciato)
+        );
+    }
+
+    #[test]
+    fn tiri_nascosti_con_nonce_lungo() {
+        let mut tavolo = Tavolo::new();
+        let nascosta = |nonce: &str| RollRequest {
+            nonce: nonce.into(),
+            visibilita: Some(Visibilita::SoloIo),
+            ..richiesta("Anna", RollOptions::new(2))
+        };
+        assert_eq!(
+            tavolo.tira(nascosta("corto")),
+            Err(RollError::NonceCorto {
+                minimo: MIN_NONCE_NASCOSTO
+            })
+        );
+        tavolo.tira(nascosta(&nonce_casuale())).unwrap();
+
+        let rilancio = |nonce: &str| RerollRequest {
+            username: "Anna".into(),
+            indice: 0,
+            nonce: nonce.into(),
+        };
+        assert!(matches!(
+            tavolo.rilancia(rilancio("")),
+            Err(RollError::NonceCorto { .. })
+        ));
+        tavolo.rilancia(rilancio(&nonce_casuale())).unwrap();
+    }
+
+    #[test]
+    fn tiri_prenotati() {
+        let mut tavolo = Tavolo::new();
+        let primo = tavolo
+            .prenota(richiesta("Anna", RollOptions::new(3)))
+            .unwrap();
+        let secondo = tavolo
+            .prenota(richiesta("Anna", RollOptions::new(4)))
+            .unwrap();
+        assert_eq!(tavolo.contatore(), 2);
+        assert_eq!(
+            tavolo.prenota(richiesta("Anna", RollOptions::new(0))).err(),
+            Some(RollError::NessunDado)
+        );
+        assert_eq!(tavolo.contatore(), 2);
+
+        // il secondo finisce prima: il primo non lo sostituisce
+        let secondo = secondo.esegui().unwrap();
+        tavolo.registra(&secondo);
+        tavolo.registra(&primo.esegui().unwrap());
+
+        let rilancio = RerollRequest {
+            username: "Anna".into(),
+            indice: 3,
+            nonce: String::new(),
+        };
+        let prenotato = tavolo.prenota_rilancio(rilancio.clone()).unwrap();
+        assert_eq!(
+            tavolo.prenota_rilancio(rilancio).err(),
+            Some(RollError::NessunTiro)
+        );
+        let rilanciato = prenotato.esegui().unwrap();
+        assert_eq!(
+            rilanciato.esito.risultati_originali,
+            secondo.esito.risultati_originali
+        );
+        assert_eq!(rilanciato.prova.as_ref().unwrap().contatore, 2);
+
+        // a sessione chiusa il rilancio non torna sul tavolo
+        tavolo.nuova_sessione();
+        tavolo.registra(&rilanciato);
+        assert_eq!(
+            tavolo.rilancia(RerollRequest {
+                username: "Anna".into(),
+                indice: 0,
+                nonce: String::new(),
+            }),
+            Err(RollError::NessunTiro)
+        );
+    }
+
+    #[test]
+    fn rilancio_senza_tiro() {
+        let mut tavolo = Tavolo::new();
+        let mut sorgente = SorgenteFissa::new([5, 5]);
+        tavolo
+            .tira_con(richiesta("Anna", RollOptions::new(2)), &mut sorgente)
+            .unwrap();
+
+        let rilancio = RerollRequest {
+            username: "Bruno".into(),
+            indice: 0,
+            nonce: String::new(),
+        };
+        assert_eq!(
+            tavolo.rilancia_con(rilancio, &mut sorgente),
+            Err(RollError::NessunTiro)
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Richieste di tiro dei giocatori ed esiti dei tiri fatti dal server.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dice::{RollOptions, RollWithRerollResponse};
+use crate::raises::TARGET_RAISE;
+use crate::verifica::Prova;
+
+/// Richiesta di un tiro al server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct RollRequest {
+    pub username: String,
+    pub opzioni: RollOptions,
+    /// Scelto dal giocatore e mescolato al seme del server
+    #[serde(default)]
+    pub nonce: String,
+    /// Chi vede il tiro; senza, tutta la stanza
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub visibilita: Option<Visibilita>,
+}
+
+/// Chi vede un tiro nascosto, oltre a chi l'ha fatto
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub enum Visibilita {
+    /// Il GM della stanza
+    SoloGm,
+    /// Nessun altro
+    SoloIo,
+    /// I giocatori elencati
+    Giocatori(Vec<String>),
+}
+
+impl Visibilita {
+    /// Se `username` vede i tiri di `autore` con questa visibilità; `gm` è
+    /// il GM della stanza, se c'è
+    pub fn vede(&self, autore: &str, username: Option<&str>, gm: Option<&str>) -> bool {
+        let Some(username) = username else {
+            return false;
+        };
+        username == autore
+            || match self {
+                Visibilita::SoloGm => gm == Some(username),
+                Visibilita::SoloIo => false,
+                Visibilita::Giocatori(giocatori) => giocatori.iter().any(|g| g == username),
+            }
+    }
+}
+
+/// Skill a rank 3: richiesta di rilanciare un dado dell'ultimo tiro
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct RerollRequest {
+    pub username: String,
+    /// Posizione del dado in `risultati_aggiornati`
+    pub indice: usize,
+    #[serde(default)]
+    pub nonce: String,
+}
+
+/// Esito di un tiro (o di un rilancio) fatto dal server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct RollResult {
+    pub username: String,
+    pub opzioni: RollOptions,
+    pub esito: RollWithRerollResponse,
+    /// Nei rilanci, il valore del dado prima di essere rilanciato
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub rilanciato_da: Option<u8>,
+    /// Per rifare il tiro quando il server rivelerà il seme della sessione
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub prova: Option<Prova>,
+    /// Quella della richiesta; i rilanci tengono quella del tiro
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub visibilita: Option<Visibilita>,
+    /// Il tiro è nascosto a chi lo riceve: dadi, opzioni e nonce non ci
+    /// sono, della prova resta solo il numero per contarlo nella verifica
+    /// della sessione
+    #[serde(default)]
+    pub coperto: bool,
+}
+
+impl RollResult {
+    /// Il tiro come lo vede chi non può vederlo. Senza opzioni e nonce
+    /// nemmeno il seme rivelato basta a rifarlo
+    pub fn coperto(&self) -> Self {
+        RollResult {
+            username: self.username.clone(),
+            opzioni: RollOptions::new(0),
+            esito: RollWithRerollResponse::default(),
+            rilanciato_da: None,
+            prova: self.prova.as_ref().map(|prova| Prova {
+                nonce: String::new(),
+                ..prova.clone()
+            }),
+            visibilita: self.visibilita.clone(),
+            coperto: true,
+        }
+    }
+
+    /// Testo del tiro per il log, es. "Anna ha tirato 5 dadi: [...] (raises: 2) | ..."
+    pub fn descrizione(&self) -> String {
+        if self.coperto {
+            return format!("{} ha fatto un tiro nascosto", self.username);
+        }
+        self.descrizione_scoperta()
+    }
+
+    fn descrizione_scoperta(&self) -> String {
+        let esito = &self.esito;
+        let mut text = match self.rilanciato_da {
+            Some(vecchio) => format!(
+                "{} ha rilanciato un dado da {} ed è uscito {}. Nuovi risultati: {:?} (raises: {}) | Combinazioni: {:?}",
+                self.username,
+                vecchio,
+                esito.rilanciato.unwrap_or_default(),
+                esito.risultati_aggiornati,
+                esito.raises,
+                esito.combinazioni
+            ),
+            None => {
+                let mut text = format!(
+                    "{} ha tirato {} dadi{}: {:?} (raises: {}) | Combinazioni: {:?}",
+                    self.username,
+                    self.opzioni.num_dadi,
+                    descrivi_regole(&self.opzioni),
+                    esito.risultati_aggiornati,
+                    esito.raises,
+                    esito.combinazioni
+                );
+                if !esito.esplosi.is_empty() {
+                    text.push_str(&format!(" | Esplosi: {:?}", esito.esplosi));
+                }
+                text
+            }
+        };
+        if let (Some(azioni), Some(costo)) = (esito.azioni, self.opzioni.costo) {
+            text.push_str(&format!(" | Azioni: {} (costo {})", azioni, costo));
+        }
+        text
+    }
+}
+
+/// Regole non standard del tiro, da riportare nel log (es. " (target 15)")
+fn descrivi_regole(opzioni: &RollOptions) -> String {
+    let regole = opzioni.regole();
+    let mut note = Vec::new();
+    if regole.target != TARGET_RAISE {
+        note.push(format!("target {}", regole.target));
+    }
+    if regole.set_doppi {
+        note.push(format!("set da {} doppi", regole.soglia_doppia()));
+    }
+    if opzioni.esplodi {
+        note.push("10 che esplodono".to_string());
+    }
+    if note.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", note.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descrizione_del_tiro() {
+        let tiro = tiro_di_prova();
+        assert_eq!(
+            tiro.descrizione(),
+            "Anna ha tirato 3 dadi (10 che esplodono): [10, 8, 3, 2] (raises: 2) \
+             | Combinazioni: [[10], [8, 2]] | Esplosi: [3] | Azioni: 1 (costo 2)"
+        );
+        assert_eq!(
+            tiro.coperto().descrizione(),
+            "Anna ha fatto un tiro nascosto"
+        );
+    }
+
+    #[test]
+    fn il_tiro_coperto_non_si_rifa() {
+        let tiro = RollResult {
+            prova: Some(Prova {
+                impegno: "impegno".into(),
+                contatore: 3,
+                nonce: "segreto".into(),
+            }),
+            visibilita: Some(Visibilita::SoloGm),
+            ..tiro_di_prova()
+        };
+        let coperto = tiro.coperto();
+        assert!(coperto.coperto);
+        assert_eq!(coperto.opzioni, RollOptions::new(0));
+        assert!(coperto.esito.risultati_originali.is_empty());
+        let prova = coperto.prova.unwrap();
+        assert_eq!((prova.impegno.as_str(), prova.contatore), ("impegno", 3));
+        assert!(prova.nonce.is_empty());
+    }
+
+    #[test]
+    fn chi_vede_i_tiri_nascosti() {
+        let gm = Some("Gina");
+        for visibilita in [
+            Visibilita::SoloGm,
+            Visibilita::SoloIo,
+            Visibilita::Giocatori(vec!["Bruno".into()]),
+        ] {
+            assert!(visibilita.vede("Anna", Some("Anna"), gm));
+            assert!(!visibilita.vede("Anna", Some("Carla"), gm));
+            assert!(!visibilita.vede("Anna", None, gm));
+        }
+        assert!(Visibilita::SoloGm.vede("Anna", Some("Gina"), gm));
+        assert!(!Visibilita::SoloIo.vede("Anna", Some("Gina"), gm));
+        assert!(Visibilita::Giocatori(vec!["Bruno".into()]).vede("Anna", Some("Bruno"), gm));
+        assert!(!Visibilita::SoloGm.vede("Anna", Some("Bruno"), None));
+    }
+
+    fn tiro_di_prova() -> RollResult {
+        RollResult {
+            username: "Anna".into(),
+            opzioni: RollOptions::new(3).esplodi(true).costo(Some(2)),
+            esito: RollWithRerollResponse {
+                risultati_originali: vec![10, 8, 2],
+                rilanciato: None,
+                risultati_aggiornati: vec![10, 8, 3, 2],
+                raises: 2,
+                combinazioni: vec![vec![10], vec![8, 2]],
+                avanzi: vec![3],
+                esplosi: vec![3],
+                azioni: Some(1),
+            },
+            rilanciato_da: None,
+            prova: None,
+            visibilita: None,
+            coperto: false,
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Tiri verificabili con uno schema commit–reveal.
+//!
+//! Il server sceglie un seme segreto e ne pubblica subito l'hash SHA-256
+//! (l'impegno). Ogni tiro prende i dadi da un generatore ricavato dal seme,
+//! dal numero del tiro nella sessione e da un nonce scelto dal giocatore; a
+//! fine sessione il server rivela il seme e chiunque può rifare tutti i tiri
+//! del log con [`verifica_sessione`].
+
+use std::collections::HashMap;
+use std::fmt;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dice::{rilancia_dado_con, roll_con, RollWithRerollResponse};
+use crate::error::ErroreVerifica;
+use crate::sorgente::{SorgenteConSeme, SorgenteDadi};
+use crate::tiri::RollResult;
+
+/// Il seme di una sessione, da tenere segreto fino alla fine
+pub struct SemeSegreto([u8; 32]);
+
+impl SemeSegreto {
+    /// Un seme nuovo preso dal generatore del sistema operativo
+    pub fn nuovo() -> Self {
+        let mut seme = [0; 32];
+        OsRng.fill_bytes(&mut seme);
+        SemeSegreto(seme)
+    }
+
+    /// Legge un seme rivelato, in esadecimale
+    pub fn da_hex(hex: &str) -> Result<Self, ErroreVerifica> {
+        let byte = da_hex(hex).ok_or(ErroreVerifica::SemeNonValido)?;
+        let seme = byte.try_into().map_err(|_| ErroreVerifica::SemeNonValido)?;
+        Ok(SemeSegreto(seme))
+    }
+
+    /// L'hash del seme, da pubblicare prima di tirare
+    pub fn impegno(&self) -> String {
+        in_hex(&Sha256::digest(self.0))
+    }
+
+    /// Il seme in esadecimale, da pubblicare a fine sessione
+    pub fn rivela(&self) -> String {
+        in_hex(&self.0)
+    }
+
+    /// Generatore dei dadi per il tiro numero `contatore` della sessione
+    pub fn sorgente(&self, contatore: u64, nonce: &str) -> SorgenteConSeme {
+        let seme = Sha256::new()
+            .chain_update(self.0)
+            .chain_update(contatore.to_le_bytes())
+            .chain_update(nonce.as_bytes())
+            .finalize();
+        SorgenteConSeme::da_byte(seme.into())
+    }
+}
+
+impl fmt::Debug for SemeSegreto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SemeSegreto({})", self.impegno())
+    }
+}
+
+/// Dati per rifare un tiro quando il seme della sessione sarà rivelato
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct Prova {
+    /// Impegno della sessione in cui è stato fatto il tiro
+    pub impegno: String,
+    /// Numero del tiro nella sessione, da 0
+    #[cfg_attr(feature = "schema", ts(type = "number"))]
+    pub contatore: u64,
+    /// Nonce scelto dal giocatore
+    pub nonce: String,
+}
+
+/// Lunghezza minima del nonce dei tiri nascosti: il nonce è quello che
+/// impedisce di rifarli a chi non li vede, una volta rivelato il seme
+pub const MIN_NONCE_NASCOSTO: usize = 16;
+
+/// Un nonce da mettere nelle richieste di tiro
+pub fn nonce_casuale() -> String {
+    let mut nonce = [0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    in_hex(&nonce)
+}
+
+/// Com'è andata la verifica di una sessione
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verifica {
+    /// Quanti tiri sono stati rifatti e tornano
+    pub verificati: usize,
+    /// Il numero del primo tiro della sessione tra quelli ricevuti: i tiri
+    /// prima di questo, per esempio in pagine del log non caricate, non sono
+    /// stati controllati
+    pub dal_tiro: u64,
+}
+
+impl Verifica {
+    /// Controllati tutti i tiri della sessione, dal primo
+    pub fn completa(&self) -> bool {
+        self.dal_tiro == 0
+    }
+}
+
+/// Rifà col seme rivelato tutti i tiri di `tiri` fatti nella sua sessione e
+/// controlla che tornino, e che non ne manchi nessuno dal primo ricevuto in
+/// poi. Ogni rilancio parte dai dadi del tiro precedente dello stesso
+/// giocatore, che devono essere quelli già verificati. Gli altri tiri
+/// vengono ignorati, e quelli coperti contano solo per la sequenza, come i
+/// rilanci di tiri che non sono tra quelli ricevuti.
+pub fn verifica_sessione<'a>(
+    seme: &str,
+    tiri: impl IntoIterator<Item = &'a RollResult>,
+) -> Result<Verifica, ErroreVerifica> {
+    let seme = SemeSegreto::da_hex(seme)?;
+    let impegno = seme.impegno();
+
+    let mut sessione: Vec<(&Prova, &RollResult)> = tiri
+        .into_iter()
+        .filter_map(|t| t.prova.as_ref().map(|p| (p, t)))
+        .filter(|(p, _)| p.impegno == impegno)
+        .collect();
+    sessione.sort_by_key(|(p, _)| p.contatore);
+    let dal_tiro = sessione.first().map_or(0, |(p, _)| p.contatore);
+
+    // l'ultimo tiro di ogni giocatore, da cui partono i suoi rilanci
+    let mut ultimi: HashMap<&str, &RollResult> = HashMap::new();
+    let mut verificati = 0;
+    for (atteso, (prova, tiro)) in (dal_tiro..).zip(&sessione) {
+        if prova.contatore != atteso {
+            return Err(ErroreVerifica::TiroMancante { contatore: atteso });
+        }
+        let precedente = ultimi.insert(&tiro.username, tiro);
+        // il tiro rilanciato può stare prima del primo ricevuto
+        let senza_base = tiro.rilanciato_da.is_some() && precedente.is_none() && dal_tiro > 0;
+        if tiro.coperto || senza_base {
+            continue;
+        }
+        let mut sorgente = seme.sorgente(prova.contatore, &prova.nonce);
+        if ricalcola(tiro, precedente, &mut sorgente).as_ref() != Some(&tiro.esito) {
+            return Err(ErroreVerifica::TiroDiverso {
+                contatore: prova.contatore,
+            });
+        }
+        verificati += 1;
+    }
+    Ok(Verifica {
+        verificati,
+        dal_tiro,
+    })
+}
+
+/// L'esito che `tiro` deve avere prendendo i dadi da `sorgente`, se il tiro
+/// ha senso. Un rilancio ha senso solo su `precedente`, l'ultimo tiro dello
+/// stesso giocatore nella sessione, se non ha già usato il suo rilancio
+fn ricalcola<S: SorgenteDadi + ?Sized>(
+    tiro: &RollResult,
+    precedente: Option<&RollResult>,
+    sorgente: &mut S,
+) -> Option<RollWithRerollResponse> {
+    let Some(vecchio) = tiro.rilanciato_da else {
+        return roll_con(&tiro.opzioni, sorgente).ok();
+    };
+
+    // come in Tavolo::rilancia: i dadi di partenza sono quelli del tiro
+    // precedente, con le sue opzioni
+    let precedente = precedente.filter(|p| !p.coperto)?;
+    if precedente.rilanciato_da.is_some()
+        || precedente.opzioni.rilancia_uno
+        || precedente.esito.rilanciato.is_some()
+        || precedente.opzioni != tiro.opzioni
+    {
+        return None;
+    }
+    let prima = &precedente.esito;
+    let indice = prima
+        .risultati_aggiornati
+        .iter()
+        .position(|&d| d == vecchio)?;
+    let dr = rilancia_dado_con(
+        prima.risultati_aggiornati.clone(),
+        indice,
+        &tiro.opzioni.regole(),
+        sorgente,
+    )
+    .ok()?;
+    Some(RollWithRerollResponse {
+        risultati_originali: prima.risultati_originali.clone(),
+        rilanciato: dr.rilanciato,
+        risultati_aggiornati: dr.risultati,
+        raises: dr.raises,
+        combinazioni: dr.combinazioni,
+        avanzi: dr.avanzi,
+        esplosi: prima.esplosi.clone(),
+        azioni: tiro.opzioni.azioni(dr.raises),
+    })
+}
+
+fn in_hex(byte: &[u8]) -> String {
+    byte.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn da_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|coppia| match coppia {
+            [alta, bassa] => Some(cifra(*alta)? << 4 | cifra(*bassa)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn cifra(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::RollOptions;
+    use crate::tavolo::Tavolo;
+    use crate::tiri::{RerollRequest, RollRequest};
+
+    fn sessione() -> (Tavolo, Vec<RollResult>) {
+        let mut tavolo = Tavolo::con_seme(SemeSegreto::da_hex(&"ab".repeat(32)).unwrap());
+        let mut tiri = Vec::new();
+        for (username, opzioni) in [
+            ("Anna", RollOptions::new(6).esplodi(true)),
+            ("Bruno", RollOptions::new(4).set_doppi(true).target(15)),
+            ("Anna", RollOptions::new(3).rilancia_uno(true)),
+        ] {
+            let richiesta = RollRequest {
+                username: username.into(),
+                opzioni,
+                nonce: format!("nonce di {}", username),
+                visibilita: None,
+            };
+            tiri.push(tavolo.tira(richiesta).unwrap());
+        }
+        let rilancio = RerollRequest {
+            username: "Bruno".into(),
+            indice: 1,
+            nonce: "altro".into(),
+        };
+        tiri.push(tavolo.rilancia(rilancio).unwrap());
+        (tavolo, tiri)
+    }
+
+    fn verificati<'a>(
+        seme: &str,
+        tiri: impl IntoIterator<Item = &'a RollResult>,
+    ) -> Result<usize, ErroreVerifica> {
+        verifica_sessione(seme, tiri).map(|v| v.verificati)
+    }
+
+    #[test]
+    fn log_parziale() {
+        // chi entra tardi ha solo le ultime pagine del log
+        let (mut tavolo, tiri) = sessione();
+        let seme = tavolo.nuova_sessione();
+        let verifica = verifica_sessione(&seme, &tiri[2..]).unwrap();
+        assert!(!verifica.completa());
+        assert_eq!(verifica.dal_tiro, 2);
+        // il rilancio di Bruno parte da un tiro che non c'è: non si controlla
+        assert_eq!(verifica.verificati, 1);
+
+        // i buchi dopo il primo tiro ricevuto restano un errore
+        assert_eq!(
+            verifica_sessione(&seme, [&tiri[1], &tiri[3]]),
+            Err(ErroreVerifica::TiroMancante { contatore: 2 })
+        );
+    }
+
+    #[test]
+    fn impegno_e_rivelazione() {
+        let seme = SemeSegreto::nuovo();
+        let rivelato = SemeSegreto::da_hex(&seme.rivela()).unwrap();
+        assert_eq!(rivelato.impegno(), seme.impegno());
+        assert_eq!(seme.impegno().len(), 64);
+        assert_ne!(seme.impegno(), seme.rivela());
+
+        assert!(SemeSegreto::da_hex("abc").is_err());
+        assert!(SemeSegreto::da_hex(&"zz".repeat(32)).is_err());
+        assert!(SemeSegreto::da_hex(&"ab".repeat(31)).is_err());
+    }
+
+    #[test]
+    fn la_sessione_si_verifica() {
+        let (mut tavolo, tiri) = sessione();
+        let impegno = tavolo.impegno();
+        assert!(tiri
+            .iter()
+            .all(|t| t.prova.as_ref().unwrap().impegno == impegno));
+
+        let seme = tavolo.nuova_sessione();
+        assert_eq!(
+            verifica_sessione(&seme, &tiri),
+            Ok(Verifica {
+                verificati: 4,
+                dal_tiro: 0
+            })
+        );
+        assert_ne!(tavolo.impegno(), impegno);
+
+        // i tiri delle altre sessioni non contano
+        let altro = SemeSegreto::nuovo().rivela();
+        assert_eq!(verificati(&altro, &tiri), Ok(0));
+    }
+
+    #[test]
+    fn sessione_ripresa() {
+        // es. dopo un riavvio del server che aveva salvato seme e contatore
+        let (tavolo, mut tiri) = sessione();
+        let seme = SemeSegreto::da_hex(&tavolo.seme().rivela()).unwrap();
+        let mut ripreso = Tavolo::riprendi(seme, tavolo.contatore());
+        assert_eq!(ripreso.impegno(), tavolo.impegno());
+
+        let richiesta = RollRequest {
+            username: "Carla".into(),
+            opzioni: RollOptions::new(5),
+            nonce: "dopo".into(),
+            visibilita: None,
+        };
+        tiri.push(ripreso.tira(richiesta).unwrap());
+        assert_eq!(tiri[4].prova.as_ref().unwrap().contatore, 4);
+        let seme = ripreso.nuova_sessione();
+        assert_eq!(verificati(&seme, &tiri), Ok(5));
+    }
+
+    #[test]
+    fn tiri_alterati_o_mancanti() {
+        let (mut tavolo, tiri) = sessione();
+        let seme = tavolo.nuova_sessione();
+
+        let mut alterati = tiri.clone();
+        alterati[1].esito.raises += 1;
+        assert_eq!(
+            verifica_sessione(&seme, &alterati),
+            Err(ErroreVerifica::TiroDiverso { contatore: 1 })
+        );
+
+        // i dadi tenuti di un rilancio sono quelli del tiro di prima
+        let mut rilancio_inventato = tiri.clone();
+        rilancio_inventato[3].esito.risultati_originali = vec![10, 10, 10, 10];
+        assert_eq!(
+            verifica_sessione(&seme, &rilancio_inventato),
+            Err(ErroreVerifica::TiroDiverso { contatore: 3 })
+        );
+        let mut rilancio_inventato = tiri.clone();
+        let esito = &mut rilancio_inventato[3].esito;
+        let nuovo = esito.rilanciato;
+        let tenuto = esito
+            .risultati_aggiornati
+            .iter_mut()
+            .find(|d| Some(**d) != nuovo)
+            .unwrap();
+        *tenuto = if *tenuto == 10 { 1 } else { 10 };
+        assert_eq!(
+            verifica_sessione(&seme, &rilancio_inventato),
+            Err(ErroreVerifica::TiroDiverso { contatore: 3 })
+        );
+
+        let mut nonce_cambiato = tiri.clone();
+        nonce_cambiato[0].prova.as_mut().unwrap().nonce.push('!');
+        assert_eq!(
+            verifica_sessione(&seme, &nonce_cambiato),
+            Err(ErroreVerifica::TiroDiverso { contatore: 0 })
+        );
+
+        assert_eq!(
+            verifica_sessione(&seme, [&tiri[0], &tiri[2], &tiri[3]]),
+            Err(ErroreVerifica::TiroMancante { contatore: 1 })
+        );
+        // chi non vede un tiro nascosto lo conta senza poterlo rifare, e
+        // così il suo rilancio, nascosto come lui
+        let coperto = tiri[1].coperto();
+        let rilancio_coperto = tiri[3].coperto();
+        assert_eq!(
+            verificati(&seme, [&tiri[0], &coperto, &tiri[2], &rilancio_coperto]),
+            Ok(2)
+        );
+        // un rilancio scoperto di un tiro coperto non si può controllare
+        assert_eq!(
+            verifica_sessione(&seme, [&tiri[0], &coperto, &tiri[2], &tiri[3]]),
+            Err(ErroreVerifica::TiroDiverso { contatore: 3 })
+        );
+        assert_eq!(
+            verifica_sessione("00", &tiri),
+            Err(ErroreVerifica::SemeNonValido)
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Connessione al server del tavolo: indirizzo, gettone di sessione,
+//! trasporto, riconnessione automatica, battito e coda dei messaggi da
+//! mandare.
+
+use std::collections::VecDeque;
+
+use yew_websocket::websocket::WebSocketTask;
+
+use crate::sse::CanaleSse;
+
+/// Server usato se la pagina non ne indica un altro; si cambia in fase di
+/// build con la variabile d'ambiente `SEVEN_SEAS_SERVER`
+pub const SERVER_PREDEFINITO: &str = match option_env!("SEVEN_SEAS_SERVER") {
+    Some(server) => server,
+    None => "wss://dice-server-qxze.onrender.com/ws/",
+};
+
+/// Attesa prima del primo tentativo di riconnessione
+const RITARDO_MIN_MS: u32 = 1_000;
+/// Attesa massima tra due tentativi
+const RITARDO_MAX_MS: u32 = 30_000;
+/// Ogni quanto controllare il battito se il server non ne chiede uno
+pub const BATTITO_PREDEFINITO_MS: u32 = 15_000;
+/// Messaggi tenuti da parte mentre si è disconnessi: oltre si scartano i più
+/// vecchi
+const MAX_CODA: usize = 100;
+
+/// Server da usare all'apertura della pagina: quello in `?server=...` se
+/// c'è, altrimenti [`SERVER_PREDEFINITO`]
+pub fn server_iniziale() -> String {
+    parametro_pagina("server").unwrap_or_else(|| SERVER_PREDEFINITO.to_string())
+}
+
+/// Codice della stanza in `?room=...`, per i link da condividere
+pub fn stanza_iniziale() -> String {
+    parametro_pagina("room").unwrap_or_default()
+}
+
+/// Un parametro non vuoto dell'indirizzo della pagina
+fn parametro_pagina(nome: &str) -> Option<String> {
+    web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .and_then(|q| web_sys::UrlSearchParams::new_with_str(&q).ok())
+        .and_then(|p| p.get(nome))
+        .filter(|s| !s.is_empty())
+}
+
+/// Il gettone di sessione ricevuto da `server` in una visita precedente
+pub fn gettone_salvato(server: &str) -> Option<String> {
+    web_sys::window()
+        .and_then(|w| w.local_storage().ok().flatten())
+        .and_then(|s| s.get_item(&chiave_gettone(server)).ok().flatten())
+}
+
+/// Conserva il gettone di `server`, per ripresentarlo anche dopo aver
+/// ricaricato la pagina
+pub fn salva_gettone(server: &str, gettone: &str) {
+    let archivio = web_sys::window().and_then(|w| w.local_storage().ok().flatten());
+    if let Some(archivio) = archivio {
+        let _ = archivio.set_item(&chiave_gettone(server), gettone);
+    }
+}
+
+/// Ogni server firma i suoi gettoni: se ne tiene uno per server
+fn chiave_gettone(server: &str) -> String {
+    format!("seven_seas_gettone:{}", server)
+}
+
+/// Aggiunge all'indirizzo il parametro `nome=valore`
+pub fn con_parametro(url: &str, nome: &str, valore: &str) -> String {
+    let separatore = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", url, separatore, nome, codifica(valore))
+}
+
+/// Indirizzo per riconnettersi ricevendo solo le righe del log dopo `seq`
+pub fn con_ripresa(url: &str, seq: u64) -> String {
+    con_parametro(url, "after", &seq.to_string())
+}
+
+/// `valore` da mettere in un indirizzo, con i caratteri speciali in `%XX`
+fn codifica(valore: &str) -> String {
+    valore
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Attesa prima del tentativo di riconnessione numero `tentativo` (da 0):
+/// raddoppia a ogni tentativo fallito, fino a [`RITARDO_MAX_MS`]
+pub fn ritardo_ms(tentativo: u32) -> u32 {
+    RITARDO_MIN_MS
+        .saturating_mul(1 << tentativo.min(16))
+        .min(RITARDO_MAX_MS)
+}
+
+/// Messaggi in attesa che il socket si apra, nell'ordine di invio
+#[derive(Default)]
+pub struct Coda(VecDeque<String>);
+
+impl Coda {
+    pub fn accoda(&mut self, msg: String) {
+        if self.0.len() == MAX_CODA {
+            self.0.pop_front();
+        }
+        self.0.push_back(msg);
+    }
+
+    pub fn svuota(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.0.drain(..)
+    }
+
+    pub fn scarta(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn in_attesa(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Come arrivano i messaggi al server e dal server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Trasporto {
+    #[default]
+    WebSocket,
+    /// Eventi SSE e POST, per quando il WebSocket non passa
+    Sse,
+}
+
+impl Trasporto {
+    fn altro(self) -> Self {
+        match self {
+            Trasporto::WebSocket => Trasporto::Sse,
+            Trasporto::Sse => Trasporto::WebSocket,
+        }
+    }
+}
+
+/// Una connessione aperta col trasporto scelto
+pub enum Canale {
+    WebSocket(WebSocketTask),
+    Sse(CanaleSse),
+}
+
+impl Canale {
+    pub fn send(&mut self, msg: String) {
+        match self {
+            Canale::WebSocket(task) => task.send(msg),
+            Canale::Sse(canale) => canale.send(msg),
+        }
+    }
+}
+
+/// Stato della connessione, condiviso dalle callback del trasporto
+#[derive(Default)]
+pub struct Connessione {
+    pub task: Option<Canale>,
+    /// Il trasporto del prossimo tentativo
+    pub trasporto: Trasporto,
+    /// Il giocatore vuole essere collegato: se la connessione cade si riprova
+    pub voluta: bool,
+    /// Il socket è aperto e si può scrivere
+    pub aperta: bool,
+    /// Tentativi falliti dall'ultima connessione riuscita
+    pub tentativi: u32,
+    /// Riconnessioni programmate finora, per far ripartire l'effetto che le
+    /// esegue
+    pub riconnessioni: u32,
+    /// Codice della stanza in cui siamo, per rientrarci riconnettendosi
+    pub stanza: Option<String>,
+    pub coda: Coda,
+    /// Ogni quanto il server vuole un ping; `None` se non li capisce
+    pub battito_ms: Option<u32>,
+    /// Quando è partito il ping che aspetta ancora il pong, in ms
+    pub ping_in_volo: Option<f64>,
+}
+
+/// Cosa fare a un colpo del battito
+#[derive(Debug, PartialEq)]
+pub enum Battito {
+    /// Niente da fare
+    Aspetta,
+    /// Mandare un ping con questo numero
+    Ping(u64),
+    /// Il pong dell'ultimo ping non è arrivato: la connessione è morta
+    Persa,
+}
+
+impl Connessione {
+    /// Manda `msg` se il socket è aperto, altrimenti lo tiene da parte
+    pub fn invia(&mut self, msg: String) {
+        self.coda.accoda(msg);
+        self.svuota();
+    }
+
+    /// Un colpo del battito all'ora `adesso` (in ms): un ping se il socket
+    /// è aperto e il server li capisce, ma solo se il pong del precedente è
+    /// arrivato in tempo
+    pub fn battito(&mut self, adesso: f64) -> Battito {
+        let Some(intervallo) = self.battito_ms.filter(|_| self.aperta) else {
+            return Battito::Aspetta;
+        };
+        match self.ping_in_volo {
+            Some(inviato) if adesso - inviato >= intervallo.into() => Battito::Persa,
+            Some(_) => Battito::Aspetta,
+            None => {
+                self.ping_in_volo = Some(adesso);
+                Battito::Ping(adesso as u64)
+            }
+        }
+    }
+
+    /// È arrivato il pong del ping `numero`: restituisce la latenza in ms
+    pub fn pong(&mut self, numero: u64, adesso: f64) -> f64 {
+        self.ping_in_volo = None;
+        adesso - numero as f64
+    }
+
+    /// Il socket si è chiuso: il battito riparte col prossimo. Se non si è
+    /// mai aperto il trasporto non passa, e si riprova con l'altro
+    pub fn chiusa(&mut self) {
+        if !self.aperta {
+            self.trasporto = self.trasporto.altro();
+        }
+        self.aperta = false;
+        self.ping_in_volo = None;
+    }
+
+    /// Manda i messaggi in coda, se il socket è aperto
+    pub fn svuota(&mut self) {
+        if let (true, Some(task)) = (self.aperta, self.task.as_mut()) {
+            for msg in self.coda.svuota() {
+                task.send(msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ritardi_raddoppiano_fino_al_massimo() {
+        let ritardi: Vec<u32> = (0..7).map(ritardo_ms).collect();
+        assert_eq!(ritardi, [1_000, 2_000, 4_000, 8_000, 16_000, 30_000, 30_000]);
+        assert_eq!(ritardo_ms(u32::MAX), RITARDO_MAX_MS);
+    }
+
+    #[test]
+    fn indirizzo_di_ripresa() {
+        assert_eq!(
+            con_ripresa("ws://localhost:8000/ws/", 7),
+            "ws://localhost:8000/ws/?after=7"
+        );
+        assert_eq!(
+            con_ripresa("ws://localhost:8000/ws/?stanza=a", 7),
+            "ws://localhost:8000/ws/?stanza=a&after=7"
+        );
+    }
+
+    #[test]
+    fn parametri_codificati() {
+        assert_eq!(
+            con_parametro("ws://localhost:8000/ws/", "username", "Anna Maria"),
+            "ws://localhost:8000/ws/?username=Anna%20Maria"
+        );
+        assert_eq!(
+            con_parametro("ws://localhost:8000/ws/?room=ABC234", "username", "Niccolò&co"),
+            "ws://localhost:8000/ws/?room=ABC234&username=Niccol%C3%B2%26co"
+        );
+    }
+
+    #[test]
+    fn coda_in_ordine_e_limitata() {
+        let mut coda = Coda::default();
+        for i in 0..MAX_CODA + 2 {
+            coda.accoda(i.to_string());
+        }
+        assert_eq!(coda.in_attesa(), MAX_CODA);
+        let messaggi: Vec<String> = coda.svuota().collect();
+        assert_eq!(messaggi.first().map(String::as_str), Some("2"));
+        assert_eq!(messaggi.last(), Some(&(MAX_CODA + 1).to_string()));
+        assert_eq!(coda.in_attesa(), 0);
+    }
+
+    #[test]
+    fn senza_socket_i_messaggi_restano_in_coda() {
+        let mut conn = Connessione {
+            aperta: true,
+            ..Connessione::default()
+        };
+        conn.invia("uno".into());
+        conn.invia("due".into());
+        assert_eq!(conn.coda.in_attesa(), 2);
+    }
+
+    #[test]
+    fn battito_e_connessione_persa() {
+        let mut conn = Connessione {
+            aperta: true,
+            ..Connessione::default()
+        };
+        // un server che non chiede il battito non riceve ping
+        assert_eq!(conn.battito(1_000.0), Battito::Aspetta);
+
+        conn.battito_ms = Some(100);
+        assert_eq!(conn.battito(1_000.0), Battito::Ping(1_000));
+        assert_eq!(conn.battito(1_050.0), Battito::Aspetta);
+        assert_eq!(conn.pong(1_000, 1_042.0), 42.0);
+        assert_eq!(conn.battito(1_100.0), Battito::Ping(1_100));
+        assert_eq!(conn.battito(1_200.0), Battito::Persa);
+
+        // dopo la chiusura si riparte da capo
+        conn.chiusa();
+        assert_eq!(conn.battito(1_300.0), Battito::Aspetta);
+        conn.aperta = true;
+        assert_eq!(conn.battito(1_300.0), Battito::Ping(1_300));
+    }
+
+    #[test]
+    fn senza_websocket_si_passa_a_sse() {
+        let mut conn = Connessione::default();
+        assert_eq!(conn.trasporto, Trasporto::WebSocket);
+
+        // una connessione caduta dopo essersi aperta si riprova uguale
+        conn.aperta = true;
+        conn.chiusa();
+        assert_eq!(conn.trasporto, Trasporto::WebSocket);
+
+        // una che non si apre proprio no, e si alterna finché una passa
+        conn.chiusa();
+        assert_eq!(conn.trasporto, Trasporto::Sse);
+        conn.chiusa();
+        assert_eq!(conn.trasporto, Trasporto::WebSocket);
+    }
+}
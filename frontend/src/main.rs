@@ -1,174 +1,225 @@
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use wasm_bindgen_futures::spawn_local;
-use wasm_logger;
-use web_sys::{console, HtmlInputElement};
+mod connessione;
+mod sse;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use connessione::{
+    con_parametro, con_ripresa, gettone_salvato, ritardo_ms, salva_gettone, server_iniziale,
+    stanza_iniziale, Battito, Canale, Connessione, Trasporto, BATTITO_PREDEFINITO_MS,
+};
+use seven_seas_core::{
+    consiglia_rilancio, nonce_casuale, verifica_sessione, Consiglio, RerollRequest, RollError,
+    RollOptions, RollRequest, RollResult, Visibilita, TARGET_RAISE,
+};
+use seven_seas_protocol::{
+    compatibile, valida_username, ChatMessage, ClientMessage, ErroreMessaggio, Giocatore, Hello,
+    Moderazione, ServerMessage, Stanza, PAGINA_LOG, STANZA_COMUNE, VERSIONE_PROTOCOLLO,
+};
+use sse::{indirizzo_sse, CanaleSse};
+use web_sys::{console, HtmlInputElement, HtmlSelectElement};
 use yew::functional::function_component;
+use yew::platform::{spawn_local, time::sleep};
 use yew::prelude::*;
-use yew::prelude::use_mut_ref;
-use yew_websocket::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
+use yew_websocket::websocket::{WebSocketService, WebSocketStatus};
 
-/* ------------------ Strutture & Messaggi per la Chat ------------------ */
+/// Nome del client nell'[`Hello`]
+const NOME_CLIENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatMessage {
-    pub username: String,
-    pub message: String,
-}
-
-/// Messaggi che il client invia al server (es. aggiungere un messaggio di chat)
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", content = "data")]
-enum ClientMessage {
-    AddMessage(ChatMessage),
-}
+/* ------------------------- Log del tavolo ------------------------- */
 
-/// Messaggi inviati dal server al client
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", content = "data")]
-enum ServerMessage {
-    FullHistory(Vec<ChatMessage>),
-    System(String),
-}
+/// Messaggi del tavolo, il più recente in index 0
+#[derive(Default, PartialEq)]
+struct LogTavolo(Vec<ChatMessage>);
 
-/* ------------------ Strutture & Logica Dadi ------------------ */
+impl LogTavolo {
+    /// L'ultima riga del server che abbiamo, per riprendere da lì
+    fn ultimo_seq(&self) -> Option<u64> {
+        self.0.iter().filter_map(|m| m.seq).max()
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct DiceResponse {
-    risultati: Vec<u8>,
-    raises: usize,
-    combinazioni: Vec<Vec<u8>>,
+    /// La riga del server più vecchia che abbiamo, per chiedere quelle prima
+    fn primo_seq(&self) -> Option<u64> {
+        self.0.iter().filter_map(|m| m.seq).min()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct RollWithRerollResponse {
-    risultati_originali: Vec<u8>,
-    rilanciato: Option<u8>,
-    risultati_aggiornati: Vec<u8>,
-    raises: usize,
-    combinazioni: Vec<Vec<u8>>,
+enum AzioneLog {
+    /// Lo storico completo mandato dal server, dal più vecchio
+    Storico(Vec<ChatMessage>),
+    /// Righe nuove del server, dalla più vecchia; quelle già viste si saltano
+    Nuove(Vec<ChatMessage>),
+    /// Una pagina di righe precedenti, dalla più vecchia; vanno in fondo
+    Precedenti(Vec<ChatMessage>),
+    /// Una riga locale, senza numero
+    Aggiungi(ChatMessage),
+    /// Una riga toccata dal GM, al posto di quella con lo stesso `seq`
+    Aggiorna(ChatMessage),
+    /// Il GM ha svuotato il log: restano solo le righe locali
+    Svuota,
+    /// Il seme di una sessione chiusa: si verificano i tiri del log
+    Rivela(String),
 }
 
-/// Calcolo dei raises
-fn massimizza_raises(dadi: &[u8]) -> (usize, Vec<Vec<u8>>) {
-    let mut raises = 0;
-    let mut combo_ottimali = Vec::new();
-    let mut restanti = dadi.to_vec();
-
-    while !restanti.is_empty() {
-        let mut possibili = Vec::new();
-        let mut combo_corrente = Vec::new();
-        trova_combinazioni(&restanti, 10, 0, &mut combo_corrente, &mut possibili);
-
-        if !possibili.is_empty() {
-            let migliore = possibili
-                .iter()
-                .max_by_key(|c| c.len())
-                .unwrap()
-                .clone();
-            raises += 1;
-            combo_ottimali.push(migliore.clone());
-
-            for v in migliore {
-                if let Some(pos) = restanti.iter().position(|&x| x == v) {
-                    restanti.remove(pos);
+impl Reducible for LogTavolo {
+    type Action = AzioneLog;
+
+    fn reduce(self: Rc<Self>, azione: AzioneLog) -> Rc<Self> {
+        match azione {
+            AzioneLog::Storico(mut log_vec) => {
+                // invertiamo => il più recente index 0
+                log_vec.reverse();
+                Rc::new(LogTavolo(log_vec))
+            }
+            AzioneLog::Nuove(righe) => {
+                let mut ultimo = self.ultimo_seq().unwrap_or(0);
+                let mut new_list = self.0.clone();
+                for msg in righe {
+                    if msg.seq.is_some_and(|seq| seq <= ultimo) {
+                        continue;
+                    }
+                    ultimo = msg.seq.unwrap_or(ultimo);
+                    new_list.insert(0, msg);
+                }
+                Rc::new(LogTavolo(new_list))
+            }
+            AzioneLog::Precedenti(righe) => {
+                let primo = self.primo_seq().unwrap_or(u64::MAX);
+                let mut new_list = self.0.clone();
+                new_list.extend(righe.into_iter().rev().filter(|m| m.seq.is_some_and(|seq| seq < primo)));
+                Rc::new(LogTavolo(new_list))
+            }
+            AzioneLog::Aggiungi(msg) => {
+                let mut new_list = self.0.clone();
+                new_list.insert(0, msg);
+                Rc::new(LogTavolo(new_list))
+            }
+            AzioneLog::Aggiorna(msg) => {
+                let mut new_list = self.0.clone();
+                match new_list.iter_mut().find(|m| m.seq.is_some() && m.seq == msg.seq) {
+                    Some(riga) => *riga = msg,
+                    None => return self,
                 }
+                Rc::new(LogTavolo(new_list))
+            }
+            AzioneLog::Svuota => {
+                let locali = self.0.iter().filter(|m| m.seq.is_none()).cloned().collect();
+                Rc::new(LogTavolo(locali))
+            }
+            AzioneLog::Rivela(seme) => {
+                let tiri = self.0.iter().filter_map(|m| m.tiro.as_deref());
+                let message = match verifica_sessione(&seme, tiri) {
+                    Ok(v) if v.completa() => {
+                        format!("Seme rivelato: {}. Tiri verificati: {}", seme, v.verificati)
+                    }
+                    // chi è entrato tardi non ha le prime pagine del log
+                    Ok(v) => format!(
+                        "Seme rivelato: {}. Tiri verificati: {}, dal numero {} della sessione (i precedenti non sono nel log caricato)",
+                        seme, v.verificati, v.dal_tiro
+                    ),
+                    Err(e) => format!("Seme rivelato: {}. ATTENZIONE, verifica fallita: {}", seme, e),
+                };
+                let mut new_list = self.0.clone();
+                new_list.insert(0, ChatMessage::new("SYSTEM", message));
+                Rc::new(LogTavolo(new_list))
             }
-        } else {
-            break;
         }
     }
-
-    (raises, combo_ottimali)
 }
 
-fn trova_combinazioni(
-    dadi: &[u8],
-    target: i32,
-    start: usize,
-    combo: &mut Vec<u8>,
-    risultato: &mut Vec<Vec<u8>>,
-) {
-    if target <= 0 {
-        risultato.push(combo.clone());
-        return;
-    }
-    if start >= dadi.len() {
-        return;
-    }
-    // includi
-    combo.push(dadi[start]);
-    trova_combinazioni(dadi, target - dadi[start] as i32, start + 1, combo, risultato);
-    combo.pop();
-    // salta
-    trova_combinazioni(dadi, target, start + 1, combo, risultato);
-}
+/* ------------------------- Giocatori presenti ------------------------- */
 
-/// Lancia `num_dadi` e ordina i risultati in decrescente
-fn roll_dice(num_dadi: usize) -> Option<DiceResponse> {
-    if num_dadi == 0 || num_dadi > 100 {
-        return None;
-    }
+/// I giocatori della stanza, in ordine di nome
+#[derive(Default, PartialEq)]
+struct Presenze(Vec<Giocatore>);
 
-    let mut rng = rand::thread_rng();
-    let mut ris: Vec<u8> = (0..num_dadi).map(|_| rng.gen_range(1..=10)).collect();
-    ris.sort_unstable_by(|a, b| b.cmp(a));
-
-    let (r, combo) = massimizza_raises(&ris);
-    Some(DiceResponse {
-        risultati: ris,
-        raises: r,
-        combinazioni: combo,
-    })
+enum AzionePresenze {
+    /// L'elenco completo mandato dal server
+    Tutte(Vec<Giocatore>),
+    /// Una riga del log scritta da `username` all'ora indicata
+    Attivita(String, u64),
 }
 
-/// Lancia `num_dadi`, e se richiesto rilancia un `1`
-fn roll_with_reroll(num_dadi: usize, rilancia_uno: bool) -> Option<RollWithRerollResponse> {
-    if num_dadi == 0 || num_dadi > 100 {
-        return None;
-    }
-
-    let mut rng = rand::thread_rng();
-    let mut ris: Vec<u8> = (0..num_dadi).map(|_| rng.gen_range(1..=10)).collect();
-    let originali = ris.clone();
+impl Reducible for Presenze {
+    type Action = AzionePresenze;
 
-    let mut rilanciato = None;
-    if rilancia_uno {
-        if let Some(idx) = ris.iter().position(|&x| x == 1) {
-            let nuovo = rng.gen_range(1..=10);
-            ris[idx] = nuovo;
-            rilanciato = Some(nuovo);
+    fn reduce(self: Rc<Self>, azione: AzionePresenze) -> Rc<Self> {
+        match azione {
+            AzionePresenze::Tutte(giocatori) => Rc::new(Presenze(giocatori)),
+            AzionePresenze::Attivita(username, timestamp) => {
+                let mut giocatori = self.0.clone();
+                match giocatori.iter_mut().find(|g| g.username == username) {
+                    Some(g) => g.ultima_attivita = g.ultima_attivita.max(timestamp),
+                    None => return self,
+                }
+                Rc::new(Presenze(giocatori))
+            }
         }
     }
+}
 
-    ris.sort_unstable_by(|a, b| b.cmp(a));
-    let (r, combo) = massimizza_raises(&ris);
+/* ------------------------------ WebSocket ------------------------------ */
 
-    Some(RollWithRerollResponse {
-        risultati_originali: originali,
-        rilanciato,
-        risultati_aggiornati: ris,
-        raises: r,
-        combinazioni: combo,
-    })
+/// Apre la connessione verso `url`, col trasporto scelto in `conn`, e la
+/// salva al posto di quella vecchia
+fn apri(
+    url: &str,
+    conn: &RefCell<Connessione>,
+    onmessage: Callback<Result<String, anyhow::Error>>,
+    onnotification: Callback<WebSocketStatus>,
+) -> Result<(), String> {
+    let trasporto = conn.borrow().trasporto;
+    let (task, url) = match trasporto {
+        Trasporto::WebSocket => {
+            let task = WebSocketService::connect_text(url, onmessage, onnotification)
+                .map_err(|e| format!("{:?}", e))?;
+            (Canale::WebSocket(task), url.to_string())
+        }
+        Trasporto::Sse => {
+            let url = indirizzo_sse(url);
+            let canale = CanaleSse::apri(&url, onmessage, onnotification)?;
+            (Canale::Sse(canale), url)
+        }
+    };
+    let mut c = conn.borrow_mut();
+    c.task = Some(task);
+    c.aperta = false;
+    // il battito lo chiede di nuovo il server nel Welcome
+    c.ping_in_volo = None;
+    c.battito_ms = None;
+    console::log_1(&format!("Connessione {:?} avviata verso {}", trasporto, url).into());
+    Ok(())
+}
+
+/// Indirizzo per entrare come `username` nella stanza `codice`, o in quella
+/// comune se il codice è vuoto, col gettone avuto da `server` se c'è
+fn indirizzo(server: &str, codice: &str, username: &str) -> String {
+    let url = match codice.trim() {
+        "" => server.to_string(),
+        codice => con_parametro(server, "room", codice),
+    };
+    let url = con_parametro(&url, "username", username);
+    match gettone_salvato(server) {
+        Some(gettone) => con_parametro(&url, "token", &gettone),
+        None => url,
+    }
 }
 
-/// Rerolla un dado `1`, riordina e ricalcola i raises
-fn reroll_dice(mut ris: Vec<u8>) -> Option<DiceResponse> {
-    let mut rng = rand::thread_rng();
-    if let Some(idx) = ris.iter().position(|&x| x == 1) {
-        ris[idx] = rng.gen_range(1..=10);
-    } else {
-        return None;
+/// Chi vede un tiro nascosto, da scrivere accanto alla sua riga del log
+fn etichetta(visibilita: &Visibilita) -> String {
+    match visibilita {
+        Visibilita::SoloGm => "solo GM".to_string(),
+        Visibilita::SoloIo => "privato".to_string(),
+        Visibilita::Giocatori(giocatori) => format!("per {}", giocatori.join(", ")),
     }
-    ris.sort_unstable_by(|a, b| b.cmp(a));
-    let (r, combo) = massimizza_raises(&ris);
-
-    Some(DiceResponse {
-        risultati: ris,
-        raises: r,
-        combinazioni: combo,
-    })
+}
+
+/// Ora locale di un timestamp del server (millisecondi dal 1970)
+fn ora(timestamp: u64) -> String {
+    js_sys::Date::new(&(timestamp as f64).into())
+        .to_locale_time_string("it-IT")
+        .into()
 }
 
 /* ---------------------- COMPONENTE PRINCIPALE YEW ---------------------- */
@@ -178,45 +229,215 @@ fn app() -> Html {
     // ---------- Stati: dadi ----------
     let num_dadi = use_state(|| 5);
     let rilancia_uno = use_state(|| false);
-    let results = use_state(|| None::<RollWithRerollResponse>);
+    let set_doppi = use_state(|| false);
+    let esplodi = use_state(|| false);
+    let target = use_state(|| TARGET_RAISE);
+    let costo = use_state(|| None::<u32>);
+    let results = use_state(|| None::<RollResult>); // ultimo tiro fatto dal server per noi
     let loading = use_state(|| false);
+    let visibilita = use_state(|| None::<Visibilita>); // chi vede il prossimo tiro; None: tutti
 
     // ---------- Stati: chat e WebSocket ----------
     let username = use_state(|| "".to_string());
-    let chat_messages = use_state(|| Vec::<ChatMessage>::new());
+    let chat_messages = use_reducer(LogTavolo::default);
+    let presenze = use_reducer(Presenze::default);
     let ws_status_text = use_state(|| "Non connesso".to_string());
-    let ws_task = use_mut_ref(|| None::<WebSocketTask>);
-    let is_connected = use_state(|| false); // stato "sono collegato?"
+    let latenza = use_state(|| None::<u32>); // millisecondi dell'ultimo ping andato e tornato
+    let conn = use_mut_ref(Connessione::default);
+    let is_connected = use_state(|| false); // stato "voglio essere collegato?", anche mentre si riconnette
+    let server = use_state(server_iniziale); // indirizzo del WebSocket del tavolo
+    let riconnessione = use_state(|| 0u32); // cambia per far partire una riconnessione
+    let impegno = use_state(|| None::<String>); // hash del seme della sessione
+    let errore_protocollo = use_state(|| None::<String>); // server e client non si capiscono
+    let rifiuto = use_state(|| None::<String>); // perché l'ultimo messaggio non è passato
+    let stanza = use_state(|| None::<Stanza>); // la stanza in cui siamo
+    let codice_stanza = use_state(stanza_iniziale); // codice scritto nel selettore delle stanze
+    let nome_stanza = use_state(String::new); // nome della stanza da creare
+    let password_gm = use_state(String::new); // per creare una stanza o prenderne il ruolo di GM
+    let altre_righe = use_state(|| false); // il server ha righe più vecchie di quelle che abbiamo
+
+    // Opzioni del prossimo tiro, prese dai controlli della pagina
+    let opzioni = RollOptions::new(*num_dadi)
+        .rilancia_uno(*rilancia_uno)
+        .set_doppi(*set_doppi)
+        .esplodi(*esplodi)
+        .target(*target)
+        .costo(*costo);
+
+    // raises attesi rilanciando ciascun dado dell'ultimo tiro, con le sue
+    // regole: costa fino a cento tiri risolti, quindi si calcola solo quando
+    // lo si chiede, e si scarta quando arriva un altro tiro
+    let consiglio = use_state(|| None::<Result<Consiglio, RollError>>);
+    {
+        let consiglio = consiglio.clone();
+        use_effect_with((*results).clone(), move |_| consiglio.set(None));
+    }
+    let chiedi_consiglio = {
+        let consiglio = consiglio.clone();
+        let results = results.clone();
+        Callback::from(move |_| {
+            if let Some(t) = &*results {
+                consiglio.set(Some(consiglia_rilancio(
+                    &t.esito.risultati_aggiornati,
+                    &t.opzioni.regole(),
+                )));
+            }
+        })
+    };
 
     // ---------- onmessage ----------
     let onmessage = {
         let chat_messages = chat_messages.clone();
+        let altre_righe = altre_righe.clone();
+        let presenze = presenze.clone();
+        let results = results.clone();
+        let loading = loading.clone();
+        let impegno = impegno.clone();
+        let errore_protocollo = errore_protocollo.clone();
+        let rifiuto = rifiuto.clone();
+        let stanza = stanza.clone();
+        let codice_stanza = codice_stanza.clone();
+        let conn_stanza = conn.clone();
+        let conn_battito = conn.clone();
+        let latenza = latenza.clone();
+        let conn = conn.clone();
+        let is_connected = is_connected.clone();
+        let username = (*username).clone();
+        let server = (*server).clone();
+        // niente riconnessioni: riprovare non servirebbe
+        let incompatibile = move |testo: String| {
+            console::error_1(&testo.clone().into());
+            let mut c = conn.borrow_mut();
+            c.voluta = false;
+            c.aperta = false;
+            c.coda.scarta();
+            c.task = None;
+            is_connected.set(false);
+            errore_protocollo.set(Some(testo));
+        };
         Callback::from(move |res: Result<String, anyhow::Error>| {
             match res {
                 Ok(txt) => {
-                    match serde_json::from_str::<ServerMessage>(&txt) {
+                    match ServerMessage::da_json(&txt) {
                         Ok(server_msg) => {
                             match server_msg {
+                                ServerMessage::RoomJoined(info) => {
+                                    console::log_1(&format!("(RoomJoined) {} ({})", info.nome, info.codice).into());
+                                    // il log e l'impegno della stanza arrivano subito dopo;
+                                    // l'ultimo tiro vale solo nella stanza in cui è stato fatto
+                                    let vecchia = conn_stanza.borrow_mut().stanza.replace(info.codice.clone());
+                                    if vecchia.as_ref() != Some(&info.codice) {
+                                        results.set(None);
+                                        loading.set(false);
+                                        presenze.dispatch(AzionePresenze::Tutte(Vec::new()));
+                                    }
+                                    if info.codice != STANZA_COMUNE {
+                                        codice_stanza.set(info.codice.clone());
+                                    }
+                                    stanza.set(Some(info));
+                                }
                                 ServerMessage::FullHistory(log_vec) => {
                                     console::log_1(&"(FullHistory) ricevuto".into());
-                                    // invertiamo => il più recente index 0
-                                    let mut reversed = log_vec;
-                                    reversed.reverse();
-                                    chat_messages.set(reversed);
+                                    // una pagina piena: prima ce ne possono essere altre
+                                    altre_righe.set(log_vec.len() >= PAGINA_LOG);
+                                    chat_messages.dispatch(AzioneLog::Storico(log_vec));
                                 }
                                 ServerMessage::System(sys_str) => {
                                     console::log_1(&format!("(System) => {}", sys_str).into());
-                                    let mut new_list = (*chat_messages).clone();
-                                    new_list.insert(0, ChatMessage {
-                                        username: "SYSTEM".to_string(),
-                                        message: sys_str,
-                                    });
-                                    chat_messages.set(new_list);
+                                    // anche i tiri rifiutati arrivano come System
+                                    loading.set(false);
+                                    chat_messages.dispatch(AzioneLog::Aggiungi(ChatMessage::new("SYSTEM", sys_str)));
+                                }
+                                ServerMessage::Presence(giocatori) => {
+                                    presenze.dispatch(AzionePresenze::Tutte(giocatori));
+                                }
+                                ServerMessage::NewMessage(msg) => {
+                                    if let Some(ts) = msg.timestamp {
+                                        presenze.dispatch(AzionePresenze::Attivita(msg.username.clone(), ts));
+                                    }
+                                    chat_messages.dispatch(AzioneLog::Nuove(vec![msg]));
+                                }
+                                ServerMessage::Resumed(righe) => {
+                                    console::log_1(&format!("(Resumed) {} righe", righe.len()).into());
+                                    chat_messages.dispatch(AzioneLog::Nuove(righe));
+                                }
+                                ServerMessage::RollResult(tiro) => {
+                                    // la riga del log arriva con NewMessage
+                                    if tiro.username == username {
+                                        loading.set(false);
+                                        results.set(Some(*tiro));
+                                    }
+                                }
+                                ServerMessage::SeedCommitment(hash) => {
+                                    console::log_1(&format!("(SeedCommitment) => {}", hash).into());
+                                    impegno.set(Some(hash));
+                                }
+                                ServerMessage::SeedReveal(seme) => {
+                                    chat_messages.dispatch(AzioneLog::Rivela(seme));
+                                }
+                                ServerMessage::Welcome(benvenuto) => {
+                                    console::log_1(&format!("(Welcome) {} v{}", benvenuto.server, benvenuto.versione).into());
+                                    conn_battito.borrow_mut().battito_ms = benvenuto.battito_ms;
+                                    if !compatibile(benvenuto.versione) {
+                                        incompatibile(format!(
+                                            "Il server {} parla il protocollo v{}, questo client la v{}",
+                                            benvenuto.server, benvenuto.versione, VERSIONE_PROTOCOLLO
+                                        ));
+                                    }
+                                }
+                                ServerMessage::Incompatible { versione, minima } => {
+                                    incompatibile(format!(
+                                        "Client non compatibile col server: questo client parla il protocollo v{}, il server la v{} (minima v{}). Aggiorna la pagina o scegli un altro server.",
+                                        VERSIONE_PROTOCOLLO, versione, minima
+                                    ));
+                                }
+                                ServerMessage::RoomUpdated(info) => {
+                                    stanza.set(Some(info));
+                                }
+                                ServerMessage::EntryUpdated(msg) => {
+                                    chat_messages.dispatch(AzioneLog::Aggiorna(msg));
+                                }
+                                ServerMessage::OlderHistory { righe, altre } => {
+                                    console::log_1(&format!("(OlderHistory) {} righe", righe.len()).into());
+                                    altre_righe.set(altre);
+                                    chat_messages.dispatch(AzioneLog::Precedenti(righe));
+                                }
+                                ServerMessage::HistoryCleared => {
+                                    altre_righe.set(false);
+                                    chat_messages.dispatch(AzioneLog::Svuota);
+                                    chat_messages.dispatch(AzioneLog::Aggiungi(ChatMessage::new("SYSTEM", "Il GM ha svuotato il log")));
+                                }
+                                ServerMessage::Rejected(motivo) => {
+                                    console::log_1(&format!("(Rejected) {:?}", motivo).into());
+                                    // anche un tiro rifiutato: non arriverà nessun risultato
+                                    loading.set(false);
+                                    rifiuto.set(Some(format!("Messaggio rifiutato dal server: {}", motivo)));
+                                }
+                                ServerMessage::Pong(numero) => {
+                                    let ms = conn_battito.borrow_mut().pong(numero, js_sys::Date::now());
+                                    latenza.set(Some(ms.max(0.0).round() as u32));
+                                }
+                                ServerMessage::SessionToken(gettone) => {
+                                    // tiene il nostro nome anche dopo aver ricaricato la pagina
+                                    salva_gettone(&server, &gettone);
+                                }
+                                ServerMessage::Kicked(giocatore) => {
+                                    // se tocca a noi, la stanza comune arriva subito dopo
+                                    let testo = if giocatore == username {
+                                        "Il GM ti ha mandato via dalla stanza".to_string()
+                                    } else {
+                                        format!("Il GM ha mandato via {}", giocatore)
+                                    };
+                                    chat_messages.dispatch(AzioneLog::Aggiungi(ChatMessage::new("SYSTEM", testo)));
                                 }
                             }
                         }
-                        Err(_) => {
-                            console::log_1(&format!("Msg non parseabile come ServerMessage: {}", txt).into());
+                        // un server più nuovo: quello che non conosciamo lo saltiamo
+                        Err(ErroreMessaggio::Sconosciuto(tipo)) => {
+                            console::log_1(&format!("Messaggio {} ignorato", tipo).into());
+                        }
+                        Err(e) => {
+                            console::error_1(&format!("{}: {}", e, txt).into());
                         }
                     }
                 }
@@ -228,56 +449,164 @@ fn app() -> Html {
     };
 
     // ---------- onnotification ----------
+    // se la connessione cade mentre la vogliamo, si riprova con attese
+    // sempre più lunghe; all'apertura si manda quello che è rimasto in coda
     let onnotification = {
         let ws_status_text = ws_status_text.clone();
-        let is_connected = is_connected.clone();
+        let latenza = latenza.clone();
+        let conn = conn.clone();
+        let riconnessione = riconnessione.clone();
+        let hello = serde_json::to_string(&ClientMessage::Hello(Hello::new(NOME_CLIENT)))
+            .expect("Hello si serializza sempre");
         Callback::from(move |status: WebSocketStatus| {
             match status {
                 WebSocketStatus::Opened => {
-                    ws_status_text.set("Collegato!".into());
-                    is_connected.set(true);
+                    let mut c = conn.borrow_mut();
+                    c.aperta = true;
+                    c.tentativi = 0;
+                    // prima ci si presenta, poi si manda quello in coda
+                    if let Some(task) = c.task.as_mut() {
+                        task.send(hello.clone());
+                    }
+                    c.svuota();
+                    ws_status_text.set(match c.trasporto {
+                        Trasporto::WebSocket => "Collegato!".into(),
+                        Trasporto::Sse => "Collegato! (via SSE, il WebSocket non passa)".into(),
+                    });
                     console::log_1(&"WS aperto".into());
                 }
                 WebSocketStatus::Closed => {
-                    ws_status_text.set("Connessione chiusa".into());
-                    is_connected.set(false);
+                    let mut c = conn.borrow_mut();
+                    c.chiusa();
+                    latenza.set(None);
                     console::log_1(&"WS chiuso".into());
+                    if !c.voluta {
+                        ws_status_text.set("Connessione chiusa".into());
+                        return;
+                    }
+                    // il task chiuso resta lì finché la riconnessione non lo sostituisce
+                    let attesa = ritardo_ms(c.tentativi);
+                    c.tentativi += 1;
+                    c.riconnessioni += 1;
+                    let numero = c.riconnessioni;
+                    let via = match c.trasporto {
+                        Trasporto::WebSocket => "",
+                        Trasporto::Sse => " via SSE",
+                    };
+                    ws_status_text.set(format!(
+                        "Connessione persa, nuovo tentativo{} tra {} s",
+                        via,
+                        attesa / 1000
+                    ));
+                    let riconnessione = riconnessione.clone();
+                    spawn_local(async move {
+                        sleep(Duration::from_millis(attesa.into())).await;
+                        riconnessione.set(numero);
+                    });
                 }
                 WebSocketStatus::Error => {
+                    // dopo un errore arriva sempre anche Closed
                     ws_status_text.set("Errore nella connessione".into());
-                    is_connected.set(false);
                     console::log_1(&"WS errore".into());
                 }
             }
         })
     };
 
+    // ---------- battito ----------
+    // un ping ogni tanto misura la latenza; se il pong non torna prima del
+    // ping successivo la connessione è morta anche se il browser non se ne
+    // accorge, e la si chiude per riconnettersi
+    {
+        let conn = conn.clone();
+        let onnote = onnotification.clone();
+        use_effect_with((), move |_| {
+            spawn_local(async move {
+                loop {
+                    let intervallo = conn.borrow().battito_ms.unwrap_or(BATTITO_PREDEFINITO_MS);
+                    sleep(Duration::from_millis(intervallo.into())).await;
+                    let colpo = conn.borrow_mut().battito(js_sys::Date::now());
+                    match colpo {
+                        Battito::Aspetta => {}
+                        Battito::Ping(numero) => {
+                            // un ping vecchio non serve: niente coda
+                            let ping = serde_json::to_string(&ClientMessage::Ping(numero))
+                                .expect("Ping si serializza sempre");
+                            if let Some(task) = conn.borrow_mut().task.as_mut() {
+                                task.send(ping);
+                            }
+                        }
+                        Battito::Persa => {
+                            console::log_1(&"WS senza risposta, lo chiudo".into());
+                            // scartando il task non arriva più nessuna notifica:
+                            // la chiusura la segnaliamo noi
+                            let task = conn.borrow_mut().task.take();
+                            drop(task);
+                            onnote.emit(WebSocketStatus::Closed);
+                        }
+                    }
+                }
+            });
+        });
+    }
+
+    // ---------- riconnessione ----------
+    {
+        let conn = conn.clone();
+        // si torna nella stanza in cui si era, facendosi mandare solo le
+        // righe che non abbiamo
+        let codice = conn.borrow().stanza.clone().unwrap_or_else(|| (*codice_stanza).clone());
+        let url = indirizzo(&server, &codice, &username);
+        let url = match chat_messages.ultimo_seq() {
+            Some(seq) => con_ripresa(&url, seq),
+            None => url,
+        };
+        let onmsg = onmessage.clone();
+        let onnote = onnotification.clone();
+        let ws_status_text = ws_status_text.clone();
+        use_effect_with(*riconnessione, move |&numero| {
+            let da_riaprire = {
+                let c = conn.borrow();
+                numero > 0 && c.voluta && !c.aperta
+            };
+            if da_riaprire {
+                ws_status_text.set("Riconnessione in corso...".into());
+                if let Err(e) = apri(&url, &conn, onmsg, onnote) {
+                    console::error_1(&format!("Riconnessione WS fallita: {}", e).into());
+                }
+            }
+        });
+    }
+
     // ---------- connect_ws ----------
     let connect_ws = {
-        let ws_task = ws_task.clone();
+        let conn = conn.clone();
+        let server = (*server).clone();
+        let url = indirizzo(&server, &codice_stanza, &username);
         let onmsg = onmessage.clone();
         let onnote = onnotification.clone();
         let is_connected = is_connected.clone();
+        let ws_status_text = ws_status_text.clone();
+        let errore_protocollo = errore_protocollo.clone();
 
         Callback::from(move |_| {
-            if *is_connected {
+            if conn.borrow().voluta {
                 console::log_1(&"Sei già connesso!".into());
                 return;
             }
-            let mut ws_ref = ws_task.borrow_mut();
-            if ws_ref.is_none() {
-                match WebSocketService::connect_text(
-                    "wss://dice-server-qxze.onrender.com/ws/",
-                    onmsg.clone(),
-                    onnote.clone(),
-                ) {
-                    Ok(task) => {
-                        *ws_ref = Some(task);
-                        console::log_1(&"Connessione WebSocket avviata".into());
-                    }
-                    Err(e) => {
-                        console::error_1(&format!("Connessione WS fallita: {:?}", e).into());
-                    }
+            match apri(&url, &conn, onmsg.clone(), onnote.clone()) {
+                Ok(()) => {
+                    let mut c = conn.borrow_mut();
+                    c.voluta = true;
+                    c.tentativi = 0;
+                    c.stanza = None;
+                    is_connected.set(true);
+                    errore_protocollo.set(None);
+                    ws_status_text.set("Connessione in corso...".into());
+                }
+                Err(e) => {
+                    console::error_1(&format!("Connessione WS fallita: {}", e).into());
+                    ws_status_text.set(format!("Indirizzo del server non valido: {}", server));
                 }
             }
         })
@@ -285,154 +614,189 @@ fn app() -> Html {
 
     // ---------- disconnect_ws ----------
     let disconnect_ws = {
-        let ws_task = ws_task.clone();
+        let conn = conn.clone();
         let is_connected = is_connected.clone();
         let ws_status_text = ws_status_text.clone();
+        let stanza = stanza.clone();
+        let presenze = presenze.clone();
 
         Callback::from(move |_| {
+            let mut c = conn.borrow_mut();
+            // niente più riconnessioni, e quello che era in coda non serve più
+            c.voluta = false;
+            c.aperta = false;
+            c.coda.scarta();
+            c.stanza = None;
             // se c'è un Some(WebSocketTask), lo prendo e lo droppo => la connessione si chiude
-            if ws_task.borrow().is_some() {
-                ws_task.borrow_mut().take(); 
-                // L'oggetto WebSocketTask viene droppato qui => disconnessione
-
+            if c.task.take().is_some() {
                 console::log_1(&"WS disconnesso manualmente".into());
-                is_connected.set(false);
-                ws_status_text.set("Connessione chiusa".into());
             }
+            is_connected.set(false);
+            stanza.set(None);
+            presenze.dispatch(AzionePresenze::Tutte(Vec::new()));
+            ws_status_text.set("Connessione chiusa".into());
         })
     };
 
-    // ---------- invio messaggi in chat ----------
+    // ---------- invio messaggi al server ----------
+    // quello che il server rifiuterebbe non parte nemmeno; se il socket non è
+    // aperto il messaggio aspetta in coda la riconnessione
     let send_message = {
-        let ws_task = ws_task.clone();
-        let username = username.clone();
-        Callback::from(move |contenuto: String| {
-            let uname = (*username).clone();
-            if uname.is_empty() {
-                console::log_1(&"Devi inserire uno username prima di inviare messaggi".into());
+        let conn = conn.clone();
+        let rifiuto = rifiuto.clone();
+        let loading = loading.clone();
+        Callback::from(move |msg: ClientMessage| {
+            if let Err(motivo) = msg.valida() {
+                loading.set(false);
+                rifiuto.set(Some(format!("Messaggio non inviato: {}", motivo)));
                 return;
             }
-
-            if let Some(ref mut task) = *ws_task.borrow_mut() {
-                // Costruiamo un ClientMessage
-                let msg = ClientMessage::AddMessage(ChatMessage {
-                    username: uname,
-                    message: contenuto,
-                });
-                if let Ok(json_str) = serde_json::to_string(&msg) {
-                    task.send(json_str);
-                }
+            rifiuto.set(None);
+            if let Ok(json_str) = serde_json::to_string(&msg) {
+                conn.borrow_mut().invia(json_str);
             }
         })
     };
 
     // ---------- reset_app ----------
+    // le opzioni del tiro tornano quelle di RollOptions, così il prossimo
+    // tiro non riusa di nascosto le regole di prima
     let reset_app = {
         let nd = num_dadi.clone();
         let ru = rilancia_uno.clone();
+        let sd = set_doppi.clone();
+        let es = esplodi.clone();
+        let tg = target.clone();
+        let co = costo.clone();
+        let vi = visibilita.clone();
         let rs = results.clone();
         Callback::from(move |_| {
-            nd.set(5);
-            ru.set(false);
+            let predefinite = RollOptions::new(5);
+            nd.set(predefinite.num_dadi);
+            ru.set(predefinite.rilancia_uno);
+            sd.set(predefinite.set_doppi);
+            es.set(predefinite.esplodi);
+            tg.set(predefinite.target);
+            co.set(predefinite.costo);
+            vi.set(None);
             rs.set(None);
         })
     };
 
     // ---------- handle_roll ----------
+    // il tiro lo fa il server: qui mandiamo solo la richiesta e aspettiamo
+    // il RollResult
     let handle_roll = {
-        let results_handle = results.clone();
         let user_handle = username.clone();
         let loading_flag = loading.clone();
         let do_send = send_message.clone();
-        let n_dadi = *num_dadi;
-        let r_auto = *rilancia_uno;
+        let visibilita = (*visibilita).clone();
 
         Callback::from(move |_| {
-            let results2 = results_handle.clone();
             let user2 = (*user_handle).clone();
-            let loading2 = loading_flag.clone();
-            let send2 = do_send.clone();
-
-            spawn_local(async move {
-                if user2.is_empty() {
-                    console::log_1(&"Inserisci username prima di rollare".into());
-                    return;
-                }
-                loading2.set(true);
-
-                let maybe = if r_auto {
-                    roll_with_reroll(n_dadi, true)
-                } else {
-                    roll_dice(n_dadi).map(|dr| RollWithRerollResponse {
-                        risultati_originali: dr.risultati.clone(),
-                        rilanciato: None,
-                        risultati_aggiornati: dr.risultati,
-                        raises: dr.raises,
-                        combinazioni: dr.combinazioni,
-                    })
-                };
-
-                if let Some(res) = maybe {
-                    results2.set(Some(res.clone()));
-
-                    // Includiamo anche la lista di combinazioni
-                    let combos_str = format!("{:?}", res.combinazioni);
-                    let text = format!(
-                        "{} ha tirato {} dadi: {:?} (raises: {}) | Combinazioni: {}",
-                        user2, n_dadi, res.risultati_aggiornati, res.raises, combos_str
-                    );
-                    send2.emit(text);
-                }
-                loading2.set(false);
-            });
+            if user2.is_empty() {
+                console::log_1(&"Inserisci username prima di rollare".into());
+                return;
+            }
+            if let Err(e) = opzioni.validate() {
+                console::log_1(&format!("Tiro non valido: {}", e).into());
+                return;
+            }
+            loading_flag.set(true);
+            do_send.emit(ClientMessage::RollRequest(RollRequest {
+                username: user2,
+                opzioni,
+                nonce: nonce_casuale(),
+                visibilita: visibilita.clone(),
+            }));
         })
     };
 
     // ---------- handle_reroll ----------
     let handle_reroll = {
-        let results_handle = results.clone();
         let user_handle = username.clone();
         let do_send = send_message.clone();
-        let r_auto = *rilancia_uno;
 
-        Callback::from(move |_| {
-            let results2 = results_handle.clone();
+        Callback::from(move |indice: usize| {
             let user2 = (*user_handle).clone();
-            let send2 = do_send.clone();
+            if user2.is_empty() {
+                console::log_1(&"Inserisci username prima di rerollare".into());
+                return;
+            }
+            do_send.emit(ClientMessage::RerollRequest(RerollRequest {
+                username: user2,
+                indice,
+                nonce: nonce_casuale(),
+            }));
+        })
+    };
 
-            spawn_local(async move {
-                if user2.is_empty() {
-                    console::log_1(&"Inserisci username prima di rerollare".into());
-                    return;
-                }
-                if let Some(cur) = &*results2 {
-                    if r_auto {
-                        console::log_1(&"Reroll automatico già attivo, non serve reroll manuale".into());
-                        return;
-                    }
-                    let dati_agg = cur.risultati_aggiornati.clone();
-                    let orig = cur.risultati_originali.clone();
-
-                    if let Some(dr) = reroll_dice(dati_agg) {
-                        let ris_clone = dr.risultati.clone();
-                        let combos_str = format!("{:?}", dr.combinazioni);
-
-                        results2.set(Some(RollWithRerollResponse {
-                            risultati_originali: orig,
-                            rilanciato: ris_clone.last().copied(),
-                            risultati_aggiornati: ris_clone.clone(),
-                            raises: dr.raises,
-                            combinazioni: dr.combinazioni,
-                        }));
-
-                        let text = format!(
-                            "{} ha rerollato un dado da 1. Nuovi risultati: {:?} (raises: {}) | Combinazioni: {}",
-                            user2, ris_clone, dr.raises, combos_str
-                        );
-                        send2.emit(text);
-                    }
-                }
+    // ---------- end_session ----------
+    // il server rivela il seme e i tiri della sessione vengono verificati
+    let end_session = {
+        let do_send = send_message.clone();
+        Callback::from(move |_| do_send.emit(ClientMessage::EndSession))
+    };
+
+    // ---------- stanze ----------
+    let join_room = {
+        let do_send = send_message.clone();
+        let codice = (*codice_stanza).clone();
+        let user = (*username).clone();
+        Callback::from(move |_| {
+            do_send.emit(ClientMessage::JoinRoom {
+                codice: codice.trim().to_string(),
+                username: user.clone(),
+                after: None,
+            })
+        })
+    };
+    let create_room = {
+        let do_send = send_message.clone();
+        let nome = (*nome_stanza).clone();
+        let user = (*username).clone();
+        let nome_stanza = nome_stanza.clone();
+        let password = password_gm.clone();
+        Callback::from(move |_| {
+            do_send.emit(ClientMessage::CreateRoom {
+                nome: nome.clone(),
+                username: user.clone(),
+                password_gm: Some((*password).clone()).filter(|p| !p.is_empty()),
+            });
+            nome_stanza.set(String::new());
+            password.set(String::new());
+        })
+    };
+    let leave_room = {
+        let do_send = send_message.clone();
+        Callback::from(move |_| do_send.emit(ClientMessage::LeaveRoom))
+    };
+
+    // ---------- GM ----------
+    // i permessi li controlla il server: qui si nascondono solo i comandi
+    let sono_gm = stanza
+        .as_ref()
+        .is_some_and(|s| s.gm.as_deref() == Some(username.as_str()));
+    let claim_gm = {
+        let do_send = send_message.clone();
+        let password = password_gm.clone();
+        Callback::from(move |_| {
+            do_send.emit(ClientMessage::ClaimGm {
+                password: (*password).clone(),
             });
+            password.set(String::new());
+        })
+    };
+    let clear_history = send_message.reform(|_| ClientMessage::ClearHistory);
+
+    // le righe prima della più vecchia che abbiamo, una pagina alla volta
+    let load_older = {
+        let chat_messages = chat_messages.clone();
+        let send_message = send_message.clone();
+        Callback::from(move |_| {
+            if let Some(before) = chat_messages.primo_seq() {
+                send_message.emit(ClientMessage::LoadOlder { before, limit: None });
+            }
         })
     };
 
@@ -461,11 +825,46 @@ fn app() -> Html {
                                     }
                                 })}
                             />
+                            <label>{ "Stanza (codice, vuoto per il tavolo comune)" }</label>
+                            <input
+                                type="text"
+                                value={(*codice_stanza).clone()}
+                                oninput={Callback::from({
+                                    let cs = codice_stanza.clone();
+                                    move |e: InputEvent| {
+                                        cs.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                    }
+                                })}
+                            />
+                            <label>{ "Server" }</label>
+                            <input
+                                type="text"
+                                value={(*server).clone()}
+                                oninput={Callback::from({
+                                    let sv = server.clone();
+                                    move |e: InputEvent| {
+                                        sv.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                    }
+                                })}
+                            />
                             <button class="roll-button"
                                 onclick={connect_ws}
-                                disabled={(*username).is_empty()}>
+                                disabled={valida_username(&username).is_err() || (*server).is_empty()}>
                                 { "Connetti" }
                             </button>
+                            {
+                                match valida_username(&username) {
+                                    Err(motivo) if !username.is_empty() => html! { <p class="errore">{ motivo.to_string() }</p> },
+                                    _ => html! {},
+                                }
+                            }
+                            <p>{ (*ws_status_text).clone() }</p>
+                            {
+                                match &*errore_protocollo {
+                                    Some(errore) => html! { <p class="errore">{ errore.clone() }</p> },
+                                    None => html! {},
+                                }
+                            }
                         </div>
                     }
                 } else {
@@ -473,9 +872,187 @@ fn app() -> Html {
                         <div class="input-container">
                             <label>{ "Sei connesso come:" }</label>
                             <p>{ (*username).clone() }</p>
+                            <p>
+                                { format!("{} ({})", *ws_status_text, *server) }
+                                {
+                                    match *latenza {
+                                        Some(ms) => html! { <span class="latenza">{ format!(" · {} ms", ms) }</span> },
+                                        None => html! {},
+                                    }
+                                }
+                            </p>
+                            {
+                                match conn.borrow().coda.in_attesa() {
+                                    0 => html! {},
+                                    n => html! { <p>{ format!("Messaggi in attesa di invio: {}", n) }</p> },
+                                }
+                            }
+                            {
+                                match &*rifiuto {
+                                    Some(motivo) => html! { <p class="errore">{ motivo.clone() }</p> },
+                                    None => html! {},
+                                }
+                            }
                             <button class="reset-button" onclick={disconnect_ws}>
                                 { "Disconnetti" }
                             </button>
+                            {
+                                match &*stanza {
+                                    Some(s) => html! {
+                                        <div class="stanza">
+                                            <p>{ format!("Stanza: {} (codice {}){}", s.nome, s.codice, if s.bloccata { ", chiusa" } else { "" }) }</p>
+                                            {
+                                                match &s.gm {
+                                                    Some(gm) => html! { <p>{ format!("GM: {}", gm) }</p> },
+                                                    None => html! {},
+                                                }
+                                            }
+                                            {
+                                                if sono_gm {
+                                                    let bloccata = s.bloccata;
+                                                    html! {
+                                                        <div class="gm">
+                                                            <button class="reset-button"
+                                                                onclick={send_message.reform(move |_| ClientMessage::LockRoom(!bloccata))}>
+                                                                { if bloccata { "Riapri la stanza" } else { "Chiudi la stanza" } }
+                                                            </button>
+                                                            <button class="reset-button" onclick={clear_history}>
+                                                                { "Svuota il log" }
+                                                            </button>
+                                                        </div>
+                                                    }
+                                                } else if s.codice != STANZA_COMUNE {
+                                                    html! {
+                                                        <div class="gm">
+                                                            <input
+                                                                type="password"
+                                                                placeholder="Password del GM"
+                                                                value={(*password_gm).clone()}
+                                                                oninput={Callback::from({
+                                                                    let pg = password_gm.clone();
+                                                                    move |e: InputEvent| {
+                                                                        pg.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                                                    }
+                                                                })}
+                                                            />
+                                                            <button class="roll-button"
+                                                                onclick={claim_gm}
+                                                                disabled={password_gm.is_empty()}>
+                                                                { "Diventa GM" }
+                                                            </button>
+                                                        </div>
+                                                    }
+                                                } else {
+                                                    html! {}
+                                                }
+                                            }
+                                            <label>{ "Entra in un'altra stanza" }</label>
+                                            <input
+                                                type="text"
+                                                placeholder="Codice"
+                                                value={(*codice_stanza).clone()}
+                                                oninput={Callback::from({
+                                                    let cs = codice_stanza.clone();
+                                                    move |e: InputEvent| {
+                                                        cs.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                                    }
+                                                })}
+                                            />
+                                            <button class="roll-button"
+                                                onclick={join_room}
+                                                disabled={codice_stanza.trim().is_empty() || codice_stanza.trim().eq_ignore_ascii_case(&s.codice)}>
+                                                { "Entra" }
+                                            </button>
+                                            <label>{ "Crea una stanza" }</label>
+                                            <input
+                                                type="text"
+                                                placeholder="Nome della campagna"
+                                                value={(*nome_stanza).clone()}
+                                                oninput={Callback::from({
+                                                    let ns = nome_stanza.clone();
+                                                    move |e: InputEvent| {
+                                                        ns.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                                    }
+                                                })}
+                                            />
+                                            <input
+                                                type="password"
+                                                placeholder="Password del GM (facoltativa)"
+                                                value={(*password_gm).clone()}
+                                                oninput={Callback::from({
+                                                    let pg = password_gm.clone();
+                                                    move |e: InputEvent| {
+                                                        pg.set(e.target_unchecked_into::<HtmlInputElement>().value());
+                                                    }
+                                                })}
+                                            />
+                                            <button class="roll-button" onclick={create_room}>
+                                                { "Crea stanza" }
+                                            </button>
+                                            {
+                                                if s.codice != STANZA_COMUNE {
+                                                    html! {
+                                                        <button class="reset-button" onclick={leave_room}>
+                                                            { "Torna al tavolo comune" }
+                                                        </button>
+                                                    }
+                                                } else {
+                                                    html! {}
+                                                }
+                                            }
+                                        </div>
+                                    },
+                                    None => html! {},
+                                }
+                            }
+                            <div class="stanza">
+                                <label>{ "Al tavolo" }</label>
+                                <ul class="presenze">
+                                {
+                                    for presenze.0.iter().map(|g| {
+                                        let tu = if g.username == *username { " (tu)" } else { "" };
+                                        html! {
+                                            <li class={if g.collegato { "collegato" } else { "scollegato" }}>
+                                                { format!("{} {}{}", if g.collegato { "●" } else { "○" }, g.username, tu) }
+                                                <span class="ora">{ format!(" ultima attività {}", ora(g.ultima_attivita)) }</span>
+                                                {
+                                                    if sono_gm && g.username != *username {
+                                                        let giocatore = g.username.clone();
+                                                        html! {
+                                                            <button class="modera"
+                                                                onclick={send_message.reform(move |_| ClientMessage::Kick { username: giocatore.clone() })}>
+                                                                { "Manda via" }
+                                                            </button>
+                                                        }
+                                                    } else {
+                                                        html! {}
+                                                    }
+                                                }
+                                            </li>
+                                        }
+                                    })
+                                }
+                                </ul>
+                            </div>
+                            {
+                                if let Some(hash) = &*impegno {
+                                    // con un GM la sessione la chiude solo lui
+                                    let puo_chiudere = sono_gm
+                                        || stanza.as_ref().is_none_or(|s| s.gm.is_none());
+                                    html! {
+                                        <>
+                                            <p class="impegno">{ format!("Impegno del server: {}", hash) }</p>
+                                            if puo_chiudere {
+                                                <button class="reset-button" onclick={end_session}>
+                                                    { "Chiudi la sessione e verifica i tiri" }
+                                                </button>
+                                            }
+                                        </>
+                                    }
+                                } else {
+                                    html! {}
+                                }
+                            }
                         </div>
                     }
                 }
@@ -499,6 +1076,44 @@ fn app() -> Html {
                 />
             </div>
 
+            <div class="input-container">
+                <label>{ "Target di un raise" }</label>
+                <input
+                    type="number"
+                    min="1"
+                    max={opzioni.regole().max_target().to_string()}
+                    value={target.to_string()}
+                    oninput={Callback::from({
+                        let tg = target.clone();
+                        move |e: InputEvent| {
+                            if let Ok(value) = e.target_unchecked_into::<HtmlInputElement>()
+                                .value()
+                                .parse::<u32>() {
+                                tg.set(value);
+                            }
+                        }
+                    })}
+                />
+            </div>
+
+            <div class="input-container">
+                <label>{ "Raises per azione (vuoto se non c'è un costo)" }</label>
+                <input
+                    type="number"
+                    min="1"
+                    value={costo.map(|c| c.to_string()).unwrap_or_default()}
+                    oninput={Callback::from({
+                        let co = costo.clone();
+                        move |e: InputEvent| {
+                            co.set(e.target_unchecked_into::<HtmlInputElement>()
+                                .value()
+                                .parse::<u32>()
+                                .ok());
+                        }
+                    })}
+                />
+            </div>
+
             <div class="checkbox-container">
                 <label>
                     <input
@@ -515,6 +1130,96 @@ fn app() -> Html {
                 </label>
             </div>
 
+            <div class="checkbox-container">
+                <label>
+                    <input
+                        type="checkbox"
+                        checked={*set_doppi}
+                        onchange={Callback::from({
+                            let sd = set_doppi.clone();
+                            move |_| {
+                                sd.set(!*sd)
+                            }
+                        })}
+                    />
+                    { format!(" Skill a rank 4: i set da {} valgono due raises", opzioni.regole().soglia_doppia()) }
+                </label>
+            </div>
+
+            <div class="checkbox-container">
+                <label>
+                    <input
+                        type="checkbox"
+                        checked={*esplodi}
+                        onchange={Callback::from({
+                            let es = esplodi.clone();
+                            move |_| {
+                                es.set(!*es)
+                            }
+                        })}
+                    />
+                    { " Skill a rank 5: i 10 esplodono" }
+                </label>
+            </div>
+
+            <div class="input-container">
+                <label>{ "Chi vede il tiro" }</label>
+                <select
+                    onchange={Callback::from({
+                        let vi = visibilita.clone();
+                        move |e: Event| {
+                            vi.set(match e.target_unchecked_into::<HtmlSelectElement>().value().as_str() {
+                                "gm" => Some(Visibilita::SoloGm),
+                                "io" => Some(Visibilita::SoloIo),
+                                "giocatori" => Some(Visibilita::Giocatori(Vec::new())),
+                                _ => None,
+                            });
+                        }
+                    })}
+                >
+                    <option value="tutti" selected={visibilita.is_none()}>{ "Tutti" }</option>
+                    <option value="gm" selected={*visibilita == Some(Visibilita::SoloGm)}>{ "Solo il GM" }</option>
+                    <option value="io" selected={*visibilita == Some(Visibilita::SoloIo)}>{ "Solo io" }</option>
+                    <option value="giocatori" selected={matches!(*visibilita, Some(Visibilita::Giocatori(_)))}>{ "Giocatori scelti" }</option>
+                </select>
+                {
+                    if let Some(Visibilita::Giocatori(scelti)) = &*visibilita {
+                        html! {
+                            <div class="checkbox-container">
+                            {
+                                for presenze.0.iter().filter(|g| g.username != *username).map(|g| {
+                                    let giocatore = g.username.clone();
+                                    let scelto = scelti.contains(&giocatore);
+                                    let scelti = scelti.clone();
+                                    let vi = visibilita.clone();
+                                    html! {
+                                        <label>
+                                            <input
+                                                type="checkbox"
+                                                checked={scelto}
+                                                onchange={Callback::from(move |_| {
+                                                    let mut scelti = scelti.clone();
+                                                    if scelto {
+                                                        scelti.retain(|s| *s != giocatore);
+                                                    } else {
+                                                        scelti.push(giocatore.clone());
+                                                    }
+                                                    vi.set(Some(Visibilita::Giocatori(scelti)));
+                                                })}
+                                            />
+                                            { format!(" {}", g.username) }
+                                        </label>
+                                    }
+                                })
+                            }
+                            </div>
+                        }
+                    } else {
+                        html! {}
+                    }
+                }
+            </div>
+
             // Pulsanti di "roll" e "reset"
             <button
                 class="roll-button"
@@ -532,7 +1237,16 @@ fn app() -> Html {
 
             // Se abbiamo un risultato, mostriamo i dettagli
             {
-                if let Some(r) = &*results {
+                if let Some(tiro) = &*results {
+                    let r = &tiro.esito;
+                    let rilanciabile = !tiro.opzioni.rilancia_uno
+                        && r.rilanciato.is_none()
+                        && !(*username).is_empty()
+                        && connected;
+                    let calcolato = match &*consiglio {
+                        Some(Ok(c)) => Some(c),
+                        _ => None,
+                    };
                     html! {
                         <div class="results">
                             <h2>{ "Ultimo Tiro" }</h2>
@@ -540,28 +1254,88 @@ fn app() -> Html {
                             {
                                 if let Some(ril) = r.rilanciato {
                                     html! {
-                                        <>
-                                            <p>{ format!("Esito dell'ultimo rilancio: {}", ril) }</p>
-                                            <p>{ format!("DADI AGGIORNATI: {:?}", r.risultati_aggiornati) }</p>
-                                        </>
+                                        <p>{ format!("Esito dell'ultimo rilancio: {}", ril) }</p>
+                                    }
+                                } else {
+                                    html! {}
+                                }
+                            }
+                            {
+                                if !r.esplosi.is_empty() {
+                                    html! {
+                                        <p>{ format!("DADI ESPLOSI: {:?}", r.esplosi) }</p>
+                                    }
+                                } else {
+                                    html! {}
+                                }
+                            }
+                            {
+                                if r.rilanciato.is_some() || !r.esplosi.is_empty() {
+                                    html! {
+                                        <p>{ format!("DADI AGGIORNATI: {:?}", r.risultati_aggiornati) }</p>
                                     }
                                 } else {
                                     html! {}
                                 }
                             }
                             <p class="success-count">{ format!("Successi (Raises): {}", r.raises) }</p>
+                            {
+                                if let Some(azioni) = r.azioni {
+                                    html! {
+                                        <p>{ format!("Azioni che puoi pagare: {}", azioni) }</p>
+                                    }
+                                } else {
+                                    html! {}
+                                }
+                            }
                             <p>{ format!("Combinazioni: {:?}", r.combinazioni) }</p>
+                            <p>{ format!("Dadi non usati: {:?}", r.avanzi) }</p>
 
-                            <button
-                                class="reroll-button"
-                                onclick={handle_reroll}
-                                disabled={*rilancia_uno
-                                    || !r.risultati_aggiornati.contains(&1)
-                                    || (*username).is_empty()
-                                    || !connected}
-                            >
-                                { "Ritira un dado da 1" }
-                            </button>
+                            <h3>{ "Skill a rank 3: scegli il dado da rilanciare" }</h3>
+                            {
+                                match &*consiglio {
+                                    Some(Ok(c)) => match c.migliore {
+                                        Some(i) => html! {
+                                            <p>{ format!(
+                                                "Consiglio: rilancia un {} (raises attesi: {:.2})",
+                                                r.risultati_aggiornati[i], c.attesi[i]
+                                            ) }</p>
+                                        },
+                                        None => html! {
+                                            <p>{ "Consiglio: non conviene rilanciare" }</p>
+                                        },
+                                    },
+                                    Some(Err(e)) => html! {
+                                        <p>{ format!("Nessun consiglio: {}", e) }</p>
+                                    },
+                                    None if rilanciabile => html! {
+                                        <button onclick={chiedi_consiglio.clone()}>
+                                            { "Consiglia il dado da rilanciare" }
+                                        </button>
+                                    },
+                                    None => html! {},
+                                }
+                            }
+                            <div class="dadi">
+                            {
+                                for r.risultati_aggiornati.iter().enumerate().map(|(i, d)| {
+                                    let (atteso, consigliato) = match calcolato {
+                                        Some(c) => (c.attesi.get(i).copied(), c.migliore == Some(i)),
+                                        None => (None, false),
+                                    };
+                                    html! {
+                                        <button
+                                            class={classes!("dado", consigliato.then_some("consigliato"))}
+                                            title={atteso.map(|a| format!("Raises attesi rilanciandolo: {:.2}", a))}
+                                            onclick={handle_reroll.reform(move |_| i)}
+                                            disabled={!rilanciabile}
+                                        >
+                                            { d }
+                                        </button>
+                                    }
+                                })
+                            }
+                            </div>
                         </div>
                     }
                 } else {
@@ -574,14 +1348,54 @@ fn app() -> Html {
                 <h2>{ "Log dei tiri:" }</h2>
                 <ul>
                 {
-                    // I messaggi più recenti in index=0 => li stampiamo in quell'ordine
-                    for (*chat_messages).iter().enumerate().map(|(_i, msg)| {
+                    // I messaggi più recenti in index=0 => li stampiamo in quell'ordine;
+                    // le righe eliminate dal GM restano solo per la verifica dei tiri
+                    for chat_messages.0.iter().filter(|msg| msg.moderata != Some(Moderazione::Eliminata)).map(|msg| {
+                        let annullata = msg.moderata == Some(Moderazione::Annullata);
+                        // i tiri nascosti, che li vediamo o no
+                        let nascosto = msg.tiro.as_deref().and_then(|t| t.visibilita.as_ref());
                         html! {
-                            <li>{ &msg.message }</li>
+                            <li class={classes!(annullata.then_some("annullata"), nascosto.map(|_| "nascosta"))}>
+                                {
+                                    match msg.timestamp {
+                                        Some(ts) => html! { <span class="ora">{ format!("[{}] ", ora(ts)) }</span> },
+                                        None => html! {},
+                                    }
+                                }
+                                {
+                                    match nascosto {
+                                        Some(v) => html! { <span class="visibilita">{ format!("({}) ", etichetta(v)) }</span> },
+                                        None => html! {},
+                                    }
+                                }
+                                { &msg.message }
+                                {
+                                    match msg.seq.filter(|_| sono_gm) {
+                                        Some(seq) => html! {
+                                            <>
+                                                if !annullata {
+                                                    <button class="modera" onclick={send_message.reform(move |_| ClientMessage::VoidEntry(seq))}>
+                                                        { "Annulla" }
+                                                    </button>
+                                                }
+                                                <button class="modera" onclick={send_message.reform(move |_| ClientMessage::DeleteEntry(seq))}>
+                                                    { "Elimina" }
+                                                </button>
+                                            </>
+                                        },
+                                        None => html! {},
+                                    }
+                                }
+                            </li>
                         }
                     })
                 }
                 </ul>
+                if *altre_righe {
+                    <button class="reset-button" onclick={load_older} disabled={!connected}>
+                        { "Carica i messaggi precedenti" }
+                    </button>
+                }
             </div>
         </div>
     }
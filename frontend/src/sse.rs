@@ -0,0 +1,180 @@
+//! Trasporto di riserva quando il WebSocket non passa: i messaggi del
+//! server arrivano come eventi SSE, quelli del client partono come POST.
+//!
+//! Il primo evento, `sessione`, dice dove mandare le POST: fino ad allora
+//! la connessione non è aperta. Le POST partono una alla volta, così il
+//! server riceve i messaggi nell'ordine in cui sono stati mandati.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use gloo_net::http::Request;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{console, EventSource, MessageEvent};
+use yew::platform::spawn_local;
+use yew::Callback;
+use yew_websocket::websocket::WebSocketStatus;
+
+/// Indirizzo degli eventi SSE del server col WebSocket in `url`, con gli
+/// stessi parametri: `ws://host/ws/?room=X` diventa `http://host/sse?room=X`
+pub fn indirizzo_sse(url: &str) -> String {
+    let (base, parametri) = match url.split_once('?') {
+        Some((base, parametri)) => (base, Some(parametri)),
+        None => (url, None),
+    };
+    let base = if let Some(resto) = base.strip_prefix("wss://") {
+        format!("https://{}", resto)
+    } else if let Some(resto) = base.strip_prefix("ws://") {
+        format!("http://{}", resto)
+    } else {
+        base.to_string()
+    };
+    let base = base.trim_end_matches('/');
+    let base = base.strip_suffix("/ws").unwrap_or(base);
+    match parametri {
+        Some(parametri) => format!("{}/sse?{}", base, parametri),
+        None => format!("{}/sse", base),
+    }
+}
+
+/// Dove e cosa mandare con le POST
+struct Invio {
+    /// `.../sse`, a cui si aggiunge il codice della sessione
+    base: String,
+    sessione: RefCell<Option<String>>,
+    coda: RefCell<VecDeque<String>>,
+    /// C'è già chi manda la coda
+    in_corso: Cell<bool>,
+}
+
+/// Una connessione SSE aperta: si chiude quando viene scartata
+pub struct CanaleSse {
+    sorgente: EventSource,
+    invio: Rc<Invio>,
+    // le callback della sorgente, che devono vivere quanto lei
+    _su_messaggio: Closure<dyn FnMut(MessageEvent)>,
+    _su_sessione: Closure<dyn FnMut(MessageEvent)>,
+    _su_errore: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl CanaleSse {
+    /// Apre gli eventi di `url`; le notifiche sono quelle del WebSocket. Al
+    /// primo errore la connessione si chiude: riaprirla, con i parametri
+    /// aggiornati, spetta a chi la usa
+    pub fn apri(
+        url: &str,
+        onmessage: Callback<Result<String, anyhow::Error>>,
+        onnotification: Callback<WebSocketStatus>,
+    ) -> Result<Self, String> {
+        let sorgente = EventSource::new(url).map_err(|e| format!("{:?}", e))?;
+        let invio = Rc::new(Invio {
+            base: url.split('?').next().unwrap_or(url).to_string(),
+            sessione: RefCell::new(None),
+            coda: RefCell::new(VecDeque::new()),
+            in_corso: Cell::new(false),
+        });
+
+        let su_messaggio = Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
+            if let Some(testo) = e.data().as_string() {
+                onmessage.emit(Ok(testo));
+            }
+        });
+        let su_sessione = {
+            let invio = invio.clone();
+            let onnotification = onnotification.clone();
+            Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
+                *invio.sessione.borrow_mut() = e.data().as_string();
+                onnotification.emit(WebSocketStatus::Opened);
+            })
+        };
+        let su_errore = {
+            let sorgente = sorgente.clone();
+            Closure::<dyn FnMut(web_sys::Event)>::new(move |_| {
+                // niente riconnessioni automatiche del browser, come col WebSocket
+                sorgente.close();
+                onnotification.emit(WebSocketStatus::Error);
+                onnotification.emit(WebSocketStatus::Closed);
+            })
+        };
+        sorgente.set_onmessage(Some(su_messaggio.as_ref().unchecked_ref()));
+        sorgente.set_onerror(Some(su_errore.as_ref().unchecked_ref()));
+        sorgente
+            .add_event_listener_with_callback("sessione", su_sessione.as_ref().unchecked_ref())
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(CanaleSse {
+            sorgente,
+            invio,
+            _su_messaggio: su_messaggio,
+            _su_sessione: su_sessione,
+            _su_errore: su_errore,
+        })
+    }
+
+    /// Manda `msg` con una POST, dopo quelle già partite
+    pub fn send(&mut self, msg: String) {
+        self.invio.coda.borrow_mut().push_back(msg);
+        if !self.invio.in_corso.replace(true) {
+            spawn_local(svuota(self.invio.clone()));
+        }
+    }
+}
+
+impl Drop for CanaleSse {
+    fn drop(&mut self) {
+        // chiusa, la sorgente non chiama più le callback
+        self.sorgente.close();
+    }
+}
+
+/// Manda i messaggi in coda uno alla volta. Una POST persa non si ripete:
+/// se la sessione è finita, se ne accorgono anche gli eventi
+async fn svuota(invio: Rc<Invio>) {
+    loop {
+        // senza sessione i messaggi aspettano il prossimo invio
+        let Some(sessione) = invio.sessione.borrow().clone() else {
+            break;
+        };
+        let Some(msg) = invio.coda.borrow_mut().pop_front() else {
+            break;
+        };
+        let url = format!("{}/{}", invio.base, sessione);
+        let risposta = match Request::post(&url)
+            .header("Content-Type", "text/plain")
+            .body(msg)
+        {
+            Ok(richiesta) => richiesta.send().await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match risposta {
+            Ok(r) if !r.ok() => {
+                console::error_1(&format!("POST {}: {}", url, r.status()).into());
+            }
+            Ok(_) => {}
+            Err(e) => console::error_1(&format!("POST {}: {}", url, e).into()),
+        }
+    }
+    invio.in_corso.set(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indirizzi_degli_eventi() {
+        assert_eq!(
+            indirizzo_sse("ws://localhost:8000/ws/"),
+            "http://localhost:8000/sse"
+        );
+        assert_eq!(
+            indirizzo_sse("wss://tavolo.example/ws/?room=ABC234&username=Anna"),
+            "https://tavolo.example/sse?room=ABC234&username=Anna"
+        );
+        assert_eq!(
+            indirizzo_sse("wss://tavolo.example/ws"),
+            "https://tavolo.example/sse"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+// Generato da seven_seas_protocol: non modificare a mano.
+
+export type ClientMessage = { "type": "Hello", "data": Hello } | { "type": "AddMessage", "data": ChatMessage } | { "type": "RollRequest", "data": RollRequest } | { "type": "RerollRequest", "data": RerollRequest } | { "type": "EndSession" } | { "type": "Resume", "data": number } | { "type": "CreateRoom", "data": { nome: string, username: string, 
+/**
+ * Chi crea la stanza ne è il GM; con una password anche un altro
+ * può prendere il ruolo con [`ClientMessage::ClaimGm`]
+ */
+password_gm?: string, } } | { "type": "JoinRoom", "data": { codice: string, username: string, after?: number, } } | { "type": "LeaveRoom" } | { "type": "ClaimGm", "data": { password: string, } } | { "type": "Kick", "data": { username: string, } } | { "type": "VoidEntry", "data": number } | { "type": "DeleteEntry", "data": number } | { "type": "ClearHistory" } | { "type": "LockRoom", "data": boolean } | { "type": "LoadOlder", "data": { before: number, limit?: number, } } | { "type": "Ping", "data": number };
+
+export type ServerMessage = { "type": "RoomJoined", "data": Stanza } | { "type": "Presence", "data": Array<Giocatore> } | { "type": "FullHistory", "data": Array<ChatMessage> } | { "type": "NewMessage", "data": ChatMessage } | { "type": "Resumed", "data": Array<ChatMessage> } | { "type": "System", "data": string } | { "type": "RollResult", "data": RollResult } | { "type": "SeedCommitment", "data": string } | { "type": "SeedReveal", "data": string } | { "type": "Welcome", "data": Welcome } | { "type": "Incompatible", "data": { versione: number, minima: number, } } | { "type": "RoomUpdated", "data": Stanza } | { "type": "EntryUpdated", "data": ChatMessage } | { "type": "HistoryCleared" } | { "type": "Kicked", "data": string } | { "type": "OlderHistory", "data": { righe: Array<ChatMessage>, altre: boolean, } } | { "type": "SessionToken", "data": string } | { "type": "Rejected", "data": Rifiuto } | { "type": "Pong", "data": number };
+
+export type Hello = { versione: number, 
+/**
+ * Nome e versione del programma, es. "seven_seas_successes/0.1.0"
+ */
+client: string, funzionalita: Array<string>, };
+
+export type Welcome = { versione: number, server: string, funzionalita: Array<string>, 
+/**
+ * Ogni quanto il client deve mandare un [`ClientMessage::Ping`]
+ */
+battito_ms?: number, };
+
+export type Stanza = { 
+/**
+ * Codice da condividere per farci entrare gli altri giocatori
+ */
+codice: string, nome: string, 
+/**
+ * Il giocatore che fa da GM, se qualcuno ha preso il ruolo
+ */
+gm?: string, 
+/**
+ * Chiusa dal GM: non entra nessun altro
+ */
+bloccata: boolean, };
+
+export type Giocatore = { username: string, 
+/**
+ * Ha almeno una connessione aperta nella stanza
+ */
+collegato: boolean, 
+/**
+ * Millisecondi dal 1970 dell'ultima volta che ha scritto, tirato,
+ * è entrato o è uscito
+ */
+ultima_attivita: number, };
+
+export type ChatMessage = { username: string, message: string, 
+/**
+ * Posizione nel log, da 1; la assegna il server
+ */
+seq?: number, 
+/**
+ * Millisecondi dal 1970 (Unix epoch); li assegna il server
+ */
+timestamp?: number, 
+/**
+ * Il tiro descritto dal messaggio. Lo imposta solo il server: nei
+ * messaggi che arrivano dai client va ignorato
+ */
+tiro?: RollResult, 
+/**
+ * Cosa ne ha fatto il GM, se l'ha toccata
+ */
+moderata?: Moderazione, };
+
+export type Moderazione = "Annullata" | "Eliminata";
+
+export type Rifiuto = { "MessaggioTroppoGrande": { massimo: number, } } | { "TroppoLungo": { campo: string, massimo: number, } } | { "UsernameNonValido": string } | { "TroppiDadi": { massimo: number, } } | { "LimiteSuperato": { campo: string, massimo: number, } } | { "TroppeRichieste": { attesa_ms: number, } };
+
+export type RollRequest = { username: string, opzioni: RollOptions, 
+/**
+ * Scelto dal giocatore e mescolato al seme del server
+ */
+nonce: string, 
+/**
+ * Chi vede il tiro; senza, tutta la stanza
+ */
+visibilita?: Visibilita, };
+
+export type RerollRequest = { username: string, 
+/**
+ * Posizione del dado in `risultati_aggiornati`
+ */
+indice: number, nonce: string, };
+
+export type RollResult = { username: string, opzioni: RollOptions, esito: RollWithRerollResponse, 
+/**
+ * Nei rilanci, il valore del dado prima di essere rilanciato
+ */
+rilanciato_da?: number, 
+/**
+ * Per rifare il tiro quando il server rivelerà il seme della sessione
+ */
+prova?: Prova, 
+/**
+ * Quella della richiesta; i rilanci tengono quella del tiro
+ */
+visibilita?: Visibilita, 
+/**
+ * Il tiro è nascosto a chi lo riceve: dadi, opzioni e nonce non ci
+ * sono, della prova resta solo il numero per contarlo nella verifica
+ * della sessione
+ */
+coperto: boolean, };
+
+export type Visibilita = "SoloGm" | "SoloIo" | { "Giocatori": Array<string> };
+
+export type RollOptions = { num_dadi: number, rilancia_uno: boolean, 
+/**
+ * Skill a rank 4: i set da 15 valgono due raises
+ */
+set_doppi: boolean, 
+/**
+ * Skill a rank 5: ogni 10 aggiunge un dado al tiro
+ */
+esplodi: boolean, 
+/**
+ * Somma che un set deve raggiungere per valere un raise
+ */
+target: number, 
+/**
+ * Raises che costa ogni azione (Pressure, regole della casa)
+ */
+costo: number | null, };
+
+export type RollWithRerollResponse = { risultati_originali: Array<number>, rilanciato: number | null, risultati_aggiornati: Array<number>, raises: number, combinazioni: Array<Array<number>>, 
+/**
+ * Dadi che non fanno parte di nessun set
+ */
+avanzi: Array<number>, 
+/**
+ * Dadi aggiunti dai 10 esplosi, nell'ordine in cui sono usciti
+ */
+esplosi: Array<number>, 
+/**
+ * Azioni che si possono pagare coi raises, se il tiro ha un costo
+ */
+azioni: number | null, };
+
+export type Prova = { 
+/**
+ * Impegno della sessione in cui è stato fatto il tiro
+ */
+impegno: string, 
+/**
+ * Numero del tiro nella sessione, da 0
+ */
+contatore: number, 
+/**
+ * Nonce scelto dal giocatore
+ */
+nonce: string, };
+
+export type PaginaStorico = { righe: Array<ChatMessage>, 
+/**
+ * Ci sono righe ancora precedenti, da chiedere con `before`
+ */
+altre: boolean, };
+
+export type ErroreApi = { errore: string, };
@@ -0,0 +1,43 @@
+//! Tipi dell'API REST del server, per chi vuole solo tirare dadi o leggere
+//! il log di una stanza senza tenere aperta una connessione.
+//!
+//! - `POST /api/roll` con delle [`RollOptions`] in JSON fa un tiro e
+//!   risponde con il [`RollWithRerollResponse`]: dadi, raises e set;
+//! - `GET /api/rooms/<codice>` descrive una [`Stanza`](crate::Stanza);
+//! - `GET /api/rooms/<codice>/history?before=N&limit=M` restituisce una
+//!   [`PaginaStorico`] del log, con i tiri nascosti coperti.
+//!
+//! Gli errori arrivano come [`ErroreApi`]. La descrizione completa è il
+//! documento OpenAPI di [`crate::schema::openapi`], servito anche su
+//! `GET /api/openapi.json`.
+//!
+//! [`RollOptions`]: crate::RollOptions
+//! [`RollWithRerollResponse`]: crate::RollWithRerollResponse
+
+use serde::{Deserialize, Serialize};
+
+use crate::messaggi::ChatMessage;
+
+/// Una pagina del log di una stanza, dalla riga più vecchia
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct PaginaStorico {
+    pub righe: Vec<ChatMessage>,
+    /// Ci sono righe ancora precedenti, da chiedere con `before`
+    pub altre: bool,
+}
+
+/// Perché una richiesta all'API non è andata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct ErroreApi {
+    pub errore: String,
+}
+
+impl ErroreApi {
+    pub fn new(errore: impl ToString) -> Self {
+        ErroreApi {
+            errore: errore.to_string(),
+        }
+    }
+}
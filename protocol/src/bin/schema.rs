@@ -0,0 +1,17 @@
+//! Rigenera JSON Schema e definizioni TypeScript del protocollo.
+
+use std::fs;
+use std::path::Path;
+
+fn main() -> std::io::Result<()> {
+    let cartella = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for (percorso, contenuto) in seven_seas_protocol::schema::file_generati() {
+        let file = cartella.join(percorso);
+        if let Some(padre) = file.parent() {
+            fs::create_dir_all(padre)?;
+        }
+        fs::write(&file, contenuto)?;
+        println!("Scritto {}", file.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+/// Messaggi del protocollo che non si riescono a leggere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErroreMessaggio {
+    /// Non è JSON, o i dati non vanno bene per il tipo indicato.
+    NonValido(String),
+    /// Il tipo non esiste in questa versione del protocollo: probabilmente
+    /// arriva da una versione più nuova.
+    Sconosciuto(String),
+}
+
+impl fmt::Display for ErroreMessaggio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErroreMessaggio::NonValido(motivo) => write!(f, "messaggio non valido: {}", motivo),
+            ErroreMessaggio::Sconosciuto(tipo) => {
+                write!(f, "tipo di messaggio sconosciuto: {}", tipo)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ErroreMessaggio {}
@@ -0,0 +1,25 @@
+//! Protocollo del tavolo: i messaggi che client e server si scambiano sul
+//! WebSocket, condivisi dal frontend, dal backend e da chiunque voglia
+//! parlare col server (bot, overlay...).
+//!
+//! Con la feature `schema` (attiva di default) il crate descrive anche i
+//! messaggi in JSON Schema e TypeScript, per i client scritti in altri
+//! linguaggi, e l'API REST in OpenAPI: vedi [`schema`] e [`api`].
+
+pub mod api;
+pub mod error;
+pub mod limiti;
+pub mod messaggi;
+#[cfg(feature = "schema")]
+pub mod schema;
+
+pub use error::ErroreMessaggio;
+pub use limiti::{valida_username, Rifiuto};
+pub use messaggi::{
+    compatibile, ChatMessage, ClientMessage, Giocatore, Hello, Moderazione, ServerMessage, Stanza,
+    Welcome, FUNZIONALITA, PAGINA_LOG, STANZA_COMUNE, VERSIONE_MINIMA, VERSIONE_PROTOCOLLO,
+};
+// i tipi dei tiri che viaggiano nei messaggi, per chi usa solo questo crate
+pub use seven_seas_core::{
+    Prova, RerollRequest, RollOptions, RollRequest, RollResult, RollWithRerollResponse, Visibilita,
+};
@@ -0,0 +1,284 @@
+//! Limiti dei messaggi dei client: lunghezze, caratteri ammessi, dadi.
+//!
+//! Il server controlla ogni [`ClientMessage`] con [`ClientMessage::valida`]
+//! e risponde ai messaggi fuori dai limiti con un [`Rifiuto`]; il client
+//! può fare lo stesso controllo prima di mandarli.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use seven_seas_core::{RollOptions, Visibilita};
+
+use crate::messaggi::ClientMessage;
+
+/// Byte di un messaggio JSON del client
+pub const MAX_BYTE_MESSAGGIO: usize = 16 * 1024;
+/// Caratteri del testo di una riga del log
+pub const MAX_TESTO: usize = 500;
+/// Caratteri del nome di un giocatore
+pub const MAX_USERNAME: usize = 32;
+/// Caratteri del nome di una stanza
+pub const MAX_NOME_STANZA: usize = 60;
+/// Caratteri del codice di una stanza
+pub const MAX_CODICE: usize = 16;
+/// Caratteri della password del GM, del nonce e del nome del client
+pub const MAX_CORTO: usize = 64;
+/// Dadi di un tiro al tavolo
+pub const MAX_DADI_TAVOLO: usize = 100;
+/// Giocatori che possono vedere un tiro nascosto
+pub const MAX_DESTINATARI: usize = 20;
+
+/// Caratteri ammessi in un nome oltre a lettere e cifre
+const SEGNI_USERNAME: &str = " -_.'";
+
+/// Perché il server non ha accettato un messaggio del client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub enum Rifiuto {
+    /// Il JSON supera [`MAX_BYTE_MESSAGGIO`]
+    MessaggioTroppoGrande { massimo: usize },
+    /// Il campo `campo` supera `massimo` caratteri (o elementi)
+    TroppoLungo { campo: String, massimo: usize },
+    /// Nome vuoto, con spazi ai lati o con caratteri non ammessi
+    UsernameNonValido(String),
+    /// Più di [`MAX_DADI_TAVOLO`] dadi
+    TroppiDadi { massimo: usize },
+    /// Il campo `campo` di un tiro supera `massimo`, oltre il quale il tiro
+    /// costa troppo al server (es. il target coi set doppi)
+    LimiteSuperato { campo: String, massimo: u32 },
+    /// Troppi messaggi in poco tempo: il prossimo passa tra `attesa_ms`
+    TroppeRichieste {
+        #[cfg_attr(feature = "schema", ts(type = "number"))]
+        attesa_ms: u64,
+    },
+}
+
+impl fmt::Display for Rifiuto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rifiuto::MessaggioTroppoGrande { massimo } => {
+                write!(f, "messaggio troppo grande (massimo {} byte)", massimo)
+            }
+            Rifiuto::TroppoLungo { campo, massimo } => {
+                write!(f, "{} troppo lungo (massimo {})", campo, massimo)
+            }
+            Rifiuto::UsernameNonValido(username) => write!(
+                f,
+                "nome non valido: \"{}\" (lettere, cifre, spazi e {}; al più {} caratteri)",
+                username,
+                SEGNI_USERNAME.trim(),
+                MAX_USERNAME
+            ),
+            Rifiuto::TroppiDadi { massimo } => {
+                write!(f, "troppi dadi (massimo {} per tiro)", massimo)
+            }
+            Rifiuto::LimiteSuperato { campo, massimo } => {
+                write!(
+                    f,
+                    "{} troppo alto per questo tiro (massimo {})",
+                    campo, massimo
+                )
+            }
+            Rifiuto::TroppeRichieste { attesa_ms } => write!(
+                f,
+                "troppi messaggi, riprova tra {:.1} s",
+                *attesa_ms as f64 / 1000.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Rifiuto {}
+
+impl ClientMessage {
+    /// Controlla che il messaggio stia nei limiti del protocollo. I nomi
+    /// vuoti, dove il protocollo li ammette, valgono come "nessun nome"
+    pub fn valida(&self) -> Result<(), Rifiuto> {
+        match self {
+            ClientMessage::Hello(hello) => {
+                lunghezza("client", &hello.client, MAX_CORTO)?;
+                elementi("funzionalita", hello.funzionalita.len(), MAX_DESTINATARI)?;
+                for funzionalita in &hello.funzionalita {
+                    lunghezza("funzionalita", funzionalita, MAX_CORTO)?;
+                }
+            }
+            ClientMessage::AddMessage(chat) => {
+                valida_username(&chat.username)?;
+                lunghezza("message", &chat.message, MAX_TESTO)?;
+            }
+            ClientMessage::RollRequest(richiesta) => {
+                valida_username(&richiesta.username)?;
+                lunghezza("nonce", &richiesta.nonce, MAX_CORTO)?;
+                valida_opzioni(&richiesta.opzioni)?;
+                if let Some(Visibilita::Giocatori(giocatori)) = &richiesta.visibilita {
+                    elementi("visibilita", giocatori.len(), MAX_DESTINATARI)?;
+                    giocatori.iter().try_for_each(|g| valida_username(g))?;
+                }
+            }
+            // il rilancio usa le opzioni del tiro che rilancia, già controllate
+            ClientMessage::RerollRequest(richiesta) => {
+                valida_username(&richiesta.username)?;
+                lunghezza("nonce", &richiesta.nonce, MAX_CORTO)?;
+            }
+            ClientMessage::CreateRoom {
+                nome,
+                username,
+                password_gm,
+            } => {
+                lunghezza("nome", nome, MAX_NOME_STANZA)?;
+                username_o_vuoto(username)?;
+                if let Some(password) = password_gm {
+                    lunghezza("password_gm", password, MAX_CORTO)?;
+                }
+            }
+            ClientMessage::JoinRoom {
+                codice, username, ..
+            } => {
+                lunghezza("codice", codice, MAX_CODICE)?;
+                username_o_vuoto(username)?;
+            }
+            ClientMessage::ClaimGm { password } => lunghezza("password", password, MAX_CORTO)?,
+            ClientMessage::Kick { username } => valida_username(username)?,
+            ClientMessage::EndSession
+            | ClientMessage::Resume(_)
+            | ClientMessage::LeaveRoom
+            | ClientMessage::VoidEntry(_)
+            | ClientMessage::DeleteEntry(_)
+            | ClientMessage::ClearHistory
+            | ClientMessage::LockRoom(_)
+            | ClientMessage::LoadOlder { .. }
+            | ClientMessage::Ping(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Le opzioni di un tiro al tavolo: al più [`MAX_DADI_TAVOLO`] dadi e un
+/// target che non fa crescere troppo il calcolo dei raises (vedi
+/// [`Regole::max_target`](seven_seas_core::Regole::max_target))
+pub fn valida_opzioni(opzioni: &RollOptions) -> Result<(), Rifiuto> {
+    if opzioni.num_dadi > MAX_DADI_TAVOLO {
+        return Err(Rifiuto::TroppiDadi {
+            massimo: MAX_DADI_TAVOLO,
+        });
+    }
+    let massimo = opzioni.regole().max_target();
+    if opzioni.target > massimo {
+        return Err(Rifiuto::LimiteSuperato {
+            campo: "target".into(),
+            massimo,
+        });
+    }
+    Ok(())
+}
+
+/// Un nome di giocatore: da 1 a [`MAX_USERNAME`] caratteri, lettere, cifre
+/// e pochi segni, senza spazi ai lati
+pub fn valida_username(username: &str) -> Result<(), Rifiuto> {
+    let ammesso = |c: char| c.is_alphanumeric() || SEGNI_USERNAME.contains(c);
+    let valido = !username.is_empty()
+        && username.chars().count() <= MAX_USERNAME
+        && username.trim() == username
+        && username.chars().all(ammesso);
+    if valido {
+        Ok(())
+    } else {
+        Err(Rifiuto::UsernameNonValido(username.to_string()))
+    }
+}
+
+fn username_o_vuoto(username: &str) -> Result<(), Rifiuto> {
+    if username.is_empty() {
+        Ok(())
+    } else {
+        valida_username(username)
+    }
+}
+
+fn lunghezza(campo: &str, testo: &str, massimo: usize) -> Result<(), Rifiuto> {
+    elementi(campo, testo.chars().count(), massimo)
+}
+
+fn elementi(campo: &str, quanti: usize, massimo: usize) -> Result<(), Rifiuto> {
+    if quanti > massimo {
+        Err(Rifiuto::TroppoLungo {
+            campo: campo.to_string(),
+            massimo,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use seven_seas_core::{RollRequest, MAX_TARGET};
+
+    use super::*;
+    use crate::messaggi::ChatMessage;
+
+    #[test]
+    fn nomi_dei_giocatori() {
+        for buono in ["Anna", "Niccolò", "Jean-Marie", "D'Artagnan", "Bruno 2"] {
+            assert_eq!(valida_username(buono), Ok(()), "{}", buono);
+        }
+        for cattivo in ["", " Anna", "Anna ", "<script>", "a\nb", &"x".repeat(33)] {
+            assert!(valida_username(cattivo).is_err(), "{:?}", cattivo);
+        }
+    }
+
+    #[test]
+    fn messaggi_nei_limiti() {
+        let chat = |testo: &str| ClientMessage::AddMessage(ChatMessage::new("Anna", testo));
+        assert_eq!(chat("ciao").valida(), Ok(()));
+        assert_eq!(
+            chat(&"a".repeat(MAX_TESTO + 1)).valida(),
+            Err(Rifiuto::TroppoLungo {
+                campo: "message".into(),
+                massimo: MAX_TESTO
+            })
+        );
+
+        let con = |opzioni, visibilita| {
+            ClientMessage::RollRequest(RollRequest {
+                username: "Anna".into(),
+                opzioni,
+                nonce: String::new(),
+                visibilita,
+            })
+        };
+        let tiro = |num_dadi, visibilita| con(RollOptions::new(num_dadi), visibilita);
+        assert_eq!(tiro(MAX_DADI_TAVOLO, None).valida(), Ok(()));
+        assert_eq!(
+            tiro(MAX_DADI_TAVOLO + 1, None).valida(),
+            Err(Rifiuto::TroppiDadi {
+                massimo: MAX_DADI_TAVOLO
+            })
+        );
+        // coi set doppi il target più alto costa troppo
+        let alto = RollOptions::new(MAX_DADI_TAVOLO).target(MAX_TARGET);
+        assert_eq!(con(alto, None).valida(), Ok(()));
+        assert_eq!(
+            con(alto.set_doppi(true), None).valida(),
+            Err(Rifiuto::LimiteSuperato {
+                campo: "target".into(),
+                massimo: 20
+            })
+        );
+        assert_eq!(con(alto.set_doppi(true).target(20), None).valida(), Ok(()));
+        let a_tutti = Visibilita::Giocatori(vec!["Bruno".into(); MAX_DESTINATARI + 1]);
+        assert!(tiro(3, Some(a_tutti)).valida().is_err());
+        let a_nessuno = Visibilita::Giocatori(vec!["".into()]);
+        assert!(tiro(3, Some(a_nessuno)).valida().is_err());
+
+        // chi entra può non dire il suo nome
+        let entra = |username: &str| ClientMessage::JoinRoom {
+            codice: "ABC234".into(),
+            username: username.into(),
+            after: None,
+        };
+        assert_eq!(entra("").valida(), Ok(()));
+        assert!(entra("Anna!").valida().is_err());
+        assert_eq!(ClientMessage::ClearHistory.valida(), Ok(()));
+    }
+}
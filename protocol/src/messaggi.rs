@@ -0,0 +1,689 @@
+//! Messaggi scambiati tra client e server sul WebSocket del tavolo.
+//!
+//! I messaggi viaggiano in JSON nella forma `{"type": ..., "data": ...}`.
+//! Ogni connessione sta in una [`Stanza`], con il suo log e i suoi tiri: chi
+//! non ne sceglie una finisce nella stanza comune.
+//! Il server numera le righe del log (`seq`, da 1) e manda ogni riga nuova
+//! con [`ServerMessage::NewMessage`]; chi si riconnette chiede solo le righe
+//! dopo l'ultima che ha visto. Entrando si ricevono solo le ultime
+//! [`PAGINA_LOG`] righe: le precedenti si chiedono a pagine con
+//! [`ClientMessage::LoadOlder`].
+//!
+//! Appena aperto il socket il client manda un [`Hello`] con la versione del
+//! protocollo che parla; il server risponde con un [`Welcome`], o con
+//! [`ServerMessage::Incompatible`] se non possono capirsi. I tipi di
+//! messaggio sconosciuti non sono un errore fatale: vedi
+//! [`ClientMessage::da_json`].
+//! Il client manda ogni tanto un [`ClientMessage::Ping`] e misura la
+//! latenza dal [`ServerMessage::Pong`]; se il pong non arriva, la
+//! connessione è morta. Il server chiude le connessioni da cui non riceve
+//! più niente.
+//! Il server scarta i messaggi fuori dai limiti di [`crate::limiti`], o
+//! mandati troppo di frequente, rispondendo con [`ServerMessage::Rejected`].
+//! I tiri li fa il server: il client manda una [`RollRequest`] e riceve un
+//! [`RollResult`], che è l'unica fonte dei risultati mostrati al tavolo. I
+//! tiri nascosti (vedi [`Visibilita`](crate::Visibilita)) arrivano solo a
+//! chi li può vedere; gli altri ricevono la riga del log col tiro coperto.
+//!
+//! Alla prima connessione il server manda un [`ServerMessage::SessionToken`],
+//! che il client conserva e ripresenta alle successive. In ogni stanza un
+//! nome appartiene al primo gettone che l'ha usato, e si scrive e si tira
+//! solo col nome con cui si è entrati.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use seven_seas_core::{RerollRequest, RollRequest, RollResult};
+
+use crate::error::ErroreMessaggio;
+use crate::limiti::Rifiuto;
+
+/// Versione del protocollo parlata da questo crate
+pub const VERSIONE_PROTOCOLLO: u32 = 4;
+/// Versione più vecchia con cui si riesce ancora a parlare
+pub const VERSIONE_MINIMA: u32 = 2;
+/// Funzionalità di questa versione, annunciate in [`Hello`] e [`Welcome`]
+pub const FUNZIONALITA: &[&str] = &[
+    "tiri",
+    "verifica",
+    "ripresa",
+    "stanze",
+    "presenza",
+    "gm",
+    "tiri_nascosti",
+    "pagine",
+    "gettoni",
+    "limiti",
+    "battito",
+];
+
+/// Righe del log mandate entrando in una stanza, e al più in ogni pagina di
+/// [`ClientMessage::LoadOlder`]
+pub const PAGINA_LOG: usize = 100;
+
+/// Codice della stanza in cui finisce chi non ne sceglie una
+pub const STANZA_COMUNE: &str = "COMUNE";
+
+/// Se si può parlare con chi usa il protocollo `versione`
+pub fn compatibile(versione: u32) -> bool {
+    versione >= VERSIONE_MINIMA
+}
+
+/// Presentazione del client, il primo messaggio dopo l'apertura del socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct Hello {
+    pub versione: u32,
+    /// Nome e versione del programma, es. "seven_seas_successes/0.1.0"
+    pub client: String,
+    #[serde(default)]
+    pub funzionalita: Vec<String>,
+}
+
+impl Hello {
+    /// Il saluto di un client che parla questa versione del protocollo
+    pub fn new(client: impl Into<String>) -> Self {
+        Hello {
+            versione: VERSIONE_PROTOCOLLO,
+            client: client.into(),
+            funzionalita: FUNZIONALITA.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// Risposta del server a un [`Hello`] compatibile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct Welcome {
+    pub versione: u32,
+    pub server: String,
+    #[serde(default)]
+    pub funzionalita: Vec<String>,
+    /// Ogni quanto il client deve mandare un [`ClientMessage::Ping`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub battito_ms: Option<u32>,
+}
+
+impl Welcome {
+    pub fn new(server: impl Into<String>) -> Self {
+        Welcome {
+            versione: VERSIONE_PROTOCOLLO,
+            server: server.into(),
+            funzionalita: FUNZIONALITA.iter().map(|f| f.to_string()).collect(),
+            battito_ms: None,
+        }
+    }
+
+    /// Chiede al client un [`ClientMessage::Ping`] ogni `battito_ms`
+    pub fn battito(self, battito_ms: u32) -> Self {
+        Welcome {
+            battito_ms: Some(battito_ms),
+            ..self
+        }
+    }
+
+    pub fn supporta(&self, funzionalita: &str) -> bool {
+        self.funzionalita.iter().any(|f| f == funzionalita)
+    }
+}
+
+/// Una stanza del server, con il suo log e la sua sessione di tiri
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct Stanza {
+    /// Codice da condividere per farci entrare gli altri giocatori
+    pub codice: String,
+    pub nome: String,
+    /// Il giocatore che fa da GM, se qualcuno ha preso il ruolo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub gm: Option<String>,
+    /// Chiusa dal GM: non entra nessun altro
+    #[serde(default)]
+    pub bloccata: bool,
+}
+
+/// Un giocatore passato da una stanza
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct Giocatore {
+    pub username: String,
+    /// Ha almeno una connessione aperta nella stanza
+    pub collegato: bool,
+    /// Millisecondi dal 1970 dell'ultima volta che ha scritto, tirato,
+    /// è entrato o è uscito
+    #[cfg_attr(feature = "schema", ts(type = "number"))]
+    pub ultima_attivita: u64,
+}
+
+/// Una riga del log del tavolo
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub struct ChatMessage {
+    pub username: String,
+    pub message: String,
+    /// Posizione nel log, da 1; la assegna il server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional, type = "number"))]
+    pub seq: Option<u64>,
+    /// Millisecondi dal 1970 (Unix epoch); li assegna il server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional, type = "number"))]
+    pub timestamp: Option<u64>,
+    /// Il tiro descritto dal messaggio. Lo imposta solo il server: nei
+    /// messaggi che arrivano dai client va ignorato
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub tiro: Option<Box<RollResult>>,
+    /// Cosa ne ha fatto il GM, se l'ha toccata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "schema", ts(optional))]
+    pub moderata: Option<Moderazione>,
+}
+
+/// Interventi del GM su una riga del log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+pub enum Moderazione {
+    /// La riga resta visibile ma non vale, es. un tiro fatto per sbaglio
+    Annullata,
+    /// Il testo è stato tolto; il tiro resta, per la verifica della sessione
+    Eliminata,
+}
+
+impl ChatMessage {
+    /// Un messaggio di chat, senza tiro e non ancora numerato
+    pub fn new(username: impl Into<String>, message: impl Into<String>) -> Self {
+        ChatMessage {
+            username: username.into(),
+            message: message.into(),
+            seq: None,
+            timestamp: None,
+            tiro: None,
+            moderata: None,
+        }
+    }
+
+    /// La riga del log che riporta `tiro`
+    pub fn di_tiro(tiro: &RollResult) -> Self {
+        ChatMessage {
+            tiro: Some(Box::new(tiro.clone())),
+            ..ChatMessage::new(tiro.username.clone(), tiro.descrizione())
+        }
+    }
+
+    /// La riga come la vede chi non può vederne il tiro
+    pub fn coperta(&self) -> Self {
+        let Some(tiro) = &self.tiro else {
+            return self.clone();
+        };
+        let mut riga = ChatMessage {
+            seq: self.seq,
+            timestamp: self.timestamp,
+            moderata: self.moderata,
+            ..ChatMessage::di_tiro(&tiro.coperto())
+        };
+        if self.moderata == Some(Moderazione::Eliminata) {
+            riga.message.clear();
+        }
+        riga
+    }
+}
+
+/// Messaggi che il client invia al server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+#[serde(tag = "type", content = "data")]
+pub enum ClientMessage {
+    Hello(Hello),
+    AddMessage(ChatMessage),
+    RollRequest(RollRequest),
+    RerollRequest(RerollRequest),
+    /// Chiude la sessione: il server rivela il seme e ne sceglie uno nuovo.
+    /// Nelle stanze con un GM può chiederlo solo lui
+    EndSession,
+    /// Chiede le righe del log successive a quella con questo `seq`
+    Resume(#[cfg_attr(feature = "schema", ts(type = "number"))] u64),
+    /// Crea una stanza nuova ed entra
+    CreateRoom {
+        nome: String,
+        username: String,
+        /// Chi crea la stanza ne è il GM; con una password anche un altro
+        /// può prendere il ruolo con [`ClientMessage::ClaimGm`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "schema", ts(optional))]
+        password_gm: Option<String>,
+    },
+    /// Entra nella stanza con questo codice, lasciando quella attuale. Con
+    /// `after` si ricevono solo le righe del log dopo quella con quel `seq`
+    JoinRoom {
+        codice: String,
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "schema", ts(optional, type = "number"))]
+        after: Option<u64>,
+    },
+    /// Torna nella stanza comune
+    LeaveRoom,
+    /// Prende il ruolo di GM della stanza con la password scelta alla
+    /// creazione
+    ClaimGm {
+        password: String,
+    },
+    /// Solo GM: manda il giocatore nella stanza comune
+    Kick {
+        username: String,
+    },
+    /// Solo GM: annulla la riga con questo `seq`
+    VoidEntry(#[cfg_attr(feature = "schema", ts(type = "number"))] u64),
+    /// Solo GM: toglie il testo della riga con questo `seq`
+    DeleteEntry(#[cfg_attr(feature = "schema", ts(type = "number"))] u64),
+    /// Solo GM: svuota il log e chiude la sessione di tiri
+    ClearHistory,
+    /// Solo GM: chiude (`true`) o riapre la stanza a chi non c'è
+    LockRoom(bool),
+    /// Chiede le righe del log prima di quella con `seq` uguale a `before`,
+    /// al più `limit` (e comunque non più di [`PAGINA_LOG`])
+    LoadOlder {
+        #[cfg_attr(feature = "schema", ts(type = "number"))]
+        before: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "schema", ts(optional))]
+        limit: Option<u32>,
+    },
+    /// Battito del client: il server risponde subito con un
+    /// [`ServerMessage::Pong`] con lo stesso numero, di solito l'ora di invio
+    /// in millisecondi
+    Ping(#[cfg_attr(feature = "schema", ts(type = "number"))] u64),
+}
+
+/// Messaggi inviati dal server al client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema, ts_rs::TS))]
+#[serde(tag = "type", content = "data")]
+pub enum ServerMessage {
+    /// Si è entrati in una stanza: seguono il suo log, il suo impegno e i
+    /// giocatori presenti
+    RoomJoined(Stanza),
+    /// I giocatori della stanza, anche quelli usciti, in ordine di nome;
+    /// arriva entrando e a ogni ingresso o uscita. L'attività successiva si
+    /// ricava dalle righe del log
+    Presence(Vec<Giocatore>),
+    /// Il log, dal più vecchio: tutto quello che il client deve avere al
+    /// posto di quello che ha. Sono al più le ultime [`PAGINA_LOG`] righe
+    FullHistory(Vec<ChatMessage>),
+    /// Una riga appena aggiunta al log
+    NewMessage(ChatMessage),
+    /// Le righe chieste con [`ClientMessage::Resume`], dalla più vecchia
+    Resumed(Vec<ChatMessage>),
+    System(String),
+    RollResult(Box<RollResult>),
+    /// Hash del seme della sessione che inizia
+    SeedCommitment(String),
+    /// Seme della sessione appena chiusa
+    SeedReveal(String),
+    Welcome(Welcome),
+    /// Il client parla una versione che il server non capisce; dopo questo
+    /// il server chiude la connessione
+    Incompatible {
+        versione: u32,
+        minima: u32,
+    },
+    /// GM o blocco della stanza cambiati
+    RoomUpdated(Stanza),
+    /// Una riga del log toccata dal GM, da sostituire a quella con lo stesso
+    /// `seq`
+    EntryUpdated(ChatMessage),
+    /// Il GM ha svuotato il log: le righe nuove ripartono dopo le vecchie,
+    /// senza riusarne i `seq`
+    HistoryCleared,
+    /// Il GM ha mandato via questo giocatore; le sue connessioni tornano
+    /// nella stanza comune
+    Kicked(String),
+    /// Una pagina di righe chieste con [`ClientMessage::LoadOlder`], dalla
+    /// più vecchia; `altre` dice se ce ne sono di ancora precedenti
+    OlderHistory {
+        righe: Vec<ChatMessage>,
+        altre: bool,
+    },
+    /// Il gettone di sessione di questo client, appena emesso: va
+    /// ripresentato nelle connessioni successive per tenere il proprio nome
+    SessionToken(String),
+    /// Il messaggio appena mandato è stato scartato, e perché
+    Rejected(Rifiuto),
+    /// Risposta a un [`ClientMessage::Ping`]
+    Pong(#[cfg_attr(feature = "schema", ts(type = "number"))] u64),
+}
+
+impl ClientMessage {
+    pub(crate) const TIPI: &'static [&'static str] = &[
+        "Hello",
+        "AddMessage",
+        "RollRequest",
+        "RerollRequest",
+        "EndSession",
+        "Resume",
+        "CreateRoom",
+        "JoinRoom",
+        "LeaveRoom",
+        "ClaimGm",
+        "Kick",
+        "VoidEntry",
+        "DeleteEntry",
+        "ClearHistory",
+        "LockRoom",
+        "LoadOlder",
+        "Ping",
+    ];
+
+    /// Legge un messaggio, distinguendo i tipi che questa versione non
+    /// conosce da quelli scritti male
+    pub fn da_json(json: &str) -> Result<Self, ErroreMessaggio> {
+        leggi(json, Self::TIPI)
+    }
+}
+
+impl ServerMessage {
+    pub(crate) const TIPI: &'static [&'static str] = &[
+        "RoomJoined",
+        "Presence",
+        "FullHistory",
+        "NewMessage",
+        "Resumed",
+        "System",
+        "RollResult",
+        "SeedCommitment",
+        "SeedReveal",
+        "Welcome",
+        "Incompatible",
+        "RoomUpdated",
+        "EntryUpdated",
+        "HistoryCleared",
+        "Kicked",
+        "OlderHistory",
+        "SessionToken",
+        "Rejected",
+        "Pong",
+    ];
+
+    /// Come [`ClientMessage::da_json`]
+    pub fn da_json(json: &str) -> Result<Self, ErroreMessaggio> {
+        leggi(json, Self::TIPI)
+    }
+}
+
+fn leggi<T: DeserializeOwned>(json: &str, tipi: &[&str]) -> Result<T, ErroreMessaggio> {
+    #[derive(Deserialize)]
+    struct Busta {
+        #[serde(rename = "type")]
+        tipo: String,
+    }
+
+    serde_json::from_str(json).map_err(|e| match serde_json::from_str::<Busta>(json) {
+        Ok(busta) if !tipi.contains(&busta.tipo.as_str()) => {
+            ErroreMessaggio::Sconosciuto(busta.tipo)
+        }
+        _ => ErroreMessaggio::NonValido(e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use seven_seas_core::{RollOptions, RollWithRerollResponse};
+
+    use super::*;
+
+    #[test]
+    fn formato_json() {
+        let msg = ClientMessage::RollRequest(RollRequest {
+            username: "Anna".into(),
+            opzioni: RollOptions::new(3),
+            nonce: String::new(),
+            visibilita: None,
+        });
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["type"], "RollRequest");
+        assert_eq!(json["data"]["opzioni"]["num_dadi"], 3);
+
+        // i messaggi di chat senza tiro restano quelli di prima
+        let vecchio = r#"{"type":"AddMessage","data":{"username":"Anna","message":"ciao"}}"#;
+        let msg: ClientMessage = serde_json::from_str(vecchio).unwrap();
+        assert_eq!(serde_json::to_string(&msg).unwrap(), vecchio);
+    }
+
+    fn tipo<T: Serialize>(msg: &T) -> String {
+        serde_json::to_value(msg).unwrap()["type"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn elenchi_dei_tipi_completi() {
+        // un esempio per ogni variante: il match non compila se ne manca una
+        let client = [
+            ClientMessage::Hello(Hello::new("test")),
+            ClientMessage::AddMessage(ChatMessage::new("Anna", "ciao")),
+            ClientMessage::RollRequest(RollRequest {
+                username: "Anna".into(),
+                opzioni: RollOptions::new(1),
+                nonce: String::new(),
+                visibilita: None,
+            }),
+            ClientMessage::RerollRequest(RerollRequest {
+                username: "Anna".into(),
+                indice: 0,
+                nonce: String::new(),
+            }),
+            ClientMessage::EndSession,
+            ClientMessage::Resume(0),
+            ClientMessage::CreateRoom {
+                nome: "Campagna".into(),
+                username: "Anna".into(),
+                password_gm: None,
+            },
+            ClientMessage::JoinRoom {
+                codice: "ABC234".into(),
+                username: "Anna".into(),
+                after: None,
+            },
+            ClientMessage::LeaveRoom,
+            ClientMessage::ClaimGm {
+                password: "segreta".into(),
+            },
+            ClientMessage::Kick {
+                username: "Bruno".into(),
+            },
+            ClientMessage::VoidEntry(1),
+            ClientMessage::DeleteEntry(1),
+            ClientMessage::ClearHistory,
+            ClientMessage::LockRoom(true),
+            ClientMessage::LoadOlder {
+                before: 101,
+                limit: None,
+            },
+            ClientMessage::Ping(1_700_000_000_000),
+        ];
+        for msg in &client {
+            match msg {
+                ClientMessage::Hello(_)
+                | ClientMessage::AddMessage(_)
+                | ClientMessage::RollRequest(_)
+                | ClientMessage::RerollRequest(_)
+                | ClientMessage::EndSession
+                | ClientMessage::Resume(_)
+                | ClientMessage::CreateRoom { .. }
+                | ClientMessage::JoinRoom { .. }
+                | ClientMessage::LeaveRoom
+                | ClientMessage::ClaimGm { .. }
+                | ClientMessage::Kick { .. }
+                | ClientMessage::VoidEntry(_)
+                | ClientMessage::DeleteEntry(_)
+                | ClientMessage::ClearHistory
+                | ClientMessage::LockRoom(_)
+                | ClientMessage::LoadOlder { .. }
+                | ClientMessage::Ping(_) => {}
+            }
+            assert!(ClientMessage::TIPI.contains(&tipo(msg).as_str()));
+        }
+        assert_eq!(ClientMessage::TIPI.len(), client.len());
+
+        let stanza = Stanza {
+            codice: "ABC234".into(),
+            nome: "Campagna".into(),
+            gm: Some("Anna".into()),
+            bloccata: false,
+        };
+        let server = [
+            ServerMessage::RoomJoined(stanza.clone()),
+            ServerMessage::Presence(Vec::new()),
+            ServerMessage::FullHistory(Vec::new()),
+            ServerMessage::NewMessage(ChatMessage::new("Anna", "ciao")),
+            ServerMessage::Resumed(Vec::new()),
+            ServerMessage::System(String::new()),
+            ServerMessage::RollResult(Box::new(tiro_di_prova())),
+            ServerMessage::SeedCommitment(String::new()),
+            ServerMessage::SeedReveal(String::new()),
+            ServerMessage::Welcome(Welcome::new("test").battito(15_000)),
+            ServerMessage::Incompatible {
+                versione: 2,
+                minima: 2,
+            },
+            ServerMessage::RoomUpdated(stanza),
+            ServerMessage::EntryUpdated(ChatMessage::new("Anna", "ciao")),
+            ServerMessage::HistoryCleared,
+            ServerMessage::Kicked("Bruno".into()),
+            ServerMessage::OlderHistory {
+                righe: Vec::new(),
+                altre: false,
+            },
+            ServerMessage::SessionToken("00.ff".into()),
+            ServerMessage::Rejected(Rifiuto::TroppoLungo {
+                campo: "message".into(),
+                massimo: 500,
+            }),
+            ServerMessage::Pong(1_700_000_000_000),
+        ];
+        for msg in &server {
+            match msg {
+                ServerMessage::RoomJoined(_)
+                | ServerMessage::Presence(_)
+                | ServerMessage::FullHistory(_)
+                | ServerMessage::NewMessage(_)
+                | ServerMessage::Resumed(_)
+                | ServerMessage::System(_)
+                | ServerMessage::RollResult(_)
+                | ServerMessage::SeedCommitment(_)
+                | ServerMessage::SeedReveal(_)
+                | ServerMessage::Welcome(_)
+                | ServerMessage::Incompatible { .. }
+                | ServerMessage::RoomUpdated(_)
+                | ServerMessage::EntryUpdated(_)
+                | ServerMessage::HistoryCleared
+                | ServerMessage::Kicked(_)
+                | ServerMessage::OlderHistory { .. }
+                | ServerMessage::SessionToken(_)
+                | ServerMessage::Rejected(_)
+                | ServerMessage::Pong(_) => {}
+            }
+            assert!(ServerMessage::TIPI.contains(&tipo(msg).as_str()));
+        }
+        assert_eq!(ServerMessage::TIPI.len(), server.len());
+    }
+
+    #[test]
+    fn messaggi_sconosciuti_o_non_validi() {
+        let nuovo = r#"{"type":"Teletrasporto","data":{"dove":"Vodacce"}}"#;
+        assert_eq!(
+            ClientMessage::da_json(nuovo),
+            Err(ErroreMessaggio::Sconosciuto("Teletrasporto".into()))
+        );
+        assert_eq!(
+            ServerMessage::da_json(nuovo),
+            Err(ErroreMessaggio::Sconosciuto("Teletrasporto".into()))
+        );
+
+        let entra = r#"{"type":"JoinRoom","data":{"codice":"ABC234","username":"Anna"}}"#;
+        assert_eq!(
+            ClientMessage::da_json(entra),
+            Ok(ClientMessage::JoinRoom {
+                codice: "ABC234".into(),
+                username: "Anna".into(),
+                after: None,
+            })
+        );
+
+        let rotto = r#"{"type":"Resume","data":"ieri"}"#;
+        assert!(matches!(
+            ClientMessage::da_json(rotto),
+            Err(ErroreMessaggio::NonValido(_))
+        ));
+        assert!(matches!(
+            ClientMessage::da_json("non è json"),
+            Err(ErroreMessaggio::NonValido(_))
+        ));
+        assert_eq!(
+            ClientMessage::da_json(r#"{"type":"EndSession"}"#),
+            Ok(ClientMessage::EndSession)
+        );
+    }
+
+    #[test]
+    fn compatibilita_delle_versioni() {
+        assert!(compatibile(VERSIONE_PROTOCOLLO));
+        // i client della v2 non conoscono le stanze e restano in quella comune
+        assert!(compatibile(2));
+        assert!(!compatibile(1));
+        let welcome = Welcome::new("server/1.0");
+        assert!(welcome.supporta("ripresa"));
+        assert!(!welcome.supporta("teletrasporto"));
+
+        // i server che non chiedono il battito non lo mandano nemmeno
+        let json = serde_json::to_value(&welcome).unwrap();
+        assert!(json.get("battito_ms").is_none());
+        let vecchio: Welcome =
+            serde_json::from_str(r#"{"versione":4,"server":"server/0.9"}"#).unwrap();
+        assert_eq!(vecchio.battito_ms, None);
+    }
+
+    #[test]
+    fn riga_del_tiro() {
+        let tiro = tiro_di_prova();
+        let riga = ChatMessage::di_tiro(&tiro);
+        assert_eq!(riga.username, "Anna");
+        assert_eq!(riga.message, tiro.descrizione());
+        assert_eq!(riga.tiro, Some(Box::new(tiro.clone())));
+
+        let coperta = ChatMessage {
+            seq: Some(4),
+            ..riga
+        }
+        .coperta();
+        assert_eq!(coperta.message, "Anna ha fatto un tiro nascosto");
+        assert_eq!(coperta.seq, Some(4));
+        let coperto = coperta.tiro.unwrap();
+        assert!(coperto.coperto);
+        assert!(coperto.esito.risultati_aggiornati.is_empty());
+        assert_eq!(coperto.prova, tiro.prova);
+    }
+
+    /// Un tiro piccolo, con i soli campi che la riga del tiro usa
+    fn tiro_di_prova() -> RollResult {
+        RollResult {
+            username: "Anna".into(),
+            opzioni: RollOptions::new(2),
+            esito: RollWithRerollResponse {
+                risultati_originali: vec![10, 5],
+                risultati_aggiornati: vec![10, 5],
+                raises: 1,
+                combinazioni: vec![vec![10]],
+                avanzi: vec![5],
+                ..Default::default()
+            },
+            rilanciato_da: None,
+            prova: None,
+            visibilita: None,
+            coperto: false,
+        }
+    }
+}
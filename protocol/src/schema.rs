@@ -0,0 +1,240 @@
+//! JSON Schema e definizioni TypeScript dei messaggi, e documento OpenAPI
+//! dell'API REST.
+//!
+//! I file generati stanno in `schema/` e `bindings/` nella cartella del
+//! crate; dopo aver cambiato un messaggio si rigenerano con
+//! `cargo run -p seven_seas_protocol --bin schema`.
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde_json::{json, Value};
+use ts_rs::TS;
+
+use crate::api::{ErroreApi, PaginaStorico};
+use crate::{
+    ChatMessage, ClientMessage, Giocatore, Hello, Moderazione, Prova, RerollRequest, Rifiuto,
+    RollOptions, RollRequest, RollResult, RollWithRerollResponse, ServerMessage, Stanza,
+    Visibilita, Welcome,
+};
+
+/// Schema dei messaggi mandati dai client
+pub fn schema_client() -> String {
+    in_json(schema_for!(ClientMessage))
+}
+
+/// Schema dei messaggi mandati dal server
+pub fn schema_server() -> String {
+    in_json(schema_for!(ServerMessage))
+}
+
+/// Tutti i tipi del protocollo in un modulo TypeScript
+pub fn typescript() -> String {
+    let dichiarazioni = [
+        ClientMessage::decl(),
+        ServerMessage::decl(),
+        Hello::decl(),
+        Welcome::decl(),
+        Stanza::decl(),
+        Giocatore::decl(),
+        ChatMessage::decl(),
+        Moderazione::decl(),
+        Rifiuto::decl(),
+        RollRequest::decl(),
+        RerollRequest::decl(),
+        RollResult::decl(),
+        Visibilita::decl(),
+        RollOptions::decl(),
+        RollWithRerollResponse::decl(),
+        Prova::decl(),
+        PaginaStorico::decl(),
+        ErroreApi::decl(),
+    ];
+    let mut ts = String::from("// Generato da seven_seas_protocol: non modificare a mano.\n");
+    for dichiarazione in dichiarazioni {
+        ts.push_str("\nexport ");
+        ts.push_str(&dichiarazione);
+        ts.push('\n');
+    }
+    ts
+}
+
+/// Documento OpenAPI 3.0 dell'API REST (vedi [`crate::api`])
+pub fn openapi() -> String {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let codice = json!({
+        "name": "codice",
+        "in": "path",
+        "required": true,
+        "description": "Codice della stanza, senza badare a maiuscole",
+        "schema": { "type": "string" },
+    });
+    let paths = json!({
+        "/api/roll": {
+            "post": {
+                "summary": "Fa un tiro",
+                "description": "Tira i dadi con le opzioni indicate e raggruppa i risultati nei set che danno più raises. Il tiro non finisce nel log di nessuna stanza.",
+                "operationId": "tira",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": { "schema": gen.subschema_for::<RollOptions>() },
+                    },
+                },
+                "responses": {
+                    "200": risposta::<RollWithRerollResponse>("Il tiro: dadi, raises e set", &mut gen),
+                    "400": risposta::<ErroreApi>("Opzioni non valide o troppi dadi", &mut gen),
+                    "429": risposta::<ErroreApi>("Troppe richieste: riprovare più tardi", &mut gen),
+                },
+            },
+        },
+        "/api/rooms/{codice}": {
+            "get": {
+                "summary": "Descrive una stanza",
+                "operationId": "stanza",
+                "parameters": [codice.clone()],
+                "responses": {
+                    "200": risposta::<Stanza>("La stanza", &mut gen),
+                    "403": risposta::<ErroreApi>("La stanza è chiusa dal GM", &mut gen),
+                    "404": risposta::<ErroreApi>("Nessuna stanza con questo codice", &mut gen),
+                },
+            },
+        },
+        "/api/rooms/{codice}/history": {
+            "get": {
+                "summary": "Legge il log di una stanza",
+                "description": "Le righe del log prima di quella con `seq` uguale a `before`, o le ultime; i tiri nascosti sono coperti.",
+                "operationId": "storico",
+                "parameters": [
+                    codice,
+                    {
+                        "name": "before",
+                        "in": "query",
+                        "required": false,
+                        "description": "Solo le righe con `seq` minore di questo",
+                        "schema": { "type": "integer", "format": "uint64", "minimum": 0 },
+                    },
+                    {
+                        "name": "limit",
+                        "in": "query",
+                        "required": false,
+                        "description": format!("Quante righe al più (e comunque non più di {})", crate::PAGINA_LOG),
+                        "schema": { "type": "integer", "format": "uint32", "minimum": 0 },
+                    },
+                ],
+                "responses": {
+                    "200": risposta::<PaginaStorico>("Una pagina del log", &mut gen),
+                    "403": risposta::<ErroreApi>("La stanza è chiusa dal GM", &mut gen),
+                    "404": risposta::<ErroreApi>("Nessuna stanza con questo codice", &mut gen),
+                },
+            },
+        },
+        "/api/openapi.json": {
+            "get": {
+                "summary": "Questo documento",
+                "operationId": "openapi",
+                "responses": {
+                    "200": { "description": "Il documento OpenAPI dell'API" },
+                },
+            },
+        },
+    });
+    let documento = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Seven Seas Successes",
+            "description": "Tiri di 7th Sea e log delle stanze del tavolo",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": gen.definitions() },
+    });
+    let mut json =
+        serde_json::to_string_pretty(&documento).expect("il documento si serializza sempre");
+    json.push('\n');
+    json
+}
+
+/// Una risposta JSON col corpo di tipo `T`
+fn risposta<T: JsonSchema>(descrizione: &str, gen: &mut SchemaGenerator) -> Value {
+    json!({
+        "description": descrizione,
+        "content": {
+            "application/json": { "schema": gen.subschema_for::<T>() },
+        },
+    })
+}
+
+/// I file generati, con il percorso relativo alla cartella del crate
+pub fn file_generati() -> Vec<(&'static str, String)> {
+    vec![
+        ("schema/client_message.schema.json", schema_client()),
+        ("schema/server_message.schema.json", schema_server()),
+        ("schema/openapi.json", openapi()),
+        ("bindings/protocollo.ts", typescript()),
+    ]
+}
+
+fn in_json(schema: RootSchema) -> String {
+    let mut json = serde_json::to_string_pretty(&schema).expect("lo schema si serializza sempre");
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn file_generati_aggiornati() {
+        let cartella = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for (percorso, atteso) in file_generati() {
+            let attuale = fs::read_to_string(cartella.join(percorso)).unwrap_or_default();
+            assert!(
+                attuale == atteso,
+                "{} non è aggiornato: va rigenerato con `cargo run -p seven_seas_protocol --bin schema`",
+                percorso
+            );
+        }
+    }
+
+    #[test]
+    fn openapi_completo() {
+        let documento: Value = serde_json::from_str(&openapi()).unwrap();
+        let schemi = &documento["components"]["schemas"];
+        for tipo in [
+            "RollOptions",
+            "RollWithRerollResponse",
+            "Stanza",
+            "PaginaStorico",
+            "ChatMessage",
+            "ErroreApi",
+        ] {
+            assert!(schemi.get(tipo).is_some(), "{}", tipo);
+        }
+        // ogni riferimento porta a uno schema che c'è
+        let testo = documento.to_string();
+        for riferimento in testo.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let tipo = riferimento.split('"').next().unwrap();
+            assert!(schemi.get(tipo).is_some(), "{}", tipo);
+        }
+        assert!(documento["paths"]["/api/roll"]["post"].is_object());
+    }
+
+    #[test]
+    fn tutti_i_tipi_di_messaggio() {
+        let ts = typescript();
+        let (client, server) = (schema_client(), schema_server());
+        for tipo in ClientMessage::TIPI {
+            assert!(ts.contains(&format!("\"type\": \"{}\"", tipo)), "{}", tipo);
+            assert!(client.contains(&format!("\"{}\"", tipo)), "{}", tipo);
+        }
+        for tipo in ServerMessage::TIPI {
+            assert!(ts.contains(&format!("\"type\": \"{}\"", tipo)), "{}", tipo);
+            assert!(server.contains(&format!("\"{}\"", tipo)), "{}", tipo);
+        }
+    }
+}
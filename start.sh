@@ -1,4 +1,9 @@
 #!/bin/bash
 
-# Avvia il frontend con Trunk
-(cd frontend && trunk serve)
\ No newline at end of file
+# Avvia il server del tavolo e, chiuso il frontend, lo ferma
+cargo run -p seven_seas_backend &
+BACKEND=$!
+trap "kill $BACKEND" EXIT
+
+# Avvia il frontend con Trunk, collegato al server locale
+(cd frontend && SEVEN_SEAS_SERVER=ws://localhost:8000/ws/ trunk serve)
\ No newline at end of file